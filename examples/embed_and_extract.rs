@@ -0,0 +1,64 @@
+// Embed a message in a buffer and read it back, without the command line:
+//
+//     cargo run --example embed_and_extract
+//
+// Everything here works on interleaved f32 samples in [-1, 1]; load them
+// however you like (msg_encoder::wav::read_file reads a WAV).
+
+use std::process;
+
+use msg_encoder::config::{FreqBand, Framing, WatermarkConfig};
+use msg_encoder::decoder::Decoder;
+use msg_encoder::encoder::Encoder;
+use msg_encoder::payload::Payload;
+
+const SAMPLE_RATE: u32 = 8000;
+
+// Two seconds of broadband noise standing in for programme audio
+fn demo_buffer() -> Vec<f32> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..SAMPLE_RATE as usize * 2)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            ((state >> 40) as f32 / (1u64 << 24) as f32 - 0.5) * 0.5
+        })
+        .collect()
+}
+
+fn main() {
+    let samples = demo_buffer();
+
+    // Encoder and decoder must agree on the layout: both get this config.
+    // Bins 20-100 and varint framing are both off the defaults; the
+    // narrower band holds 80 bits a frame, room for a few bytes.
+    let config = WatermarkConfig {
+        band: FreqBand::from_name("20-100").expect("a valid band"),
+        framing: Framing::Varint,
+        ..WatermarkConfig::default()
+    };
+
+    let message = Payload::Text("lib".to_string());
+    let (encoded, report) = Encoder::new(config.clone())
+        .encode(&samples, 1, SAMPLE_RATE, &message)
+        .unwrap_or_else(|err| {
+            eprintln!("encode failed: {}", err);
+            process::exit(1);
+        });
+    println!(
+        "Embedded {} bits at {:.1} dB SNR ({} frame/bin pairs)",
+        report.bits.len(),
+        report.snr_db,
+        report.bins_used.len()
+    );
+
+    let decoded = Decoder::new(config)
+        .decode(&encoded, 1, SAMPLE_RATE)
+        .unwrap_or_else(|err| {
+            eprintln!("decode failed: {}", err);
+            process::exit(1);
+        });
+    println!("Decoded {:?}", decoded.message);
+    assert_eq!(decoded.message, "lib");
+}
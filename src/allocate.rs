@@ -0,0 +1,94 @@
+// =============================================================================
+// ALLOCATE - Which bits of the stream each frame carries
+// =============================================================================
+//
+// By default every frame carries the whole stream. Under --bits-per-frame a
+// frame holds at most N bits, so consecutive frames take turns: the first
+// frame carries bits 0..N, the next N..2N, and so on until the stream runs
+// out, when the following frame starts the next copy from bit 0.
+//
+// The allocation is a pure function of the capacities, so the decoder can
+// re-derive it as long as it can work out the same capacities: uniform caps
+// (--bits-per-frame), with 0 for the frames --vad-threshold-db finds silent.
+// A frame's level barely moves when it is marked (see vad.rs); finer
+// capacities measured from the audio would differ between the clean input
+// and the watermarked copy.
+
+// Bit indices each frame carries when frame f holds at most
+// `frame_capacities[f]` bits of a `total_bits` stream. Frames are filled in
+// order; a copy never straddles its own end, so a frame that finishes the
+// stream carries fewer bits than it could. A frame of capacity 0 carries none.
+pub fn allocate_bits(frame_capacities: &[usize], total_bits: usize) -> Vec<Vec<usize>> {
+    let mut next = 0;
+    frame_capacities
+        .iter()
+        .map(|&capacity| {
+            let end = (next + capacity).min(total_bits);
+            let bits = (next..end).collect();
+            next = if end == total_bits { 0 } else { end };
+            bits
+        })
+        .collect()
+}
+
+// Inverse of allocate_bits for one stream: every copy the frames carry, put
+// back together from each frame's `values` (in the order allocate_bits gave
+// them). A copy cut off by the last frame is dropped.
+pub fn gather_copies<T: Clone>(allocation: &[Vec<usize>], values: &[Vec<T>], total_bits: usize) -> Vec<Vec<T>> {
+    let mut copies = Vec::new();
+    let mut copy = Vec::with_capacity(total_bits);
+    for (indices, values) in allocation.iter().zip(values) {
+        for (&index, value) in indices.iter().zip(values) {
+            debug_assert_eq!(index, copy.len());
+            copy.push(value.clone());
+        }
+        if total_bits > 0 && copy.len() == total_bits {
+            copies.push(std::mem::replace(&mut copy, Vec::with_capacity(total_bits)));
+        }
+    }
+    copies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uneven_capacities_place_and_recover_every_bit() {
+        // Frames 1 and 4 are unusable (say, silent); the rest hold 3 to 7 bits
+        let capacities = [5, 0, 3, 7, 0, 4, 6, 5, 2];
+        let stream: Vec<u8> = (0..12).map(|bit| bit % 3 % 2).collect();
+        let allocation = allocate_bits(&capacities, stream.len());
+        assert_eq!(
+            allocation,
+            [
+                vec![0, 1, 2, 3, 4],
+                vec![],
+                vec![5, 6, 7],
+                vec![8, 9, 10, 11], // ends the copy: 4 of its 7 slots
+                vec![],
+                vec![0, 1, 2, 3], // second copy
+                vec![4, 5, 6, 7, 8, 9],
+                vec![10, 11],
+                vec![0, 1], // a third copy, cut off
+            ]
+        );
+        for (indices, &capacity) in allocation.iter().zip(&capacities) {
+            assert!(indices.len() <= capacity);
+        }
+
+        // What each frame would carry, read back into whole copies
+        let carried: Vec<Vec<u8>> = allocation
+            .iter()
+            .map(|indices| indices.iter().map(|&index| stream[index]).collect())
+            .collect();
+        assert_eq!(gather_copies(&allocation, &carried, stream.len()), [stream.clone(), stream]);
+    }
+
+    #[test]
+    fn uncapped_frames_each_carry_the_whole_stream() {
+        let allocation = allocate_bits(&[10, 10, 10], 4);
+        assert!(allocation.iter().all(|indices| *indices == [0, 1, 2, 3]));
+        assert!(allocate_bits(&[3, 3], 0).iter().all(Vec::is_empty));
+    }
+}
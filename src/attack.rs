@@ -0,0 +1,228 @@
+use std::error::Error;
+use std::path::Path;
+
+use hound::{SampleFormat, WavReader, WavWriter};
+
+use crate::channels::{deinterleave, interleave};
+use crate::resample::resample_interleaved;
+
+// =============================================================================
+// ATTACK - Deliberate degradations for measuring how robust the watermark is
+// =============================================================================
+//
+// Each attack takes samples in [-1, 1] and returns the damaged copy, the way
+// a lossy step in a real pipeline would leave them. `attack` on the command
+// line applies one to a 16-bit WAV and writes the result back as 16-bit, so
+// the decoder reads it like any other file.
+
+// 16-bit full scale, as the encoder and decoder normalize
+const SAMPLE_DIVISOR: f32 = 32768.0;
+
+// Round to `bits` of resolution and back: 8 bits is the 256 levels of an
+// 8-bit WAV. Adds roughly uniform noise one step wide (-42 dBFS at 8 bits),
+// spread evenly over the spectrum, so the quiet suppressed bins fill in.
+pub fn requantize(samples: &[f32], bits: u32) -> Vec<f32> {
+    let levels = (1u32 << (bits.clamp(1, 16) - 1)) as f32; // steps per half scale
+    samples
+        .iter()
+        .map(|&sample| (sample * levels).round().clamp(-levels, levels - 1.0) / levels)
+        .collect()
+}
+
+// Seed for the noise attacks (robustness, verify), fixed so two runs print
+// the same numbers
+pub const NOISE_SEED: u64 = 0x5eed;
+
+// Added-noise levels `robustness` sweeps, from barely there to as loud as
+// the audio itself
+pub const NOISE_SWEEP_DB: [f32; 9] = [40.0, 35.0, 30.0, 25.0, 20.0, 15.0, 10.0, 5.0, 0.0];
+
+// White Gaussian noise `snr_db` below the samples' own mean power: 0 dB adds
+// noise as loud as the signal. Drawn from a xorshift stream (Box-Muller), so
+// the same seed gives the same noise and a sweep can be rerun exactly.
+pub fn add_noise(samples: &[f32], snr_db: f32, seed: u64) -> Vec<f32> {
+    let power = samples.iter().map(|&x| f64::from(x) * f64::from(x)).sum::<f64>()
+        / samples.len().max(1) as f64;
+    let sigma = (power / 10f64.powf(f64::from(snr_db) / 10.0)).sqrt();
+
+    let mut state = seed.max(1); // xorshift never leaves 0
+    let mut uniform = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        ((state >> 11) as f64 + 0.5) / (1u64 << 53) as f64 // in (0, 1), so ln is finite
+    };
+    samples
+        .iter()
+        .map(|&sample| {
+            let radius = (-2.0 * uniform().ln()).sqrt();
+            let gaussian = radius * (std::f64::consts::TAU * uniform()).cos();
+            sample + (sigma * gaussian) as f32
+        })
+        .collect()
+}
+
+// Louder or quieter by `db`, clipped at full scale the way a fixed-point
+// gain stage would be
+pub fn gain(samples: &[f32], db: f32) -> Vec<f32> {
+    let factor = 10f32.powf(db / 20.0);
+    samples.iter().map(|&sample| (sample * factor).clamp(-1.0, 1.0)).collect()
+}
+
+// Taps in lowpass's filter: long enough for a sharp edge at 8 kHz
+const LOWPASS_TAPS: usize = 101;
+
+// Windowed-sinc (Hamming) low-pass at `cutoff_hz`, zero-phase, one channel
+// at a time. Bins above the cutoff lose their marks; bins below keep them.
+pub fn lowpass(samples: &[f32], channels: u16, sample_rate: u32, cutoff_hz: f32) -> Vec<f32> {
+    let fc = (cutoff_hz / sample_rate as f32).min(0.5); // cycles per sample
+    let centre = LOWPASS_TAPS / 2;
+    let mut taps: Vec<f32> = (0..LOWPASS_TAPS)
+        .map(|n| {
+            let t = n as f32 - centre as f32;
+            let sinc = if t == 0.0 {
+                2.0 * fc
+            } else {
+                (2.0 * std::f32::consts::PI * fc * t).sin() / (std::f32::consts::PI * t)
+            };
+            let phase = 2.0 * std::f32::consts::PI * n as f32 / (LOWPASS_TAPS - 1) as f32;
+            sinc * (0.54 - 0.46 * phase.cos())
+        })
+        .collect();
+    let sum: f32 = taps.iter().sum();
+    taps.iter_mut().for_each(|tap| *tap /= sum); // unity gain at DC
+
+    let tracks: Vec<Vec<f32>> = deinterleave(samples, usize::from(channels.max(1)))
+        .iter()
+        .map(|track| {
+            (0..track.len())
+                .map(|index| {
+                    taps.iter()
+                        .enumerate()
+                        .filter_map(|(k, tap)| {
+                            let source = (index + k).checked_sub(centre)?;
+                            track.get(source).map(|sample| sample * tap)
+                        })
+                        .sum()
+                })
+                .collect()
+        })
+        .collect();
+    interleave(&tracks)
+}
+
+// Out to `rate` and back again, as a file converted for another system and
+// returned would be
+pub fn resample_round_trip(samples: &[f32], channels: u16, sample_rate: u32, rate: u32) -> Vec<f32> {
+    let converted = resample_interleaved(samples, channels, sample_rate, rate);
+    resample_interleaved(&converted, channels, rate, sample_rate)
+}
+
+// One named degradation, as `verify --attack NAME:VALUE` spells it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Attack {
+    Noise { snr_db: f32 },
+    Requantize { bits: u32 },
+    Gain { db: f32 },
+    Lowpass { cutoff_hz: f32 },
+    Resample { rate: u32 },
+}
+
+impl Attack {
+    // "noise:20" (SNR dB), "requantize:8" (bits), "gain:-6" (dB),
+    // "lowpass:3000" (Hz), "resample:16000" (Hz)
+    pub fn parse(spec: &str) -> Result<Attack, String> {
+        let (name, value) = spec
+            .split_once(':')
+            .ok_or_else(|| format!("attack {:?} needs a value, like noise:20", spec))?;
+        let number = || {
+            value
+                .parse::<f32>()
+                .ok()
+                .filter(|number| number.is_finite())
+                .ok_or_else(|| format!("attack {:?} expects a number after the colon", spec))
+        };
+        let positive = || match number()? {
+            number if number > 0.0 => Ok(number),
+            _ => Err(format!("attack {:?} expects a positive value", spec)),
+        };
+        match name {
+            "noise" => Ok(Attack::Noise { snr_db: number()? }),
+            "requantize" => match value.parse() {
+                Ok(bits @ 1..=16) => Ok(Attack::Requantize { bits }),
+                _ => Err(format!("attack {:?} expects 1 to 16 bits", spec)),
+            },
+            "gain" => Ok(Attack::Gain { db: number()? }),
+            "lowpass" => Ok(Attack::Lowpass { cutoff_hz: positive()? }),
+            "resample" => match value.parse() {
+                Ok(rate) if rate > 0 => Ok(Attack::Resample { rate }),
+                _ => Err(format!("attack {:?} expects a whole sample rate in Hz", spec)),
+            },
+            "mp3" => Err("mp3 needs an MP3 codec, which this crate doesn't include; \
+                          requantize and lowpass approximate its damage"
+                .to_string()),
+            _ => Err(format!(
+                "unknown attack {:?} (expected noise, requantize, gain, lowpass or resample)",
+                name
+            )),
+        }
+    }
+
+    // The damaged copy of interleaved `samples`
+    pub fn apply(self, samples: &[f32], channels: u16, sample_rate: u32) -> Vec<f32> {
+        match self {
+            Attack::Noise { snr_db } => add_noise(samples, snr_db, NOISE_SEED),
+            Attack::Requantize { bits } => requantize(samples, bits),
+            Attack::Gain { db } => gain(samples, db),
+            Attack::Lowpass { cutoff_hz } => lowpass(samples, channels, sample_rate, cutoff_hz),
+            Attack::Resample { rate } => resample_round_trip(samples, channels, sample_rate, rate),
+        }
+    }
+}
+
+// Read 16-bit `input`, run `damage` over its interleaved samples and write
+// the result to `output` with the same format
+pub fn attack_file(
+    input: &Path,
+    output: &Path,
+    damage: impl FnOnce(&[f32]) -> Vec<f32>,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = WavReader::open(input)?;
+    let spec = reader.spec();
+    if spec.sample_format != SampleFormat::Int || spec.bits_per_sample != 16 {
+        return Err(format!(
+            "attacks read 16-bit integer WAVs; {} is {}-bit {:?}",
+            input.display(),
+            spec.bits_per_sample,
+            spec.sample_format
+        )
+        .into());
+    }
+    let samples = reader
+        .samples::<i16>()
+        .map(|sample| sample.map(|sample| sample as f32 / SAMPLE_DIVISOR))
+        .collect::<Result<Vec<f32>, _>>()?;
+
+    let damaged = damage(&samples);
+
+    let mut writer = WavWriter::create(output, spec)?;
+    for sample in damaged {
+        let value = (sample * SAMPLE_DIVISOR).round().clamp(i16::MIN as f32, i16::MAX as f32);
+        writer.write_sample(value as i16)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resample_rate_must_be_a_positive_integer() {
+        assert_eq!(Attack::parse("resample:16000"), Ok(Attack::Resample { rate: 16000 }));
+        for spec in ["resample:0", "resample:0.5", "resample:-8000", "resample:16000.0"] {
+            assert!(Attack::parse(spec).is_err(), "{} should not parse", spec);
+        }
+    }
+}
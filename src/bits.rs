@@ -0,0 +1,95 @@
+// =============================================================================
+// BITS - Byte <-> bit conversion for the embedded stream
+// =============================================================================
+//
+// Every byte on the wire (length header, CRC, payload, sentinel) travels
+// MSB first, one bit per u8 holding 0 or 1. The encoder and the decoder both
+// go through these functions, so the two sides can't disagree on bit order.
+
+// Which end of a byte goes first; the stream itself only uses MsbFirst
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+impl BitOrder {
+    // Shift of the `index`th bit sent from a byte
+    fn shift(self, index: usize) -> usize {
+        match self {
+            BitOrder::MsbFirst => 7 - index,
+            BitOrder::LsbFirst => index,
+        }
+    }
+}
+
+// Each byte as eight bits, in `order`
+pub fn bytes_to_bits(bytes: &[u8], order: BitOrder) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|&byte| (0..8).map(move |index| (byte >> order.shift(index)) & 1))
+        .collect()
+}
+
+// Inverse of bytes_to_bits. A short final chunk puts its bits where a whole
+// byte's first bits would go and leaves the rest 0 (the high bits MSB first,
+// the low bits LSB first), so a truncated stream keeps every bit it has in
+// the position it was sent in.
+pub fn bits_to_bytes(bits: &[u8], order: BitOrder) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (index, bit)| byte | ((bit & 1) << order.shift(index)))
+        })
+        .collect()
+}
+
+// Up to 16 bits read as an unsigned big-endian number
+pub fn bits_to_number(bits: &[u8]) -> usize {
+    bits.iter()
+        .fold(0u16, |value, bit| (value << 1) | u16::from(bit & 1)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [BitOrder; 2] = [BitOrder::MsbFirst, BitOrder::LsbFirst];
+
+    #[test]
+    fn each_order_sends_its_own_end_first() {
+        assert_eq!(bytes_to_bits(&[0b1000_0110], BitOrder::MsbFirst), [1, 0, 0, 0, 0, 1, 1, 0]);
+        assert_eq!(bytes_to_bits(&[0b1000_0110], BitOrder::LsbFirst), [0, 1, 1, 0, 0, 0, 0, 1]);
+        let bytes: Vec<u8> = (0..=255).collect();
+        for order in ORDERS {
+            assert_eq!(bits_to_bytes(&bytes_to_bits(&bytes, order), order), bytes, "{:?}", order);
+        }
+        assert!(bytes_to_bits(&[], BitOrder::MsbFirst).is_empty());
+        assert!(bits_to_bytes(&[], BitOrder::MsbFirst).is_empty());
+    }
+
+    #[test]
+    fn a_partial_byte_keeps_its_bits_in_place() {
+        // 0xA5 cut off after 3 bits, in either order
+        assert_eq!(bits_to_bytes(&[1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1], BitOrder::MsbFirst), [0xa5, 0b1010_0000]);
+        assert_eq!(bits_to_bytes(&[1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1], BitOrder::LsbFirst), [0xa5, 0b0000_0101]);
+        for order in ORDERS {
+            for kept in 1..8 {
+                let bits = &bytes_to_bits(&[0xff], order)[..kept];
+                let byte = bits_to_bytes(bits, order)[0];
+                // The bits kept still read back first, and nothing else is set
+                assert_eq!(bytes_to_bits(&[byte], order)[..kept], *bits);
+                assert_eq!(byte.count_ones() as usize, kept, "{:?} {}", order, kept);
+            }
+        }
+    }
+
+    #[test]
+    fn numbers_read_big_endian() {
+        assert_eq!(bits_to_number(&[1, 0, 1]), 5);
+        assert_eq!(bits_to_number(&bytes_to_bits(&[0x12, 0x34], BitOrder::MsbFirst)), 0x1234);
+        assert_eq!(bits_to_number(&[]), 0);
+    }
+}
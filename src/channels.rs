@@ -0,0 +1,67 @@
+// =============================================================================
+// CHANNELS - Split interleaved WAV samples into per-channel tracks and back
+// =============================================================================
+//
+// WAV stores multichannel audio interleaved, one sample per channel per
+// frame (L R L R ... for stereo, FL FR C LFE SL SR for 5.1). The embedder
+// works on one channel at a time, so the encoder deinterleaves, watermarks
+// the chosen channels and interleaves again; the decoder keeps one channel.
+// Stereo files can also be watermarked in mid/side form (to_mid_side).
+
+// One track per channel; a trailing partial frame is dropped
+pub fn deinterleave(samples: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    let frames = samples.len() / channels;
+    (0..channels)
+        .map(|channel| {
+            samples
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .take(frames)
+                .copied()
+                .collect()
+        })
+        .collect()
+}
+
+// Inverse of deinterleave; every track must have the same length
+pub fn interleave(tracks: &[Vec<f32>]) -> Vec<f32> {
+    let frames = tracks.first().map_or(0, Vec::len);
+    (0..frames)
+        .flat_map(|frame| tracks.iter().map(move |track| track[frame]))
+        .collect()
+}
+
+// Loudest channel at every instant, so a silence trim only drops frames
+// that are quiet on all channels
+pub fn peak_track(tracks: &[Vec<f32>]) -> Vec<f32> {
+    let frames = tracks.first().map_or(0, Vec::len);
+    (0..frames)
+        .map(|frame| {
+            tracks
+                .iter()
+                .map(|track| track[frame].abs())
+                .fold(0.0f32, f32::max)
+        })
+        .collect()
+}
+
+// [left, right] -> [mid, side], with mid = (L+R)/2 and side = (L-R)/2
+pub fn to_mid_side(tracks: &mut [Vec<f32>]) {
+    let [left, right] = tracks else {
+        return; // only defined for a stereo pair
+    };
+    for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+        (*l, *r) = ((*l + *r) * 0.5, (*l - *r) * 0.5);
+    }
+}
+
+// [mid, side] -> [left, right]: L = M + S, R = M - S
+pub fn from_mid_side(tracks: &mut [Vec<f32>]) {
+    let [mid, side] = tracks else {
+        return;
+    };
+    for (m, s) in mid.iter_mut().zip(side.iter_mut()) {
+        (*m, *s) = (*m + *s, *m - *s);
+    }
+}
@@ -0,0 +1,51 @@
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+// =============================================================================
+// COMPRESS - Optional deflate of the payload (--compress on both sides)
+// =============================================================================
+//
+// One flag byte leads the payload bytes (after the type tag, if any, has
+// been added), so the decoder knows whether to inflate:
+//
+//   Stored    0x00 | bytes
+//   Deflated  0x01 | raw deflate stream (no zlib header or checksum)
+//
+// Deflate only pays off on longer, repetitive text; a short message usually
+// grows, so the encoder stores it whenever deflating wouldn't save a byte.
+// The flag is part of the payload, so the length header counts it.
+
+const FLAG_STORED: u8 = 0x00;
+const FLAG_DEFLATED: u8 = 0x01;
+
+// Flag byte + the smaller of the deflated and the stored bytes
+pub fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(vec![FLAG_DEFLATED], Compression::best());
+    let deflated = encoder
+        .write_all(bytes)
+        .and_then(|()| encoder.finish())
+        .expect("deflating into memory failed");
+    if deflated.len() < bytes.len() + 1 {
+        deflated
+    } else {
+        [&[FLAG_STORED][..], bytes].concat()
+    }
+}
+
+// Inverse of compress. None for an unknown flag or a stream that doesn't
+// inflate (a damaged payload).
+pub fn decompress(bytes: &[u8]) -> Option<Vec<u8>> {
+    let (&flag, body) = bytes.split_first()?;
+    match flag {
+        FLAG_STORED => Some(body.to_vec()),
+        FLAG_DEFLATED => {
+            let mut inflated = Vec::new();
+            DeflateDecoder::new(body).read_to_end(&mut inflated).ok()?;
+            Some(inflated)
+        }
+        _ => None,
+    }
+}
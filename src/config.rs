@@ -0,0 +1,581 @@
+use crate::payload::Payload;
+use crate::window::Window;
+
+// =============================================================================
+// Watermark configuration shared by the encoder and the decoder
+// =============================================================================
+
+// Which embedding algorithm carries the bits
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scheme {
+    // Boost / suppress bin magnitudes in every frame (the original scheme)
+    #[default]
+    Amplitude,
+    // Write bits into the absolute phase of the first frame (see phase.rs)
+    PhaseCoding,
+}
+
+impl Scheme {
+    // Parse the name used on the command line
+    pub fn from_name(name: &str) -> Option<Scheme> {
+        match name {
+            "amplitude" => Some(Scheme::Amplitude),
+            "phase" => Some(Scheme::PhaseCoding),
+            _ => None,
+        }
+    }
+
+    // Name used on the command line and in reports
+    pub fn name(self) -> &'static str {
+        match self {
+            Scheme::Amplitude => "amplitude",
+            Scheme::PhaseCoding => "phase",
+        }
+    }
+
+    // Bins of a `spectrum_len`-bin spectrum this scheme can write to
+    pub fn carrier_bins(self, spectrum_len: usize) -> usize {
+        self.algorithm().carrier_bins(spectrum_len)
+    }
+}
+
+// How the decoder learns where the message ends
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    // 16-bit byte count right after the pilot (caps messages at 65535 bytes)
+    #[default]
+    LengthHeader,
+    // No length; a reserved end-of-message pattern follows the bit-stuffed payload
+    Sentinel,
+    // Variable-length byte count (see varint.rs): 8 bits for short messages,
+    // more only when needed
+    Varint,
+}
+
+impl Framing {
+    pub fn from_name(name: &str) -> Option<Framing> {
+        match name {
+            "length" => Some(Framing::LengthHeader),
+            "sentinel" => Some(Framing::Sentinel),
+            "varint" => Some(Framing::Varint),
+            _ => None,
+        }
+    }
+}
+
+// End-of-message pattern for sentinel framing (0x7E, as in HDLC).
+// The payload is bit-stuffed (see stuffing.rs) so the pattern can't occur inside it.
+pub const SENTINEL: u8 = 0x7E;
+
+// How the bit-carrying bins are picked out of the band
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BinSpacing {
+    // Every bin (minus guards), the original layout
+    #[default]
+    Linear,
+    // Bins at least LOG_BIN_RATIO apart in frequency, so the bits spread
+    // evenly on a pitch scale: dense at the bottom, sparse at the top,
+    // the way hearing resolves frequency. Holds far fewer bits.
+    Log,
+}
+
+// Log spacing: a quarter tone (2^(1/24)) between consecutive carrier bins.
+// Below about 34 bins neighbours are already that far apart, so the low end
+// stays linear, like the mel scale; the default band keeps 73 of 119 bins.
+const LOG_BIN_RATIO: f32 = 1.0293;
+
+impl BinSpacing {
+    pub fn from_name(name: &str) -> Option<BinSpacing> {
+        match name {
+            "linear" => Some(BinSpacing::Linear),
+            "log" => Some(BinSpacing::Log),
+            _ => None,
+        }
+    }
+}
+
+// FFT bins that carry watermark bits, low_bin..high_bin (high_bin exclusive).
+// With 256-sample frames at 8 kHz each bin is 31.25 Hz wide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FreqBand {
+    pub low_bin: usize,
+    pub high_bin: usize,
+    pub spacing: BinSpacing,
+}
+
+impl FreqBand {
+    // Bin 10 (~310 Hz, below it is most speech energy) up to Nyquist, the original layout
+    pub const FULL: FreqBand = FreqBand {
+        low_bin: 10,
+        high_bin: 129,
+        spacing: BinSpacing::Linear,
+    };
+    // 3 kHz up to Nyquist at 8 kHz, clear of the 2-3 kHz formant region.
+    // Only 33 bins, so it suits short sentinel-framed messages.
+    pub const SPEECH: FreqBand = FreqBand {
+        low_bin: 96,
+        high_bin: 129,
+        spacing: BinSpacing::Linear,
+    };
+
+    // Parse a preset name ("full", "speech") or an explicit "LOW-HIGH" bin range
+    pub fn from_name(name: &str) -> Option<FreqBand> {
+        match name {
+            "full" => Some(FreqBand::FULL),
+            "speech" => Some(FreqBand::SPEECH),
+            _ => {
+                let (low, high) = name.split_once('-')?;
+                let band = FreqBand {
+                    low_bin: low.parse().ok()?,
+                    high_bin: high.parse().ok()?,
+                    spacing: BinSpacing::Linear,
+                };
+                (band.low_bin < band.high_bin).then_some(band)
+            }
+        }
+    }
+
+    // The part of the band that exists in a spectrum of `spectrum_len` bins.
+    // Encoder and decoder both go through this, so an over-wide band shrinks
+    // the same way on each side instead of erroring.
+    pub fn clamp_to(self, spectrum_len: usize) -> FreqBand {
+        let high_bin = self.high_bin.min(spectrum_len);
+        FreqBand {
+            low_bin: self.low_bin.min(high_bin),
+            high_bin,
+            spacing: self.spacing,
+        }
+    }
+
+    pub fn bin_count(self) -> usize {
+        self.high_bin - self.low_bin
+    }
+
+    // Bins that carry bits, in order: the band minus every `guard`th bin
+    // (0 = no guards). Guard bins are never modified, so a bit's leakage
+    // spills into a guard instead of the next bit. Log spacing then keeps
+    // only the bins a quarter tone or more above the previous one kept; the
+    // set depends on the band alone, so the decoder finds the same bins.
+    pub fn data_bins(self, guard: usize) -> impl Iterator<Item = usize> + Clone {
+        let low_bin = self.low_bin;
+        let spacing = self.spacing;
+        let mut next = 0usize; // lowest bin the next log-spaced pick may use
+        // Nothing but 0 is a multiple of 0, so guard 0 keeps every bin
+        (self.low_bin..self.high_bin)
+            .filter(move |bin| !(bin - low_bin + 1).is_multiple_of(guard))
+            .filter(move |&bin| match spacing {
+                BinSpacing::Linear => true,
+                BinSpacing::Log if bin < next => false,
+                BinSpacing::Log => {
+                    next = ((bin as f32 * LOG_BIN_RATIO).round() as usize).max(bin + 1);
+                    true
+                }
+            })
+    }
+
+    // Bits one frame of this band holds with `guard`
+    pub fn capacity(self, guard: usize) -> usize {
+        self.data_bins(guard).count()
+    }
+
+    // Bins that carry a `bit_count`-bit sequence: bit i goes to the ith data bin
+    pub fn bit_bins(self, bit_count: usize, guard: usize) -> impl Iterator<Item = usize> + Clone {
+        self.data_bins(guard).take(bit_count)
+    }
+}
+
+impl Default for FreqBand {
+    fn default() -> Self {
+        FreqBand::FULL
+    }
+}
+
+// Which signal the watermark goes into on a multichannel file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelStrategy {
+    // The channels listed in WatermarkConfig::channels, as stored
+    #[default]
+    Discrete,
+    // Stereo only: the mid (L+R)/2 or side (L-R)/2 signal. Side embedding
+    // is often less audible because it cancels when the pair is summed.
+    MidSide(MidSideChannel),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidSideChannel {
+    Mid,
+    Side,
+}
+
+impl ChannelStrategy {
+    pub fn from_name(name: &str) -> Option<ChannelStrategy> {
+        match name {
+            "discrete" => Some(ChannelStrategy::Discrete),
+            "mid" => Some(ChannelStrategy::MidSide(MidSideChannel::Mid)),
+            "side" => Some(ChannelStrategy::MidSide(MidSideChannel::Side)),
+            _ => None,
+        }
+    }
+}
+
+// A position in the file: a sample index, or a time converted at the file's rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Offset {
+    Samples(usize),
+    Seconds(f32),
+}
+
+impl Offset {
+    // "48000" is a sample index, "1.5s" a time
+    pub fn parse(value: &str) -> Option<Offset> {
+        match value.strip_suffix('s') {
+            Some(seconds) => seconds
+                .parse()
+                .ok()
+                .filter(|seconds: &f32| seconds.is_finite() && *seconds >= 0.0)
+                .map(Offset::Seconds),
+            None => value.parse().ok().map(Offset::Samples),
+        }
+    }
+
+    pub fn to_samples(self, sample_rate: u32) -> usize {
+        match self {
+            Offset::Samples(samples) => samples,
+            Offset::Seconds(seconds) => (seconds * sample_rate as f32).round() as usize,
+        }
+    }
+}
+
+// Sample layout of the encoded file (--output-format). Each field left None
+// keeps the default: 16-bit integer, the input's channel count and the rate
+// the watermark was embedded at.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputFormat {
+    // Integer bits per sample: 16, 24 or 32
+    pub bits: Option<u16>,
+    // 32-bit float samples, written as embedded with no quantization
+    pub float: bool,
+    pub channels: Option<u16>,
+    // Embed (and write) at this rate; the same as --resample
+    pub rate: Option<u32>,
+}
+
+impl OutputFormat {
+    pub const BIT_DEPTHS: [u16; 3] = [16, 24, 32];
+
+    // "bits=24,channels=2,rate=44100", any subset in any order; "float"
+    // in place of bits= (or beside bits=32) asks for float samples
+    pub fn parse(value: &str) -> Option<OutputFormat> {
+        let mut format = OutputFormat::default();
+        for field in value.split(',') {
+            if field.trim() == "float" {
+                format.float = true;
+                continue;
+            }
+            let (key, number) = field.split_once('=')?;
+            match key.trim() {
+                "bits" => {
+                    let bits = number.trim().parse().ok();
+                    format.bits = Some(bits.filter(|bits| Self::BIT_DEPTHS.contains(bits))?);
+                }
+                "channels" => format.channels = Some(number.trim().parse().ok().filter(|&n| n > 0)?),
+                "rate" => format.rate = Some(number.trim().parse().ok().filter(|&n| n > 0)?),
+                _ => return None,
+            }
+        }
+        if format.float && format.bits.is_some_and(|bits| bits != 32) {
+            return None; // float samples are 32-bit only
+        }
+        Some(format)
+    }
+}
+
+// What the encoder does with NaN or infinite samples in a float input
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonFiniteMode {
+    // Replace each one with silence and report how many there were
+    #[default]
+    Zero,
+    // Refuse the file
+    Error,
+}
+
+impl NonFiniteMode {
+    pub fn from_name(name: &str) -> Option<NonFiniteMode> {
+        match name {
+            "zero" => Some(NonFiniteMode::Zero),
+            "error" => Some(NonFiniteMode::Error),
+            _ => None,
+        }
+    }
+}
+
+// How quantize maps a scaled sample onto an integer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoundMode {
+    // Round half away from zero (f32::round), the original behaviour
+    #[default]
+    Nearest,
+    // Round half to even (banker's rounding), matches most DSP tools
+    HalfEven,
+    // Drop the fractional part (round toward zero)
+    Truncate,
+}
+
+impl RoundMode {
+    pub fn from_name(name: &str) -> Option<RoundMode> {
+        match name {
+            "nearest" => Some(RoundMode::Nearest),
+            "even" => Some(RoundMode::HalfEven),
+            "trunc" => Some(RoundMode::Truncate),
+            _ => None,
+        }
+    }
+}
+
+// Whether the encoder writes the watermark at all
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EmbedMode {
+    #[default]
+    Watermark,
+    // Load, frame, FFT, IFFT, quantize and write as usual, but change no
+    // bin: a control file whose only difference from the input is the
+    // pipeline's own round trip, for A/B listening against the watermark
+    None,
+    // Amplitude scheme: a 1 bin is boosted as usual but never left below
+    // an absolute floor (floor_db), so bits survive in silent or near-silent
+    // frames where a multiple of almost nothing is still almost nothing.
+    // 0 bins are suppressed as usual. Phase is kept either way.
+    AdditiveFloor,
+}
+
+impl EmbedMode {
+    pub fn from_name(name: &str) -> Option<EmbedMode> {
+        match name {
+            "watermark" => Some(EmbedMode::Watermark),
+            "none" => Some(EmbedMode::None),
+            "floor" => Some(EmbedMode::AdditiveFloor),
+            _ => None,
+        }
+    }
+}
+
+// Settings for the encode and decode pipelines. The watermark fields must
+// match on both sides for a watermark to round-trip; the rest only affect
+// one half.
+#[derive(Clone, Debug, Default)]
+pub struct WatermarkConfig {
+    // --- Watermark format (encoder and decoder) ---
+    pub scheme: Scheme,
+    pub framing: Framing,
+    // Analysis window for the amplitude scheme; phase coding always uses raw frames
+    pub window: Window,
+    // Bins the bits are written to
+    pub band: FreqBand,
+    // Carry a random nonce and scramble the payload with it, so the same
+    // message embedded twice gives different audio
+    pub nonce: bool,
+    // Lead the payload with a type tag (see payload.rs)
+    pub typed_payload: bool,
+    // Deflate the payload behind a stored / deflated flag (see compress.rs)
+    pub compress: bool,
+    // Follow the payload with an HMAC-SHA256 tag under this key
+    pub key: Option<Vec<u8>>,
+    // Leave every `guard`th bin of the band unmodified (0 = no guard bins)
+    pub guard: usize,
+    // Analysis window length in milliseconds; None keeps DEFAULT_WINDOW_MS
+    pub window_ms: Option<f32>,
+    // Fraction of each frame shared with the next (amplitude scheme only);
+    // 0.0 keeps the original back-to-back frames
+    pub overlap: f32,
+    // Carry at most this many bits per frame (amplitude scheme): the
+    // sequence splits into groups of N, and consecutive frames take turns
+    // carrying one group each. None carries the whole sequence in every frame.
+    pub max_bits_per_frame: Option<usize>,
+    // Channels carrying the watermark; the decoder reads the first one.
+    // Empty means channel 0 (front left in a stereo or 5.1 file).
+    pub channels: Vec<usize>,
+    // Discrete channels, or the mid / side signal of a stereo pair
+    pub channel_strategy: ChannelStrategy,
+    // One message per `channels` entry (--channel-map), in the same order.
+    // Empty means every carrier gets the message passed to the encoder.
+    pub channel_messages: Vec<Payload>,
+
+    // --- Input file (encoder and decoder) ---
+    // Keep the samples before a read error (a data chunk shorter than its
+    // header says) instead of aborting
+    pub allow_truncated: bool,
+
+    // --- Input rate (encoder and decoder) ---
+    // Linearly resample the input to this rate before embedding / decoding
+    pub resample_to: Option<u32>,
+
+    // --- Alignment (encoder and decoder) ---
+    // Samples at the start of each track (at the embedding rate, after any
+    // silence trim) that the encoder leaves untouched, so the first frame
+    // lines up with a known cue; the decoder starts reading there
+    pub embed_offset: usize,
+
+    // --- Decoder input ---
+    // Skip to this point of the carrier channel before aligning and decoding
+    pub since: Option<Offset>,
+    // Decide every bit against this score (log ratio of a bin to its
+    // neighbours) instead of the one the pilot calibrates, and use every frame
+    // rather than those whose pilot checks out (amplitude scheme). For files
+    // whose pilot bins were destroyed; a wrong value decodes garbage.
+    pub threshold: Option<f32>,
+
+    // --- Debugging (encoder and decoder) ---
+    // Only embed / analyse the first N frames; later audio passes through
+    pub limit_frames: Option<usize>,
+
+    // --- Performance (decoder) ---
+    // Threads the per-frame spectra are computed on; None uses one per core.
+    // Each thread takes a contiguous run of frames, so the count never
+    // changes the result.
+    pub threads: Option<usize>,
+
+    // --- Encoder policy ---
+    // Hard cap on message size, enforced even when the frame could hold more
+    pub max_message_bytes: Option<usize>,
+
+    // --- Encoder input ---
+    // NaN / Inf samples: zero them or reject the file
+    pub non_finite: NonFiniteMode,
+
+    // --- Encoder preprocessing ---
+    // Subtract each channel's mean before embedding
+    pub remove_dc: bool,
+    // Trim leading/trailing samples quieter than this many dBFS before embedding
+    pub trim_silence_db: Option<f32>,
+    // Put the trimmed silence back on output (leading trim snaps to whole frames)
+    pub repad_silence: bool,
+    // Pad the input to at least this many seconds before embedding
+    pub pad_to_seconds: Option<f32>,
+    // Pad by looping the input instead of with zeros (zeros carry no watermark)
+    pub pad_loop: bool,
+    // Peak-normalize the input to near full scale before embedding, so a
+    // quiet file gives the watermark more signal to ride on
+    pub normalize_input: bool,
+    // Undo the normalization gain on output, keeping the input's level
+    pub restore_level: bool,
+    // Scale the input down by this many dB to leave headroom for the boost
+    pub attenuate_db: Option<f32>,
+
+    // --- Transparency ---
+    // Refuse to encode when the watermark leaves a carrier channel with a
+    // lower SNR than this, in dB
+    pub min_snr_db: Option<f32>,
+    // Under min_snr_db, weaken the watermark and re-embed instead of refusing
+    pub snr_retry: bool,
+    // Boost / suppress as a power of the full factors: 1.0 (None) is full
+    // strength, smaller is gentler. The decoder is indifferent to it.
+    pub strength: Option<f32>,
+    // Fade the watermark in over the first N frames and out over the last N
+    // (amplitude scheme), so it doesn't start abruptly; 0 embeds every frame
+    // at full strength. The decoder leaves those frames out of its vote.
+    pub ramp_frames: usize,
+    // Leave frames quieter than this many dBFS (RMS) unmarked (amplitude
+    // scheme); the decoder leaves them out, so both sides need the same value
+    pub vad_threshold_db: Option<f32>,
+    // Weaken the factors frame by frame wherever the embedded samples would
+    // leave [-1, 1] (amplitude scheme), so nothing clips; the per-frame
+    // decoder threshold follows the weaker contrast
+    pub headroom: bool,
+    // EmbedMode::None leaves the audio unwatermarked (a matched control)
+    pub embed_mode: EmbedMode,
+    // EmbedMode::AdditiveFloor's floor, as the dBFS of a full-frame sine
+    // that peaks in the bin; None is DEFAULT_FLOOR_DBFS
+    pub floor_db: Option<f32>,
+    // Re-embed the full message every this many seconds (rounded to whole
+    // frames), each copy with its own pilot, for schemes that write it once
+    // (phase coding). `decode --all` finds every surviving copy.
+    pub repeat_every: Option<f32>,
+
+    // --- Encoder output ---
+    // Replace an existing output file instead of refusing to write
+    pub overwrite: bool,
+    pub round_mode: RoundMode,
+    // Bit depth and channel count of the written file (the rate goes through
+    // resample_to)
+    pub output_format: OutputFormat,
+}
+
+// Frame lengths an explicit window may produce. Any length in between works:
+// both sides transform exactly one frame, so their bins line up.
+pub const MIN_FRAME_LEN: usize = 32;
+pub const MAX_FRAME_LEN: usize = 16384;
+
+// Default analysis window: 256 samples at 8 kHz
+pub const DEFAULT_WINDOW_MS: f32 = 32.0;
+
+// Samples per frame of a `window_ms` window at `sample_rate`. The encoder and
+// the decoder both derive their frame length here, so they can't disagree.
+pub fn frame_len_for(sample_rate: u32, window_ms: f32) -> usize {
+    ((sample_rate as f32 * window_ms / 1000.0).round() as usize).max(1)
+}
+
+impl WatermarkConfig {
+    // Samples per frame for an explicit window_ms; Err carries the length
+    // when it isn't usable
+    pub fn window_frame_len(&self, sample_rate: u32) -> Option<Result<usize, usize>> {
+        self.window_ms.map(|ms| {
+            let frame_len = frame_len_for(sample_rate, ms);
+            if (MIN_FRAME_LEN..=MAX_FRAME_LEN).contains(&frame_len) {
+                Ok(frame_len)
+            } else {
+                Err(frame_len)
+            }
+        })
+    }
+
+    // Worker threads for the decoder's frame analysis (--threads)
+    pub fn worker_threads(&self) -> usize {
+        self.threads.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |cores| cores.get())
+        })
+    }
+
+    // Tracks that carry the watermark. In mid/side mode the tracks are
+    // [mid, side] rather than [left, right].
+    pub fn carrier_channels(&self) -> &[usize] {
+        if let ChannelStrategy::MidSide(channel) = self.channel_strategy {
+            return match channel {
+                MidSideChannel::Mid => &[0],
+                MidSideChannel::Side => &[1],
+            };
+        }
+        if self.channels.is_empty() {
+            &[0]
+        } else {
+            &self.channels
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Centre frequency of `bin` in a 256-point spectrum at 8 kHz
+    fn bin_hz(bin: usize) -> f32 {
+        bin as f32 * 8000.0 / 256.0
+    }
+
+    #[test]
+    fn speech_band_stays_above_the_formants() {
+        assert_eq!(FreqBand::from_name("speech"), Some(FreqBand::SPEECH));
+        let bins: Vec<usize> = FreqBand::SPEECH.data_bins(0).collect();
+        assert!(bins.iter().all(|&bin| bin_hz(bin) >= 3000.0), "{:?}", bins);
+        assert!(FreqBand::FULL.data_bins(0).any(|bin| (2000.0..3000.0).contains(&bin_hz(bin))));
+    }
+
+    #[test]
+    fn clamp_keeps_the_part_of_the_band_that_exists() {
+        let band = FreqBand::from_name("60-400").unwrap();
+        assert_eq!((band.clamp_to(129).low_bin, band.clamp_to(129).high_bin), (60, 129));
+        let beyond = FreqBand::from_name("200-400").unwrap().clamp_to(129);
+        assert_eq!((beyond.low_bin, beyond.high_bin), (129, 129)); // empty, not inverted
+        assert_eq!(beyond.capacity(0), 0);
+    }
+}
@@ -0,0 +1,21 @@
+// =============================================================================
+// CRC-8 - Guards the header fields the decoder must trust before the payload
+// =============================================================================
+
+// CRC-8/SMBUS polynomial x^8 + x^2 + x + 1
+const POLYNOMIAL: u8 = 0x07;
+
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ POLYNOMIAL
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
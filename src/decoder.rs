@@ -1,166 +1,1673 @@
 use std::cmp::Ordering; // for median selection
+use std::fmt; // error display
+use std::io::Read; // decode_stream input
 use std::path::{Path, PathBuf}; // build file paths
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering}; // sync-search cancel flag
+use std::thread; // --threads frame analysis
 
-use hound::WavReader; // read WAV data
-use realfft::RealFftPlanner; // perform FFTs
+use realfft::num_complex::Complex; // spectrum bins
+use serde::Serialize; // JSON reports
+
+use crate::bits::{bits_to_bytes, BitOrder}; // decode_stream bytes
+use crate::channels::{deinterleave, to_mid_side}; // stereo mid/side
+use crate::compress::{compress, decompress}; // --compress
+use crate::config::{
+    frame_len_for, BinSpacing, ChannelStrategy, Framing, FreqBand, Scheme, WatermarkConfig,
+    DEFAULT_WINDOW_MS, MAX_FRAME_LEN, MIN_FRAME_LEN,
+}; // shared settings
+use crate::fft::{FftPlans, PlanCache}; // FFT plans, cached per Decoder
+use crate::log::Muted; // quiet candidate search
+use crate::payload::Payload; // typed payloads
+use crate::phase; // phase coding scheme
+use crate::polar::magnitude; // |bin|, as the encoder measures it
+use crate::resample::resample_interleaved; // --resample
+use crate::vad::{active_frames, frame_level_db}; // --vad-threshold-db
+use crate::varint::MAX_VARINT_BYTES; // BadVarint message
+use crate::watermark::{
+    decides_one, threshold_from_pilot, Watermark, MAGIC_PATTERN, PILOT_PATTERN,
+}; // wire format
+use crate::wav::{self, SampleStream, WavError}; // WAV reading, extensible headers included
+use crate::whitening::whiten; // decode_stream payload
+use crate::window::{frame_starts, hop_len, make_window}; // analysis window and framing
 
 // --- Decoder configuration mirroring the encoder ---
-const PILOT_PATTERN: [u8; 8] = [0, 1, 0, 1, 0, 1, 0, 1]; // known pilot
-const LENGTH_HEADER_BITS: usize = 16; // payload length field
-const WATERMARK_FRAME_DURATION: f32 = 0.032; // frame duration (32ms)
-const SAMPLE_DIVISOR: f32 = 32768.0; // i16 -> f32 scale
-const START_BIN: usize = 10; // first watermark bin
+const PILOT_MIN_MATCHES: usize = 5; // pilot bits a frame must get right to be used
+const SCORE_RADIUS: usize = 3; // neighbours on each side in spectral_scores
+const STREAM_MARGIN: f32 = 0.1; // decode_stream: share of the pilot spread a bit's mean must clear
 
 /// Struct returned by the decoder.
 pub struct DecodedWatermark {
     pub message: String,    // recovered UTF-8 text
     pub raw_bytes: Vec<u8>, // raw byte payload
+    pub bits: Vec<u8>,      // every decided bit (pilot + header + payload)
+    pub crc_ok: Option<bool>, // header CRC result (None when framing has no CRC)
+    pub confidence: f32,    // 0..1, share of frames (or pilot bits) that checked out
+    pub nonce: Option<u16>, // payload nonce, when the config carries one
+    pub tag_ok: Option<bool>, // HMAC tag result (None without --key)
+    pub payload: Payload,   // the message as the variant it was embedded as
+}
+
+/// Reasons the decoder gives up instead of returning a message.
+#[derive(Debug)]
+pub enum DecodeError {
+    NotEnoughBins,                           // frame too small for pilot + header
+    NoWatermark,                             // magic pattern absent after the pilot
+    NoReliableFrames,                        // every frame failed the pilot check
+    HeaderCorrupt { expected: u8, found: u8 }, // header CRC mismatch (Watermark::header_error)
+    BadVarint,                               // varint length chain that never ends
+    NoSuchChannel { channel: usize, channels: usize }, // --channels past the file's count
+    NotStereo { channels: usize },           // mid/side decode of a non-stereo file
+    BadWindow { ms: f32, frame_len: usize }, // --window-duration-ms frame too short or too long
+    Cancelled,                               // the cancel flag was set mid-search
+    BadWav(WavError),                        // the file couldn't be read as audio
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::NotEnoughBins => {
+                write!(f, "not enough spectral bins to recover watermark")
+            }
+            DecodeError::NoWatermark => {
+                write!(f, "no watermark found: magic pattern missing after the pilot")
+            }
+            DecodeError::NoReliableFrames => {
+                write!(f, "unable to decode watermark: no reliable frames detected")
+            }
+            DecodeError::HeaderCorrupt { expected, found } => write!(
+                f,
+                "watermark header is corrupt: CRC {:#04x} does not match computed {:#04x}",
+                found, expected
+            ),
+            DecodeError::BadVarint => write!(
+                f,
+                "watermark header is corrupt: the varint length never ends within {} bytes",
+                MAX_VARINT_BYTES
+            ),
+            DecodeError::NoSuchChannel { channel, channels } => write!(
+                f,
+                "channel {} does not exist; the file has {} channel(s)",
+                channel, channels
+            ),
+            DecodeError::NotStereo { channels } => write!(
+                f,
+                "mid/side decoding needs a stereo file; this one has {} channel(s)",
+                channels
+            ),
+            DecodeError::BadWindow { ms, frame_len } => write!(
+                f,
+                "a {} ms window is {} samples at this rate; it must be between {} and {}",
+                ms, frame_len, MIN_FRAME_LEN, MAX_FRAME_LEN
+            ),
+            DecodeError::Cancelled => {
+                write!(f, "decode cancelled before the sync search finished")
+            }
+            DecodeError::BadWav(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Ok while `cancel` is clear. The sync searches check it before every
+/// offset or segment they try, so setting it from another thread (a UI's
+/// stop button, a timer) ends the search within one step.
+fn check_cancel(cancel: &AtomicBool) -> Result<(), DecodeError> {
+    if cancel.load(AtomicOrdering::Relaxed) {
+        return Err(DecodeError::Cancelled);
+    }
+    Ok(())
 }
 
 /// Blindly decode the watermark from the provided path.
-pub fn decode_watermarked_sample(path: impl AsRef<Path>) -> DecodedWatermark {
-    println!("=== Audio Watermark Decoder (Blind) ===\n"); // header
+pub fn decode_watermarked_sample(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+) -> Result<DecodedWatermark, DecodeError> {
+    diag!("=== Audio Watermark Decoder (Blind) ===\n"); // header
+
+    let (samples, channels, sample_rate) = read_wav(path.as_ref(), config)?; // load waveform
+    decode_samples(&samples, channels, sample_rate, config)
+}
+
+/// Blind decode of samples already in memory (interleaved, in [-1, 1]).
+/// This is decode_watermarked_sample without the file read; Decoder::decode
+/// for many buffers.
+pub fn decode_samples(
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    config: &WatermarkConfig,
+) -> Result<DecodedWatermark, DecodeError> {
+    Decoder::new(config.clone()).decode(samples, channels, sample_rate)
+}
 
-    let (samples, sample_rate) = load_audio(path.as_ref()); // load waveform
-    let (scores, votes, valid, skipped) = summarise_frames(&samples, sample_rate, 3); // aggregate frame stats
+/// Blind decoder for any number of buffers with one configuration. Like
+/// Encoder it keeps a plan cache, so buffers at one sample rate share their
+/// FFT plans; scratch space is per call, so `decode` takes &self and a
+/// Decoder is Send + Sync.
+pub struct Decoder {
+    config: WatermarkConfig,
+    plans: PlanCache,
+}
+
+// Checked at compile time: a Decoder must stay shareable across threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Decoder>();
+};
 
-    if scores.len() < PILOT_PATTERN.len() + LENGTH_HEADER_BITS {
-        panic!("not enough spectral bins to recover watermark"); // guard
+impl Decoder {
+    pub fn new(config: WatermarkConfig) -> Decoder {
+        Decoder {
+            config,
+            plans: PlanCache::new(),
+        }
     }
 
-    println!("Used {} frames for decoding ({} skipped)", valid, skipped); // diagnostics
+    /// Blind decode of interleaved samples in [-1, 1]. DecodeReport::new
+    /// turns the result into the `decode --json` summary.
+    pub fn decode(
+        &self,
+        samples: &[f32],
+        channels: u16,
+        sample_rate: u32,
+    ) -> Result<DecodedWatermark, DecodeError> {
+        let config = &self.config;
+        check_window(config, sample_rate)?;
+        let samples = carrier_channel(samples, channels, config)?; // channel to decode
+        let samples = skip_to_since(samples, sample_rate, config);
+        let plans = self.plans.plans(analysis_fft_len(config, sample_rate)); // cached after the first call
+        let bits = config.scheme.algorithm().extract(&samples, sample_rate, &plans, config)?; // per-bin bit decisions
 
-    let (avg_high, avg_low, threshold) = pilot_stats(&scores); // global threshold from pilot
-    println!(
-        "Pilot scores -> high: {:.6}, low: {:.6}, threshold: {:.6}",
-        avg_high, avg_low, threshold
+        assemble_watermark(bits, config)
+    }
+}
+
+/// Decode by averaging every bin's score over all frames before thresholding.
+/// Every frame repeats the full message, so averaging N frames lifts the
+/// watermark above uncorrelated noise; no frame is rejected up front.
+pub fn decode_averaged(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+) -> Result<DecodedWatermark, DecodeError> {
+    diag!("=== Audio Watermark Decoder (Averaged) ===\n"); // header
+
+    let (samples, sample_rate) = load_audio(path.as_ref(), config)?; // load waveform
+    let plans = plans_for(config, sample_rate);
+    let algorithm = config.scheme.algorithm();
+    let bits = if algorithm.repeats_every_frame() {
+        averaged_amplitude_bits(&samples, sample_rate, &plans, config)?
+    } else {
+        diag!("The {} scheme does not repeat per frame; nothing to average", config.scheme.name());
+        algorithm.extract(&samples, sample_rate, &plans, config)?
+    }; // per-bin bit decisions
+
+    assemble_watermark(bits, config)
+}
+
+/// Machine-readable summary printed by `decode --json`.
+#[derive(Serialize)]
+pub struct DecodeReport {
+    pub message: String,      // recovered UTF-8 text
+    pub offset: usize,        // samples skipped before the first frame
+    pub ber: Option<f32>,     // against --expect, if given
+    pub crc_ok: Option<bool>, // header CRC (None without a CRC)
+    pub tag_ok: Option<bool>, // HMAC tag (None without a key)
+    pub payload: Payload,     // typed view of the message
+    pub confidence: f32,      // 0..1
+    pub scheme: String,       // embedding scheme name
+    pub channel_confidences: Option<Vec<f32>>, // per channel, with --channels-independent
+}
+
+impl DecodeReport {
+    pub fn new(decoded: &DecodedWatermark, offset: usize, ber: Option<f32>, scheme: Scheme) -> Self {
+        DecodeReport {
+            message: decoded.message.clone(),
+            offset,
+            ber,
+            crc_ok: decoded.crc_ok,
+            tag_ok: decoded.tag_ok,
+            payload: decoded.payload.clone(),
+            confidence: decoded.confidence,
+            scheme: scheme.name().to_string(),
+            channel_confidences: None,
+        }
+    }
+}
+
+/// One channel of `decode --per-channel --json`.
+#[derive(Serialize)]
+pub struct ChannelReport {
+    pub channel: usize,                // channel index in the file
+    pub report: Option<DecodeReport>,  // None when the channel did not decode
+    pub error: Option<String>,         // why it did not
+}
+
+/// One line of `decode --trace`: everything the per-frame gate looked at.
+#[derive(Serialize)]
+pub struct FrameTrace {
+    pub frame: usize,             // frame index after the offset
+    pub start: usize,             // first sample of the frame in the file
+    pub threshold: Option<f32>,   // pilot-calibrated threshold (None if unusable)
+    pub pilot_matches: usize,     // pilot bits this frame got right
+    pub used: bool,               // passed the pilot gate
+    pub confidence: f32,          // pilot_matches / pilot length
+    pub magnitudes: Vec<f32>,     // raw |bin| of each bit-carrying bin
+    pub scores: Vec<f32>,         // log-normalised scores the decision uses
+    pub bits: Vec<u8>,            // this frame's own bit decisions
+}
+
+/// Per-frame trace of the amplitude decoder starting at `offset`. Computed
+/// separately from the decode itself, so asking for it can't change the result.
+pub fn trace_frames(
+    path: impl AsRef<Path>,
+    offset: usize,
+    config: &WatermarkConfig,
+) -> Result<Vec<FrameTrace>, DecodeError> {
+    let (samples, sample_rate) = load_audio(path.as_ref(), config)?; // load waveform
+    let samples = &samples[offset.min(samples.len())..]; // same start as the decode
+    let band = decoder_band(config, sample_rate); // same clamp as the encoder
+    let hop = hop_len(decoder_frame_len(config, sample_rate), config.overlap); // frame step
+
+    let traces = frame_spectra(samples, sample_rate, config)
+        .into_iter()
+        .enumerate()
+        .map(|(frame, spectrum)| {
+            let band_scores = spectral_scores(&spectrum[band.low_bin..band.high_bin], SCORE_RADIUS);
+            let data_bins = band.data_bins(config.guard); // bit-carrying bins only
+            let magnitudes: Vec<f32> = data_bins.clone().map(|bin| spectrum[bin]).collect();
+            let scores: Vec<f32> = data_bins.map(|bin| band_scores[bin - band.low_bin]).collect();
+            let stats = frame_pilot_stats(&scores);
+            let pilot_matches = stats.map_or(0, |(_, matches)| matches);
+            FrameTrace {
+                frame,
+                start: offset + frame * hop,
+                threshold: stats.map(|(threshold, _)| threshold),
+                pilot_matches,
+                used: pilot_matches >= PILOT_MIN_MATCHES,
+                confidence: pilot_matches as f32 / PILOT_PATTERN.len() as f32,
+                bits: stats.map_or_else(Vec::new, |(threshold, _)| {
+                    bits_from_magnitudes(scores.iter().copied(), threshold).collect()
+                }),
+                magnitudes,
+                scores,
+            }
+        })
+        .collect();
+    Ok(traces)
+}
+
+// Mean log score (nats above the neighbours, as spectral_scores measures it)
+// a bin needs across frames to count as set by the watermark. Suppressed
+// bins sit about 4 nats under boosted ones; speech averages out to near 0.
+const DETECT_MIN_SCORE: f32 = 1.0;
+
+// Unmarked bins tolerated inside one band: a run of equal bits leaves its
+// inner bins level with their neighbours
+const DETECT_MAX_GAP: usize = 6;
+
+/// Best guess at the band a watermark was embedded in, from the magnitude
+/// spectra of its frames (frame_spectra). The embed applies the same
+/// boost / suppress mask to every frame, so inside the band each bin's
+/// score against its neighbours keeps the same sign frame after frame,
+/// while over speech it averages out. The widest cluster of such bins
+/// locates the band; its start is then pinned to the bin where the known
+/// pilot + magic bits line up best, since marks bleed into the neighbours.
+/// The end is where the marks stop (plus slack for trailing 1s), which
+/// covers the message even when the encoder's band ran further. Assumes
+/// linear spacing and no guard bins. None when nothing looks watermarked.
+pub fn detect_watermark_band(frames: &[Vec<f32>]) -> Option<FreqBand> {
+    let bins = frames.first()?.len();
+    let mut means = vec![0.0f32; bins];
+    for frame in frames {
+        for (mean, score) in means.iter_mut().zip(spectral_scores(frame, SCORE_RADIUS)) {
+            *mean += score / frames.len() as f32;
+        }
+    }
+
+    // DC and Nyquist only have neighbours on one side; leave them out
+    let marked: Vec<usize> = (1..bins.saturating_sub(1))
+        .filter(|&bin| means[bin].abs() >= DETECT_MIN_SCORE)
+        .collect();
+    let (low, high, count) = marked
+        .chunk_by(|a, b| b - a <= DETECT_MAX_GAP + 1)
+        .map(|cluster| (cluster[0], cluster[cluster.len() - 1] + 1, cluster.len()))
+        .max_by_key(|&(_, _, count)| count)?;
+    if count < PILOT_PATTERN.len() / 2 {
+        return None; // a few stray bins, not a watermark
+    }
+
+    // The known prefix's 1s should score above its 0s; shifting it by a bin
+    // inverts the alternating pilot, and by two breaks the magic
+    let known: Vec<u8> = PILOT_PATTERN.iter().chain(&MAGIC_PATTERN).copied().collect();
+    let contrast = |start: usize| {
+        let level = |bit: u8| {
+            let scores: Vec<f32> = known
+                .iter()
+                .enumerate()
+                .filter(|&(_, &known_bit)| known_bit == bit)
+                .map(|(index, _)| means[start + index])
+                .collect();
+            scores.iter().sum::<f32>() / scores.len() as f32
+        };
+        level(1) - level(0)
+    };
+    let low = (low.saturating_sub(DETECT_MAX_GAP)..=low + DETECT_MAX_GAP)
+        .filter(|&start| start + known.len() <= bins)
+        .max_by(|&a, &b| contrast(a).partial_cmp(&contrast(b)).unwrap_or(Ordering::Equal))?;
+    Some(FreqBand {
+        low_bin: low,
+        high_bin: (high + DETECT_MAX_GAP).min(bins).max(low + known.len()),
+        spacing: BinSpacing::Linear,
+    })
+}
+
+/// detect_watermark_band for the carrier channel of a file.
+pub fn detect_band(path: impl AsRef<Path>, config: &WatermarkConfig) -> Result<Option<FreqBand>, DecodeError> {
+    let (samples, sample_rate) = load_audio(path.as_ref(), config)?; // load waveform
+    Ok(detect_watermark_band(&frame_spectra(&samples, sample_rate, config)))
+}
+
+/// Frame offset chosen by the alignment search.
+pub struct Alignment {
+    pub offset: usize,   // samples skipped before the first frame
+    pub confidence: f32, // share of frames that passed the pilot check
+}
+
+// Sub-frame offsets tried by decode_best_alignment (multiples of frame_len / 8)
+const ALIGNMENT_STEPS: usize = 8;
+
+/// Try a few sub-frame offsets and decode at the one with the strongest pilot.
+/// Cheap fix for small edit-induced shifts; it doesn't search sample by sample.
+/// Setting `cancel` stops the search with DecodeError::Cancelled.
+pub fn decode_best_alignment(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+    cancel: &AtomicBool,
+) -> Result<(DecodedWatermark, Alignment), DecodeError> {
+    diag!("=== Audio Watermark Decoder (Alignment Search) ===\n"); // header
+
+    let (samples, sample_rate) = load_audio(path.as_ref(), config)?; // load waveform
+    let plans = plans_for(config, sample_rate); // shared by every offset tried
+    let algorithm = config.scheme.algorithm();
+    if !algorithm.repeats_every_frame() {
+        diag!("The {} scheme does not repeat per frame; skipping alignment search", config.scheme.name());
+        let decoded = assemble_watermark(algorithm.extract(&samples, sample_rate, &plans, config)?, config)?;
+        let alignment = Alignment {
+            offset: 0,
+            confidence: decoded.confidence,
+        };
+        return Ok((decoded, alignment));
+    }
+
+    let frame_len = decoder_frame_len(config, sample_rate);
+    let offsets = coarse_offsets(frame_len);
+    let alignment = best_alignment(&samples, sample_rate, &plans, config, offsets, cancel)?;
+    let bits = decode_amplitude_bits(&samples[alignment.offset..], sample_rate, &plans, config)?;
+    Ok((assemble_watermark(bits, config)?, alignment))
+}
+
+/// Decode a re-recorded copy (playback through a speaker, picked up by a
+/// phone): a coarse-then-fine sync search, then contrast-weighted averaging.
+/// Assumes the recording keeps the original sample rate and frame grid up
+/// to a small shift, and that every frame still repeats the message.
+/// Reverb smears energy across neighbour bins but leaves their order
+/// mostly intact, which is what the pilot-relative scores rely on. Setting
+/// `cancel` stops the search with DecodeError::Cancelled.
+pub fn decode_robust(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+    cancel: &AtomicBool,
+) -> Result<(DecodedWatermark, Alignment), DecodeError> {
+    diag!("=== Audio Watermark Decoder (Robust) ===\n"); // header
+
+    let (samples, sample_rate) = load_audio(path.as_ref(), config)?; // load waveform
+    let plans = plans_for(config, sample_rate); // shared by every offset tried
+    let algorithm = config.scheme.algorithm();
+    if !algorithm.repeats_every_frame() {
+        diag!("The {} scheme does not repeat per frame; decoding it as-is", config.scheme.name());
+        let decoded = assemble_watermark(algorithm.extract(&samples, sample_rate, &plans, config)?, config)?;
+        let alignment = Alignment {
+            offset: 0,
+            confidence: decoded.confidence,
+        };
+        return Ok((decoded, alignment));
+    }
+
+    // Coarse grid first, then refine around the winner: a re-recording's
+    // shift is arbitrary, and reverb leaves little margin for a near miss
+    let frame_len = decoder_frame_len(config, sample_rate);
+    let coarse = coarse_offsets(frame_len);
+    let coarse = best_alignment(&samples, sample_rate, &plans, config, coarse, cancel)?;
+    let fine = fine_offsets(coarse.offset, frame_len);
+    let alignment = best_alignment(&samples, sample_rate, &plans, config, fine, cancel)?;
+    let bits = normalised_average_bits(&samples[alignment.offset..], sample_rate, &plans, config)?;
+    Ok((assemble_watermark(bits, config)?, alignment))
+}
+
+// Frames per re-sync segment in decode_resync. Frames still decode up to
+// about an eighth of a frame off the grid, so a segment must not drift
+// further than that: 3 frames (768 samples at 8 kHz) allow about +-5%.
+const RESYNC_FRAMES: usize = 3;
+
+// Offsets tried per segment: every frame_len / RESYNC_STEPS samples
+const RESYNC_STEPS: usize = 32;
+
+/// Decode a copy whose frame grid drifts, as after a small tempo change
+/// (broadcast speed-up, WSOLA time-stretch): every RESYNC_FRAMES frames the
+/// frame alignment is searched again, and the frames of every segment are
+/// pooled into one pilot-gated vote. Tolerates roughly +-5% of stretch at
+/// the default 32 ms frame; pitch must be unchanged (a resampled copy moves
+/// every bin, which no re-sync can follow). Setting `cancel` stops the
+/// search with DecodeError::Cancelled.
+pub fn decode_resync(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+    cancel: &AtomicBool,
+) -> Result<DecodedWatermark, DecodeError> {
+    diag!("=== Audio Watermark Decoder (Re-sync) ===\n"); // header
+
+    let (samples, sample_rate) = load_audio(path.as_ref(), config)?; // load waveform
+    let plans = plans_for(config, sample_rate); // shared by every offset tried
+    let algorithm = config.scheme.algorithm();
+    if !algorithm.repeats_every_frame() {
+        diag!("The {} scheme does not repeat per frame; nothing to re-sync", config.scheme.name());
+        return assemble_watermark(algorithm.extract(&samples, sample_rate, &plans, config)?, config);
+    }
+
+    describe_framing(&samples, sample_rate, config);
+    let frame_len = decoder_frame_len(config, sample_rate);
+    let hop = hop_len(frame_len, config.overlap);
+    let span = RESYNC_FRAMES * hop; // samples per segment
+    let mut frames = Vec::new(); // aligned frames from every segment
+    let mut offsets = Vec::new(); // chosen offset per segment, for the drift report
+    for start in (0..samples.len()).step_by(span) {
+        check_cancel(cancel)?;
+        // One frame of slack, so any offset still sees RESYNC_FRAMES frames
+        let segment = &samples[start..(start + span + frame_len).min(samples.len())];
+        let Some((offset, scores)) = segment_alignment(segment, sample_rate, &plans, config, frame_len) else {
+            continue; // no pilot anywhere in this segment (silence)
+        };
+        offsets.push(offset);
+        frames.extend(scores);
+    }
+    if let (Some(first), Some(last)) = (offsets.first(), offsets.last()) {
+        diag!(
+            "Re-synchronised {} segments of {} frames (offset {} -> {} samples)",
+            offsets.len(),
+            RESYNC_FRAMES,
+            first,
+            last
+        );
+    }
+
+    let bits = bits_from_frames(&without_ramp(frames, config), config)?;
+    assemble_watermark(bits, config)
+}
+
+/// Decode every channel on its own and combine them bit by bit, for files
+/// where several channels carry the same watermark (--channels 0,1 at
+/// encode time). Each bit is a vote weighted by its channel's confidence,
+/// so one badly damaged channel is outvoted by a clean one; a channel that
+/// fails to decode at all gets no vote. Uses the --channels list when it
+/// names two or more channels, otherwise every channel in the file.
+/// Returns the combined result and the confidence of each channel.
+pub fn decode_channels_independent(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+) -> Result<(DecodedWatermark, Vec<f32>), DecodeError> {
+    diag!("=== Audio Watermark Decoder (Independent Channels) ===\n"); // header
+
+    let (samples, channels, sample_rate) = read_wav(path.as_ref(), config)?; // load waveform
+    check_window(config, sample_rate)?;
+    let channels = usize::from(channels.max(1));
+    let listed = listed_channels(config, channels)?;
+
+    let tracks = deinterleave(&samples, channels);
+    let plans = plans_for(config, sample_rate); // every channel has the same rate
+    let algorithm = config.scheme.algorithm();
+    let mut decoded_channels = Vec::new(); // (bits, confidence) per channel that decoded
+    let mut confidences = Vec::new();
+    let mut first_error = None;
+    for &channel in &listed {
+        diag!("--- Channel {} of {} ---", channel, channels);
+        let track = skip_to_since(tracks[channel].clone(), sample_rate, config);
+        match algorithm.extract(&track, sample_rate, &plans, config) {
+            Ok(frame_bits) => {
+                let confidence = bits_confidence(&frame_bits);
+                confidences.push(confidence);
+                decoded_channels.push((frame_bits.bits, confidence));
+            }
+            Err(err) => {
+                diag!("Channel {} did not decode: {}", channel, err);
+                confidences.push(0.0);
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    if decoded_channels.is_empty() {
+        return Err(first_error.unwrap_or(DecodeError::NoReliableFrames));
+    }
+    diag!("Channel confidences: {:?}", confidences);
+
+    let bits = vote_channels(&decoded_channels);
+    let confidence = confidences.iter().copied().fold(0.0f32, f32::max);
+    let decoded = assemble_watermark(
+        FrameBits {
+            bits,
+            confidence: Some(confidence), // at least as good as the best channel
+        },
+        config,
+    )?;
+    Ok((decoded, confidences))
+}
+
+// decode_candidates flips only the this many least confident bits, at most
+// MAX_CANDIDATE_FLIPS of them at once: 697 decodes at worst
+const CANDIDATE_POSITIONS: usize = 16;
+const MAX_CANDIDATE_FLIPS: usize = 3;
+
+/// A message decode_candidates found plausible, and its score.
+pub type Candidate = (String, f32);
+
+/// Every plausible decoding of `samples` (interleaved, in [-1, 1]), best
+/// first, for when the blind decode's bits are in doubt. Variants flip up
+/// to `max_flips` (at most MAX_CANDIDATE_FLIPS) of the CANDIDATE_POSITIONS
+/// least confident message bits, and each one whose header CRC and (with
+/// --key) tag check out is kept. A bit's confidence is its score's distance
+/// from the threshold in half pilot contrasts; a variant scores 1.0 less
+/// what its flips cost, and one costing more than 1.0 is dropped as
+/// implausible. The CRC covers only the length header, so without --key
+/// every payload flip checks out and the score is all that ranks them.
+/// Schemes that don't repeat every frame have no averaged scores and return
+/// the blind decode alone.
+pub fn decode_candidates(
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    config: &WatermarkConfig,
+    max_flips: usize,
+) -> Result<Vec<Candidate>, DecodeError> {
+    check_window(config, sample_rate)?;
+    let samples = carrier_channel(samples, channels, config)?; // channel to decode
+    let samples = skip_to_since(samples, sample_rate, config);
+    let plans = plans_for(config, sample_rate);
+    let algorithm = config.scheme.algorithm();
+    if !algorithm.repeats_every_frame() {
+        let bits = algorithm.extract(&samples, sample_rate, &plans, config)?;
+        return Ok(vec![(assemble_watermark(bits, config)?.message, 1.0)]);
+    }
+
+    let frames = frame_scores(&samples, sample_rate, SCORE_RADIUS, &plans, config);
+    let frames = without_ramp(frames, config); // full-strength frames only
+    candidates_from_frames(&frames, config, max_flips)
+}
+
+/// decode_candidates on the WAV at `path` (decode --candidates).
+pub fn decode_candidates_file(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+    max_flips: usize,
+) -> Result<Vec<Candidate>, DecodeError> {
+    diag!("=== Audio Watermark Decoder (Candidates) ===\n"); // header
+
+    let (samples, channels, sample_rate) = read_wav(path.as_ref(), config)?; // load waveform
+    decode_candidates(&samples, channels, sample_rate, config, max_flips)
+}
+
+/// decode_candidates' search, over the frames' spectral scores.
+fn candidates_from_frames(
+    frames: &[Vec<f32>],
+    config: &WatermarkConfig,
+    max_flips: usize,
+) -> Result<Vec<Candidate>, DecodeError> {
+    let base = bits_from_frames(frames, config)?;
+    let (scores, _, _, _) = summarise_frames(frames, config.threshold)?;
+    let (avg_high, avg_low, threshold) = pilot_stats(&scores);
+    let threshold = config.threshold.unwrap_or(threshold);
+
+    // Rank only the bits the message occupies; past it the band holds audio
+    let used = match assemble_watermark(base.clone(), config) {
+        Ok(decoded) => expected_bits(&decoded.message, config, decoded.nonce).len(),
+        Err(_) => base.bits.len(),
+    };
+    let half_contrast = ((avg_high - avg_low) / 2.0).max(f32::EPSILON);
+    let mut doubtful: Vec<(usize, f32)> = (PILOT_PATTERN.len()..used.min(base.bits.len()))
+        .map(|idx| (idx, (scores[idx] - threshold).abs() / half_contrast))
+        .collect();
+    doubtful.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+    doubtful.truncate(CANDIDATE_POSITIONS);
+    diag!(
+        "Flipping up to {} of the {} least confident bits",
+        max_flips.min(MAX_CANDIDATE_FLIPS),
+        doubtful.len()
+    );
+
+    let _muted = Muted::new(); // every variant would print a full decode
+    let mut candidates: Vec<(Vec<u8>, Candidate)> = Vec::new(); // keyed by raw bytes
+    for flips in flip_sets(doubtful.len(), max_flips.min(MAX_CANDIDATE_FLIPS)) {
+        let cost: f32 = flips.iter().map(|&flip| doubtful[flip].1).sum();
+        if cost > 1.0 {
+            continue;
+        }
+        let mut variant = base.clone();
+        for &flip in &flips {
+            variant.bits[doubtful[flip].0] ^= 1;
+        }
+        let Ok(decoded) = assemble_watermark(variant, config) else {
+            continue;
+        };
+        if decoded.crc_ok == Some(false) || decoded.tag_ok == Some(false) {
+            continue;
+        }
+        let score = 1.0 - cost;
+        match candidates.iter_mut().find(|(bytes, _)| *bytes == decoded.raw_bytes) {
+            Some((_, best)) => best.1 = best.1.max(score),
+            None => candidates.push((decoded.raw_bytes, (decoded.message, score))),
+        }
+    }
+    let mut candidates: Vec<Candidate> = candidates.into_iter().map(|(_, candidate)| candidate).collect();
+    candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    Ok(candidates)
+}
+
+/// Every subset of `0..count` with at most `max` members, smallest first
+/// (the empty set leads).
+fn flip_sets(count: usize, max: usize) -> Vec<Vec<usize>> {
+    let mut sets = vec![Vec::new()];
+    let mut next = 0;
+    while next < sets.len() {
+        let set: Vec<usize> = sets[next].clone();
+        next += 1;
+        if set.len() == max {
+            continue;
+        }
+        let first = set.last().map_or(0, |&last| last + 1);
+        for idx in first..count {
+            let mut grown = set.clone();
+            grown.push(idx);
+            sets.push(grown);
+        }
+    }
+    sets
+}
+
+/// A channel index and that channel's own decode.
+pub type ChannelResult = (usize, Result<DecodedWatermark, DecodeError>);
+
+/// Decode every channel as a watermark of its own, for files whose channels
+/// carry different messages (--channel-map at encode time). Uses the
+/// --channels list when it names two or more channels, otherwise every
+/// channel in the file. One result per channel, in list order; a channel
+/// that fails to decode doesn't stop the others.
+pub fn decode_per_channel(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+) -> Result<Vec<ChannelResult>, DecodeError> {
+    diag!("=== Audio Watermark Decoder (Per Channel) ===\n"); // header
+
+    let (samples, channels, sample_rate) = read_wav(path.as_ref(), config)?; // load waveform
+    check_window(config, sample_rate)?;
+    let channels = usize::from(channels.max(1));
+    let listed = listed_channels(config, channels)?;
+
+    let tracks = deinterleave(&samples, channels);
+    let plans = plans_for(config, sample_rate); // every channel has the same rate
+    let algorithm = config.scheme.algorithm();
+    Ok(listed
+        .into_iter()
+        .map(|channel| {
+            diag!("--- Channel {} of {} ---", channel, channels);
+            let track = skip_to_since(tracks[channel].clone(), sample_rate, config);
+            let decoded = algorithm
+                .extract(&track, sample_rate, &plans, config)
+                .and_then(|frame_bits| assemble_watermark(frame_bits, config));
+            (channel, decoded)
+        })
+        .collect())
+}
+
+// Mean |sin(phase)| an anchor frame's header bins must reach for decode_all
+// to try it; ordinary audio sits near 2/pi (0.64)
+const ANCHOR_MIN_SCORE: f32 = 0.9;
+
+/// Every copy of the watermark in the file, each with the sample it starts
+/// at, for files embedded with --repeat-every. Phase coding writes a copy
+/// into one anchor frame, so the carrier is scanned sample by sample for
+/// frames whose header bins sit at +-pi/2 and the best frame of each run is
+/// decoded; a trimmed file still finds its later copies wherever they now
+/// start. Schemes that repeat the message every frame decode as one copy.
+pub fn decode_all(
+    path: impl AsRef<Path>,
+    config: &WatermarkConfig,
+) -> Result<Vec<(usize, DecodedWatermark)>, DecodeError> {
+    diag!("=== Audio Watermark Decoder (All Copies) ===\n"); // header
+
+    let (samples, sample_rate) = load_audio(path.as_ref(), config)?; // load waveform
+    let plans = plans_for(config, sample_rate);
+    let algorithm = config.scheme.algorithm();
+    if algorithm.repeats_every_frame() {
+        diag!(
+            "The {} scheme repeats the message every frame; decoding it as one copy",
+            config.scheme.name()
+        );
+        let bits = algorithm.extract(&samples, sample_rate, &plans, config)?;
+        return Ok(vec![(0, assemble_watermark(bits, config)?)]);
+    }
+
+    // Pilot, magic and header bins are in every copy, whatever its length
+    let header_bins = Watermark::min_bits(config.framing);
+    let scores = phase::anchor_scores(&samples, &plans, config.band, config.guard, header_bins);
+    let frame_len = plans.fft_len();
+    let mut anchors: Vec<(usize, f32)> = Vec::new(); // best offset of each run
+    for (offset, &score) in scores.iter().enumerate() {
+        if score < ANCHOR_MIN_SCORE {
+            continue;
+        }
+        match anchors.last_mut() {
+            Some(last) if offset < last.0 + frame_len => {
+                if score > last.1 {
+                    *last = (offset, score);
+                }
+            }
+            _ => anchors.push((offset, score)),
+        }
+    }
+    diag!("{} candidate anchor frame(s)", anchors.len());
+
+    let copies: Vec<(usize, DecodedWatermark)> = anchors
+        .into_iter()
+        .filter_map(|(offset, _)| {
+            let bits = phase_bits(&samples[offset..], sample_rate, &plans, config);
+            assemble_watermark(bits, config).ok().map(|decoded| (offset, decoded))
+        })
+        .collect();
+    if copies.is_empty() {
+        return Err(DecodeError::NoWatermark);
+    }
+    Ok(copies)
+}
+
+/// Decode a WAV while it is read from `reader`, handing each message byte
+/// to `on_byte` as soon as the frames carrying it are in, so a UI can show
+/// partial results. The first copy whose header checks out is read as its
+/// frames arrive (under --bits-per-frame a frame's share at a time), and a
+/// byte goes out once each of its bits is clear of the threshold, averaged
+/// over that copy and the ones after it. At the end of the stream the audio
+/// is decoded as decode_samples decodes it, any bytes it found past those
+/// already sent follow, and that full decode is returned.
+///
+/// A payload that only reads whole (--compress, --key, a nonce, --typed, a
+/// framing without the length header) is held back until then, like a
+/// block code that needs the whole block; so is audio that needs the whole
+/// file first (--resample, a scheme that doesn't repeat every frame). Sent
+/// bytes aren't taken back: a full decode that disagrees gets a warning.
+pub fn decode_stream<R: Read>(
+    reader: R,
+    config: &WatermarkConfig,
+    mut on_byte: impl FnMut(u8),
+) -> Result<DecodedWatermark, DecodeError> {
+    diag!("=== Audio Watermark Decoder (Streaming) ===\n"); // header
+
+    let mut stream = SampleStream::new(reader).map_err(DecodeError::BadWav)?;
+    let spec = stream.spec();
+    check_window(config, spec.sample_rate)?;
+    carrier_channel(&[], spec.channels, config)?; // a missing channel fails before any reading
+    let channels = usize::from(spec.channels);
+    let mut copy = FirstCopy::new(config, spec.sample_rate);
+    let batch = copy.as_ref().map_or(usize::MAX, |copy| copy.hop * channels); // samples per push
+
+    let declared = stream.declared();
+    let mut samples = Vec::with_capacity(stream.capacity());
+    let mut fed = 0; // samples already pushed to `copy`
+    let mut sent = Vec::new();
+    for sample in &mut stream {
+        match sample {
+            Ok(sample) => samples.push(sample),
+            Err(WavError::Truncated { .. }) if config.allow_truncated => {
+                samples.truncate(samples.len() / channels * channels);
+                diag!(
+                    "Warning: WAV data ends early; keeping {} of {} declared samples",
+                    samples.len(),
+                    declared
+                );
+                break;
+            }
+            Err(err) => return Err(DecodeError::BadWav(err)),
+        }
+        if let Some(copy) = copy.as_mut().filter(|_| samples.len() - fed >= batch) {
+            let carrier = {
+                let _quiet = Muted::new(); // once per batch
+                carrier_channel(&samples[fed..], spec.channels, config)?
+            };
+            fed = samples.len();
+            for byte in copy.push(&carrier) {
+                sent.push(byte);
+                on_byte(byte);
+            }
+        }
+    }
+    diag!("Streamed {} message bytes; decoding the whole stream", sent.len());
+
+    let (samples, sample_rate) = match config.resample_to.filter(|&rate| rate != spec.sample_rate) {
+        Some(rate) => (resample_interleaved(&samples, spec.channels, spec.sample_rate, rate), rate),
+        None => (samples, spec.sample_rate),
+    };
+    let decoded = Decoder::new(config.clone()).decode(&samples, spec.channels, sample_rate)?;
+    if !decoded.raw_bytes.starts_with(&sent) {
+        diag!(
+            "Warning: the full decode differs from the {} bytes already streamed",
+            sent.len()
+        );
+    }
+    for &byte in decoded.raw_bytes.iter().skip(sent.len()) {
+        on_byte(byte);
+    }
+    Ok(decoded)
+}
+
+/// decode_stream's reading of the first copy, one frame at a time. The
+/// frames are scored as frame_scores scores them and put back together as
+/// regroup_frames does, from the first frame whose header checks out.
+struct FirstCopy {
+    config: WatermarkConfig, // one frame per call: no overlap, VAD or regroup
+    vad_threshold_db: Option<f32>,
+    plans: FftPlans,
+    sample_rate: u32,
+    frame_len: usize,
+    hop: usize,
+    cap: usize,             // stream bits per frame
+    groups: usize,          // frames per copy
+    skip: usize,            // carrier samples still to drop (--since)
+    carrier: Vec<f32>,      // carrier samples since then
+    framed: usize,          // frames read from `carrier`
+    frames: Vec<Vec<f32>>,  // scores of those frames loud enough to be marked
+    locked: Option<Locked>, // the copy being read, once its header is in
+    sent: usize,            // message bytes handed out
+}
+
+/// The copy FirstCopy reads, found from its header
+#[derive(Clone, Copy)]
+struct Locked {
+    first: usize,   // index in FirstCopy::frames of its first frame
+    length: usize,  // message bytes the header declares
+    threshold: f32, // from its pilot
+    spread: f32,    // its pilot's high mean less its low mean
+}
+
+impl FirstCopy {
+    /// None when the message can't be read until the whole stream is in
+    fn new(config: &WatermarkConfig, sample_rate: u32) -> Option<FirstCopy> {
+        let whole_payload = config.compress
+            || config.key.is_some()
+            || config.nonce
+            || config.typed_payload
+            || config.framing != Framing::LengthHeader;
+        let whole_file = config.resample_to.is_some() || !config.scheme.algorithm().repeats_every_frame();
+        let capacity = decoder_band(config, sample_rate).capacity(config.guard);
+        if whole_payload || whole_file || capacity < Watermark::min_bits(Framing::LengthHeader) {
+            diag!("The message is held back until the whole stream is in");
+            return None;
+        }
+
+        let frame_len = decoder_frame_len(config, sample_rate);
+        let cap = config.max_bits_per_frame.map_or(capacity, |cap| cap.min(capacity));
+        let since = config.since.map_or(0, |since| since.to_samples(sample_rate));
+        Some(FirstCopy {
+            config: WatermarkConfig {
+                overlap: 0.0,
+                vad_threshold_db: None,
+                max_bits_per_frame: None,
+                ..config.clone()
+            },
+            vad_threshold_db: config.vad_threshold_db,
+            plans: plans_for(config, sample_rate),
+            sample_rate,
+            frame_len,
+            hop: hop_len(frame_len, config.overlap),
+            cap,
+            groups: capacity.div_ceil(cap),
+            skip: since.max(config.embed_offset),
+            carrier: Vec::new(),
+            framed: 0,
+            frames: Vec::new(),
+            locked: None,
+            sent: 0,
+        })
+    }
+
+    /// Take in more carrier samples; the message bytes they complete
+    fn push(&mut self, carrier: &[f32]) -> Vec<u8> {
+        let skipped = self.skip.min(carrier.len());
+        self.skip -= skipped;
+        self.carrier.extend_from_slice(&carrier[skipped..]);
+
+        let limit = self.config.limit_frames.unwrap_or(usize::MAX); // --limit-frames
+        let mut bytes = Vec::new();
+        while self.framed < limit && self.framed * self.hop + self.frame_len <= self.carrier.len() {
+            let start = self.framed * self.hop;
+            self.framed += 1;
+            let frame = &self.carrier[start..start + self.frame_len];
+            if self.vad_threshold_db.is_some_and(|threshold_db| frame_level_db(frame) < threshold_db) {
+                continue; // the encoder left it unmarked (see vad.rs)
+            }
+            let scores = {
+                let _quiet = Muted::new(); // once per frame
+                frame_scores(frame, self.sample_rate, SCORE_RADIUS, &self.plans, &self.config)
+            };
+            self.frames.extend(scores);
+            bytes.extend(self.read());
+        }
+        bytes
+    }
+
+    /// The bytes the newest frame settles: none until a copy's header is in,
+    /// then every byte of that copy not handed out yet whose bits have all
+    /// settled (see settled)
+    fn read(&mut self) -> Vec<u8> {
+        let header_end = PILOT_PATTERN.len() + Watermark::header_bits(Framing::LengthHeader);
+        let locked = match self.locked {
+            Some(locked) => locked,
+            None => {
+                // The copy whose header the newest frame completes
+                let Some(first) = self.frames.len().checked_sub(header_end.div_ceil(self.cap)) else {
+                    return Vec::new();
+                };
+                let scores = self.scores(first, header_end);
+                let (high, low, calibrated) = pilot_stats(&scores);
+                let threshold = self.config.threshold.unwrap_or(calibrated);
+                let bits: Vec<u8> = bits_from_magnitudes(scores.into_iter(), threshold).collect();
+                let pilot_matches = bits
+                    .iter()
+                    .zip(PILOT_PATTERN.iter())
+                    .filter(|(got, want)| got == want)
+                    .count();
+                let length = Watermark::declared_length(&bits).filter(|_| pilot_matches >= PILOT_MIN_MATCHES);
+                let Some(length) = length else {
+                    return Vec::new();
+                };
+                if header_end + 8 * length > self.frames[first].len() {
+                    return Vec::new(); // a header no frame could have carried
+                }
+                diag!("Streaming the copy from frame {}: {} message bytes", first, length);
+                let locked = Locked {
+                    first,
+                    length,
+                    threshold,
+                    spread: high - low,
+                };
+                self.locked = Some(locked);
+                locked
+            }
+        };
+
+        let total = header_end + 8 * locked.length;
+        let payload: Vec<u8> = (header_end + 8 * self.sent..total)
+            .map_while(|bit| self.settled(locked, bit))
+            .collect();
+        // Put back in its place in the whitening mask, which starts after the magic
+        let offset = header_end + 8 * self.sent - PILOT_PATTERN.len() - MAGIC_PATTERN.len();
+        let masked: Vec<u8> = std::iter::repeat_n(0, offset).chain(payload).collect();
+        let bytes = bits_to_bytes(&whiten(&masked)[offset..masked.len() / 8 * 8], BitOrder::MsbFirst);
+        self.sent += bytes.len();
+        bytes
+    }
+
+    /// Stream bit `bit`, from its mean score over every copy read so far
+    /// (runs of `groups` frames from the locked one); None until one has
+    /// reached it, and while the mean is within STREAM_MARGIN of the pilot's
+    /// spread from the threshold, where one more copy could still flip it
+    fn settled(&self, locked: Locked, bit: usize) -> Option<u8> {
+        let scores: Vec<f32> = (locked.first + bit / self.cap..self.frames.len())
+            .step_by(self.groups)
+            .map(|frame| self.frames[frame][bit])
+            .collect();
+        if scores.is_empty() {
+            return None;
+        }
+        let mean = scores.iter().sum::<f32>() / scores.len() as f32;
+        let clear = (mean - locked.threshold).abs() >= STREAM_MARGIN * locked.spread;
+        clear.then(|| u8::from(decides_one(mean, locked.threshold)))
+    }
+
+    /// Scores of the first `count` stream bits of the copy starting at
+    /// frame `first`: bit i is in data bin i of its group's frame
+    fn scores(&self, first: usize, count: usize) -> Vec<f32> {
+        (0..count).map(|bit| self.frames[first + bit / self.cap][bit]).collect()
+    }
+}
+
+/// Channels the multichannel modes decode: the --channels list when it
+/// names two or more, otherwise all `channels` of the file.
+fn listed_channels(config: &WatermarkConfig, channels: usize) -> Result<Vec<usize>, DecodeError> {
+    let listed: Vec<usize> = if config.channels.len() > 1 {
+        config.channels.clone()
+    } else {
+        (0..channels).collect()
+    };
+    if let Some(&channel) = listed.iter().find(|&&channel| channel >= channels) {
+        return Err(DecodeError::NoSuchChannel { channel, channels });
+    }
+    Ok(listed)
+}
+
+/// Confidence-weighted vote per bit position; a tie keeps the first channel's bit.
+fn vote_channels(channels: &[(Vec<u8>, f32)]) -> Vec<u8> {
+    let len = channels.iter().map(|(bits, _)| bits.len()).min().unwrap_or(0);
+    (0..len)
+        .map(|index| {
+            let score: f32 = channels
+                .iter()
+                .map(|(bits, weight)| if bits[index] == 1 { *weight } else { -*weight })
+                .sum();
+            match score.partial_cmp(&0.0) {
+                Some(Ordering::Greater) => 1,
+                Some(Ordering::Less) => 0,
+                _ => channels[0].0[index],
+            }
+        })
+        .collect()
+}
+
+/// Offset within one segment with the strongest total pilot contrast over
+/// its frames, and those frames' scores. None when no frame has a pilot.
+fn segment_alignment(
+    segment: &[f32],
+    sample_rate: u32,
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+    frame_len: usize,
+) -> Option<(usize, Vec<Vec<f32>>)> {
+    let step = (frame_len / RESYNC_STEPS).max(1);
+    (0..frame_len)
+        .step_by(step)
+        .filter(|&offset| offset < segment.len())
+        .filter_map(|offset| {
+            let mut scores = frame_scores(&segment[offset..], sample_rate, SCORE_RADIUS, plans, config);
+            scores.truncate(RESYNC_FRAMES);
+            let contrast: f32 = scores
+                .iter()
+                .map(|frame| {
+                    let (high, low, _) = pilot_stats(frame);
+                    (high - low).max(0.0) // an inverted pilot adds nothing
+                })
+                .sum();
+            (contrast > 0.0).then_some((offset, scores, contrast))
+        })
+        .max_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal))
+        .map(|(offset, scores, _)| (offset, scores))
+}
+
+/// Coarse sub-frame grid searched by decode_best_alignment.
+fn coarse_offsets(frame_len: usize) -> impl Iterator<Item = usize> {
+    (0..ALIGNMENT_STEPS).map(move |step| step * frame_len / ALIGNMENT_STEPS)
+}
+
+/// Finer offsets covering one coarse step around `centre` (wrapping within a frame).
+fn fine_offsets(centre: usize, frame_len: usize) -> impl Iterator<Item = usize> {
+    let half = frame_len / ALIGNMENT_STEPS / 2; // half a coarse step
+    let step = (frame_len / (ALIGNMENT_STEPS * ALIGNMENT_STEPS)).max(1); // fine granularity
+    (0..=2 * half)
+        .step_by(step)
+        .map(move |delta| (centre + frame_len + delta - half) % frame_len)
+}
+
+/// Candidate offset with the strongest pilot contrast.
+fn best_alignment(
+    samples: &[f32],
+    sample_rate: u32,
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+    offsets: impl Iterator<Item = usize>,
+    cancel: &AtomicBool,
+) -> Result<Alignment, DecodeError> {
+    let mut best: Option<(Alignment, f32)> = None; // (alignment, pilot contrast)
+    for offset in offsets {
+        check_cancel(cancel)?;
+        let shifted = &samples[offset.min(samples.len())..];
+        let frames = frame_scores(shifted, sample_rate, SCORE_RADIUS, plans, config);
+        let Ok((scores, _, valid, skipped)) = summarise_frames(&frames, None) else {
+            diag!("Offset {:>4}: no reliable frames", offset);
+            continue; // nothing passed the pilot here
+        };
+        let (avg_high, avg_low, _) = pilot_stats(&scores);
+        let contrast = avg_high - avg_low; // pilot correlation strength
+        let confidence = valid as f32 / (valid + skipped) as f32;
+        diag!(
+            "Offset {:>4}: pilot contrast {:.4}, {:.1}% frames pass",
+            offset,
+            contrast,
+            confidence * 100.0
+        );
+        if best.as_ref().is_none_or(|(_, top)| contrast > *top) {
+            best = Some((Alignment { offset, confidence }, contrast));
+        }
+    }
+
+    let (alignment, _) = best.ok_or(DecodeError::NoReliableFrames)?;
+    diag!(
+        "Best alignment: offset {} samples (confidence {:.3})\n",
+        alignment.offset, alignment.confidence
     );
+    Ok(alignment)
+}
+
+/// Convenience path used by CLI.
+pub fn default_watermarked_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("output_data")
+        .join("OSR_us_000_0057_8k_watermarked.wav")
+}
 
-    let bits = decide_bits(&scores, &votes, threshold, avg_high, avg_low); // convert scores to bits
+// --- Bit assembly -------------------------------------------------------------
 
-    let (pilot_bits, remainder) = bits.split_at(PILOT_PATTERN.len()); // separate pilot
-    let pilot_matches = pilot_bits
+/// Per-bin decisions plus how trustworthy the frames behind them were.
+#[derive(Clone)]
+pub struct FrameBits {
+    bits: Vec<u8>,
+    confidence: Option<f32>, // None: fall back to the pilot match ratio
+}
+
+pub fn phase_bits(
+    samples: &[f32],
+    sample_rate: u32,
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+) -> FrameBits {
+    warn_if_clamped(config, sample_rate);
+    FrameBits {
+        bits: phase::extract_phase_coding(samples, plans, config.band, config.guard),
+        confidence: None, // a single frame: judge it by its pilot
+    }
+}
+
+/// How far to trust one channel's bits: the frame confidence when the
+/// scheme measures one, otherwise the share of pilot bits that came out right.
+fn bits_confidence(frame_bits: &FrameBits) -> f32 {
+    frame_bits.confidence.unwrap_or_else(|| {
+        let matches = frame_bits
+            .bits
+            .iter()
+            .zip(PILOT_PATTERN.iter())
+            .filter(|(got, want)| **got == **want)
+            .count();
+        matches as f32 / PILOT_PATTERN.len() as f32
+    })
+}
+
+fn assemble_watermark(
+    frame_bits: FrameBits,
+    config: &WatermarkConfig,
+) -> Result<DecodedWatermark, DecodeError> {
+    let bits = frame_bits.bits;
+    if bits.len() < PILOT_PATTERN.len() {
+        return Err(DecodeError::NotEnoughBins); // guard
+    }
+
+    let pilot_matches = bits[..PILOT_PATTERN.len()]
         .iter()
         .zip(PILOT_PATTERN.iter())
         .filter(|(got, want)| **got == **want)
         .count(); // count matches
 
     if pilot_matches == PILOT_PATTERN.len() {
-        println!(
+        diag!(
             "Pilot pattern verified ({} / {} matches)",
             pilot_matches,
             PILOT_PATTERN.len()
         );
     } else {
-        println!(
+        diag!(
             "Warning: pilot pattern mismatch ({} / {} matches); continuing with majority vote bits",
             pilot_matches,
             PILOT_PATTERN.len()
         );
     }
 
-    let (len_bits, data_bits_all) = remainder.split_at(LENGTH_HEADER_BITS.min(remainder.len())); // length header slice
-    let message_bytes = decode_length_header(len_bits); // parse payload size
-    println!("Length header reports {} message bytes", message_bytes);
+    let watermark = Watermark::from_bits(&bits, config.framing, config.nonce, config.key.as_deref())?; // magic + framing
+    if let Some(err) = watermark.header_error() {
+        diag!("Warning: {}; the message is best effort", err);
+    }
+    diag!("Recovered {} message bytes", watermark.message.len());
+
+    let mut message = watermark.message;
+    if config.compress {
+        match decompress(&message) {
+            Some(inflated) => {
+                diag!("Inflated {} payload bytes to {}", message.len(), inflated.len());
+                message = inflated;
+            }
+            None => diag!("Warning: payload does not inflate; returning the bytes as received"),
+        }
+    }
+    let mut decoded = if config.typed_payload {
+        typed_message(message)
+    } else {
+        bytes_to_message(message) // convert to DecodedWatermark
+    };
+    decoded.nonce = watermark.nonce; // needed to rebuild the expected bits
+    decoded.tag_ok = watermark.tag_ok;
+    decoded.bits = bits; // keep the full stream for bit error measurements
+    decoded.crc_ok = watermark.crc_ok; // Some(false): best-effort bytes, length unverified
+    decoded.confidence = frame_bits
+        .confidence
+        .unwrap_or(pilot_matches as f32 / PILOT_PATTERN.len() as f32);
+    diag!(
+        "\nDecoded message: \"{}\" (bytes: {:?})",
+        decoded.message, decoded.raw_bytes
+    );
+    diag!("\n=== Decoding Complete ==="); // footer
+    Ok(decoded)
+}
+
+// --- Frame analysis helpers -------------------------------------------------
+
+fn decoder_frame_len(config: &WatermarkConfig, sample_rate: u32) -> usize {
+    match config.window_frame_len(sample_rate) {
+        Some(Ok(frame_len)) => frame_len, // --window-duration-ms, checked up front
+        _ => frame_len_for(sample_rate, DEFAULT_WINDOW_MS), // samples per frame
+    }
+}
+
+/// Reject a --window-duration-ms the encoder would also have rejected.
+fn check_window(config: &WatermarkConfig, sample_rate: u32) -> Result<(), DecodeError> {
+    match config.window_frame_len(sample_rate) {
+        Some(Err(frame_len)) => Err(DecodeError::BadWindow {
+            ms: config.window_ms.unwrap_or_default(),
+            frame_len,
+        }),
+        _ => Ok(()),
+    }
+}
 
-    let required_bits = message_bytes.saturating_mul(8); // bits required
-    let available_bits = data_bits_all.len(); // bits available
-    let actual_bits = required_bits.min(available_bits); // clamp
+/// FFT length the configured scheme analyses frames at.
+fn analysis_fft_len(config: &WatermarkConfig, sample_rate: u32) -> usize {
+    config.scheme.algorithm().fft_len(decoder_frame_len(config, sample_rate))
+}
+
+/// Plans for one decode; Decoder keeps them between decodes instead.
+fn plans_for(config: &WatermarkConfig, sample_rate: u32) -> FftPlans {
+    PlanCache::new().plans(analysis_fft_len(config, sample_rate))
+}
 
-    if available_bits < required_bits {
-        println!(
-            "Warning: only {} of {} expected bits available; truncating",
-            available_bits, required_bits
+fn describe_framing(samples: &[f32], sample_rate: u32, config: &WatermarkConfig) {
+    let frame_len = decoder_frame_len(config, sample_rate);
+    diag!("Processing {} samples", samples.len());
+    diag!("Processing {}-sample frames", frame_len);
+    warn_if_clamped(config, sample_rate);
+}
+
+/// Band actually read at this rate, clamped the same way the encoder clamps.
+pub fn decoder_band(config: &WatermarkConfig, sample_rate: u32) -> FreqBand {
+    let spectrum_len = decoder_frame_len(config, sample_rate) / 2 + 1; // real FFT bins
+    config.band.clamp_to(config.scheme.carrier_bins(spectrum_len))
+}
+
+fn warn_if_clamped(config: &WatermarkConfig, sample_rate: u32) {
+    let band = decoder_band(config, sample_rate);
+    if band != config.band {
+        diag!(
+            "Warning: band {}-{} runs past the spectrum; clamped to bins {}-{}",
+            config.band.low_bin, config.band.high_bin, band.low_bin, band.high_bin
         );
     }
+}
 
-    let message_bits = data_bits_all[..actual_bits].to_vec(); // payload bits
-    println!("Recovered {} data bits", message_bits.len());
+pub fn decode_amplitude_bits(
+    samples: &[f32],
+    sample_rate: u32,
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+) -> Result<FrameBits, DecodeError> {
+    describe_framing(samples, sample_rate, config);
+    let frames = frame_scores(samples, sample_rate, SCORE_RADIUS, plans, config); // per-frame scores
+    let frames = without_ramp(frames, config); // full-strength frames only
+    bits_from_frames(&frames, config)
+}
 
-    let decoded = bits_to_message(message_bits); // convert to DecodedWatermark
-    println!(
-        "\nDecoded message: \"{}\" (bytes: {:?})",
-        decoded.message, decoded.raw_bytes
+/// Pilot-gated vote over per-frame scores (the blind decoder's decision).
+fn bits_from_frames(frames: &[Vec<f32>], config: &WatermarkConfig) -> Result<FrameBits, DecodeError> {
+    let (scores, votes, valid, skipped) = summarise_frames(frames, config.threshold)?; // aggregate frame stats
+
+    if scores.len() < Watermark::min_bits(config.framing) {
+        return Err(DecodeError::NotEnoughBins); // guard
+    }
+
+    diag!("Used {} frames for decoding ({} skipped)", valid, skipped); // diagnostics
+
+    let (avg_high, avg_low, threshold) = pilot_stats(&scores); // global threshold from pilot
+    diag!(
+        "Pilot scores -> high: {:.6}, low: {:.6}, threshold: {:.6}",
+        avg_high, avg_low, threshold
     );
-    println!("\n=== Decoding Complete ==="); // footer
-    decoded
+    if config.threshold.is_some() {
+        let threshold = manual_threshold(threshold, config);
+        return Ok(FrameBits {
+            bits: bits_from_magnitudes(scores.iter().copied(), threshold).collect(), // plain threshold
+            confidence: None, // no pilot gate to pass
+        });
+    }
+
+    let header_bits = Watermark::header_bits(config.framing); // decided conservatively
+    Ok(FrameBits {
+        bits: decide_bits(&scores, &votes, threshold, avg_high, avg_low, header_bits), // convert scores to bits
+        confidence: Some(valid as f32 / (valid + skipped) as f32), // share of frames used
+    })
+}
+
+fn averaged_amplitude_bits(
+    samples: &[f32],
+    sample_rate: u32,
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+) -> Result<FrameBits, DecodeError> {
+    describe_framing(samples, sample_rate, config);
+    let frames = frame_scores(samples, sample_rate, SCORE_RADIUS, plans, config); // per-frame scores
+    let frames = without_ramp(frames, config); // full-strength frames only
+    if frames.is_empty() {
+        return Err(DecodeError::NoReliableFrames); // nothing to average
+    }
+
+    let mut means = vec![0.0f32; frames[0].len()]; // per-bin running sum
+    for scores in &frames {
+        for (mean, score) in means.iter_mut().zip(scores) {
+            *mean += score;
+        }
+    }
+    for mean in &mut means {
+        *mean /= frames.len() as f32; // sum -> mean
+    }
+    diag!("Averaged {} frames", frames.len()); // diagnostics
+
+    let (avg_high, avg_low, threshold) = pilot_stats(&means); // global threshold from pilot
+    diag!(
+        "Pilot scores -> high: {:.6}, low: {:.6}, threshold: {:.6}",
+        avg_high, avg_low, threshold
+    );
+    let threshold = manual_threshold(threshold, config);
+
+    let passing = frames
+        .iter()
+        .filter(|scores| frame_pilot_stats(scores).is_some_and(|(_, matches)| matches >= PILOT_MIN_MATCHES))
+        .count(); // frames whose own pilot checks out
+
+    Ok(FrameBits {
+        bits: bits_from_magnitudes(means.iter().copied(), threshold).collect(), // plain threshold
+        confidence: Some(passing as f32 / frames.len() as f32),
+    })
+}
+
+/// Like averaged_amplitude_bits, but each frame is weighted by its own pilot
+/// contrast, so frames where the watermark came through cleanly outvote
+/// those buried in noise; frames whose pilot points the wrong way are left
+/// out. The log scores are already independent of the frame's level.
+fn normalised_average_bits(
+    samples: &[f32],
+    sample_rate: u32,
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+) -> Result<FrameBits, DecodeError> {
+    describe_framing(samples, sample_rate, config);
+    let frames = frame_scores(samples, sample_rate, SCORE_RADIUS, plans, config); // per-frame scores
+    let frames = without_ramp(frames, config); // full-strength frames only
+
+    let mut means = vec![0.0f32; frames.first().map_or(0, Vec::len)]; // per-bin weighted sum
+    let mut total_weight = 0.0f32; // sum of frame weights
+    let mut used = 0usize; // frames that contributed
+    for scores in &frames {
+        let (high, low, _) = pilot_stats(scores);
+        let contrast = if config.threshold.is_some() {
+            1.0 // --threshold: no pilot to weight by
+        } else {
+            high - low // this frame's pilot spread
+        };
+        if contrast <= 0.0 {
+            continue; // inverted or flat pilot: noise
+        }
+        for (mean, score) in means.iter_mut().zip(scores) {
+            *mean += score * contrast; // clean frames count for more
+        }
+        total_weight += contrast;
+        used += 1;
+    }
+    if used == 0 {
+        return Err(DecodeError::NoReliableFrames); // nothing to average
+    }
+    for mean in &mut means {
+        *mean /= total_weight; // weighted sum -> mean
+    }
+    diag!("Averaged {} of {} frames (weighted by pilot contrast)", used, frames.len());
+
+    let (avg_high, avg_low, threshold) = pilot_stats(&means); // global threshold from pilot
+    diag!(
+        "Pilot scores -> high: {:.6}, low: {:.6}, threshold: {:.6}",
+        avg_high, avg_low, threshold
+    );
+    let threshold = manual_threshold(threshold, config);
+
+    Ok(FrameBits {
+        bits: bits_from_magnitudes(means.iter().copied(), threshold).collect(), // plain threshold
+        confidence: Some(used as f32 / frames.len() as f32),
+    })
+}
+
+/// Drop the --ramp-frames frames at either end, where the encoder faded the
+/// watermark in and out: their weaker marks would only dilute the vote.
+/// Kept when nothing else would be left.
+fn without_ramp(mut frames: Vec<Vec<f32>>, config: &WatermarkConfig) -> Vec<Vec<f32>> {
+    let ramp = config.ramp_frames;
+    if ramp == 0 || frames.len() <= 2 * ramp {
+        return frames;
+    }
+    frames.truncate(frames.len() - ramp);
+    frames.drain(..ramp);
+    frames
+}
+
+/// Log-normalised spectral scores for every frame with enough bins.
+fn frame_scores(
+    samples: &[f32],
+    sample_rate: u32,
+    window_radius: usize,
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+) -> Vec<Vec<f32>> {
+    let band = decoder_band(config, sample_rate); // same clamp as the encoder
+    if band.capacity(config.guard) < PILOT_PATTERN.len() {
+        return Vec::new(); // not enough bins in any frame
+    }
+
+    let mut frames: Vec<Vec<f32>> = spectra(samples, sample_rate, plans, config)
+        .iter()
+        .map(|magnitudes| {
+            // Guard bins still count as neighbours, but carry no bit
+            let scores = spectral_scores(&magnitudes[band.low_bin..band.high_bin], window_radius);
+            band.data_bins(config.guard)
+                .map(|bin| scores[bin - band.low_bin])
+                .collect()
+        })
+        .collect(); // log-normalised scores
+    if let Some(threshold_db) = config.vad_threshold_db {
+        // The frames the encoder left unmarked carry nothing (see vad.rs)
+        let frame_len = decoder_frame_len(config, sample_rate);
+        let active = active_frames(samples, frame_len, hop_len(frame_len, config.overlap), threshold_db);
+        frames = frames.into_iter().zip(active).filter_map(|(frame, active)| active.then_some(frame)).collect();
+    }
+    match config.max_bits_per_frame {
+        Some(cap) => regroup_frames(frames, cap),
+        None => frames,
+    }
+}
+
+/// --bits-per-frame: put each run of frames that together carry one copy of
+/// the sequence back into a single frame of scores, so everything after
+/// sees whole copies. The encoder splits every frame's data bins into
+/// groups of `cap`, so a copy takes the bin count over `cap` frames; only
+/// the frame the first group starts in is searched for, as the offset whose
+/// runs match the pilot best.
+fn regroup_frames(frames: Vec<Vec<f32>>, cap: usize) -> Vec<Vec<f32>> {
+    let bins = frames.first().map_or(0, Vec::len);
+    if cap >= bins {
+        return frames; // every frame carries everything it can
+    }
+    let groups = bins.div_ceil(cap);
+    let regrouped = |phase: usize| -> Vec<Vec<f32>> {
+        frames[phase.min(frames.len())..]
+            .chunks_exact(groups)
+            .map(|run| {
+                let mut scores = run[groups - 1].clone(); // the last group runs to the end of the band
+                for (group, frame) in run.iter().enumerate().take(groups - 1) {
+                    let bins = group * cap..(group + 1) * cap;
+                    scores[bins.clone()].copy_from_slice(&frame[bins]);
+                }
+                scores
+            })
+            .collect()
+    };
+    // Unmarked bins match a random half of the pilot, often enough to pass
+    // it, so phases are ranked by how many pilot bits match on average
+    let mean_matches = |frames: &[Vec<f32>]| {
+        let matches: usize = frames
+            .iter()
+            .filter_map(|scores| frame_pilot_stats(scores).map(|(_, matches)| matches))
+            .sum();
+        matches as f32 / frames.len().max(1) as f32
+    };
+
+    // Reversed so that a tie goes to the earliest phase
+    let (phase, matches) = (0..groups)
+        .rev()
+        .map(|phase| (phase, mean_matches(&regrouped(phase))))
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+        .unwrap_or((0, 0.0));
+    diag!(
+        "--bits-per-frame {}: {} frames per copy, first group in frame {} ({:.1} of {} pilot bits match)",
+        cap,
+        groups,
+        phase,
+        matches,
+        PILOT_PATTERN.len()
+    );
+    regrouped(phase)
 }
 
-/// Convenience path used by CLI.
-pub fn default_watermarked_path() -> PathBuf {
-    Path::new(env!("CARGO_MANIFEST_DIR"))
-        .join("output_data")
-        .join("OSR_us_000_0057_8k_watermarked.wav")
+/// Windowed magnitude spectrum of every frame, as the decoder frames the audio.
+pub fn frame_spectra(samples: &[f32], sample_rate: u32, config: &WatermarkConfig) -> Vec<Vec<f32>> {
+    let fft_len = decoder_frame_len(config, sample_rate); // FFT size
+    spectra(samples, sample_rate, &PlanCache::new().plans(fft_len), config)
 }
 
-// --- Frame analysis helpers -------------------------------------------------
-
-fn summarise_frames(
+/// frame_spectra with the caller's plans (whose length is the FFT size).
+/// The frames are split into one contiguous run per --threads worker and
+/// the runs put back in order, so the result is the same for any count.
+fn spectra(
     samples: &[f32],
     sample_rate: u32,
-    window_radius: usize,
-) -> (Vec<f32>, Vec<f32>, usize, usize) {
-    let frame_len = ((sample_rate as f32 * WATERMARK_FRAME_DURATION)
-        .round()
-        .max(1.0)) as usize; // samples per frame
-    let fft_len = frame_len.next_power_of_two().max(2); // FFT size
-
-    println!("Processing {} samples", samples.len());
-    println!(
-        "Processing {}-sample frames (FFT len {})",
-        frame_len, fft_len
-    );
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+) -> Vec<Vec<f32>> {
+    let frame_len = decoder_frame_len(config, sample_rate); // samples per frame
+    let hop = hop_len(frame_len, config.overlap); // same hop as the encoder
+    let limit = config.limit_frames.unwrap_or(usize::MAX); // --limit-frames
+    let starts: Vec<usize> = frame_starts(samples.len(), hop).take(limit).collect();
+
+    let threads = config.worker_threads().clamp(1, starts.len().max(1));
+    if threads == 1 {
+        return run_spectra(samples, &starts, frame_len, plans, config);
+    }
+    thread::scope(|scope| {
+        let workers: Vec<_> = starts
+            .chunks(starts.len().div_ceil(threads))
+            .map(|run| scope.spawn(move || run_spectra(samples, run, frame_len, plans, config)))
+            .collect(); // one run of frames per thread
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("spectrum worker panicked"))
+            .collect()
+    })
+}
+
+/// Magnitude spectra of the frames starting at `starts`, with this run's own
+/// buffers.
+fn run_spectra(
+    samples: &[f32],
+    starts: &[usize],
+    frame_len: usize,
+    plans: &FftPlans,
+    config: &WatermarkConfig,
+) -> Vec<Vec<f32>> {
+    let fft_len = plans.fft_len(); // FFT size
 
-    let mut planner = RealFftPlanner::<f32>::new(); // FFT planner
-    let forward = planner.plan_fft_forward(fft_len); // forward FFT
+    let forward = &plans.forward; // forward FFT
     let mut scratch = forward.make_scratch_vec(); // scratch buffer
     let mut buffer = vec![0.0f32; fft_len]; // time-domain buffer
+    let window = make_window(config.window, frame_len); // same window as the encoder
     let mut spectrum = forward.make_output_vec(); // frequency-domain buffer
 
-    let usable_bins = spectrum.len().saturating_sub(START_BIN); // candidate bins
-    let mut score_samples: Vec<Vec<f32>> =
-        (0..usable_bins).map(|_| Vec::with_capacity(128)).collect(); // per-bin scores
-    let mut vote_counts = vec![0u32; usable_bins]; // per-bin “1” votes
-    let mut valid_frames = 0usize; // accepted frames
-    let mut skipped_frames = 0usize; // rejected frames
-
-    let mut offset = 0usize; // frame pointer
-    while offset < samples.len() {
-        let end = (offset + frame_len).min(samples.len()); // clamp frame
-        let frame = &samples[offset..end]; // frame view
+    let mut frames = Vec::with_capacity(starts.len()); // per-frame output
 
+    for &start in starts {
+        let frame = &samples[start..(start + frame_len).min(samples.len())]; // zero-padded tail
         buffer.fill(0.0); // clear buffer
-        buffer[..frame.len()].copy_from_slice(frame); // copy samples
+        for ((slot, sample), w) in buffer.iter_mut().zip(frame).zip(&window) {
+            *slot = sample * w; // copy windowed samples
+        }
 
         forward
             .process_with_scratch(&mut buffer, &mut spectrum, &mut scratch)
             .expect("FFT failed"); // FFT
 
-        let mut magnitudes = Vec::with_capacity(usable_bins); // magnitude list
-        for idx in 0..usable_bins {
-            let bin = START_BIN + idx; // actual bin
-            if bin >= spectrum.len() {
-                break;
-            }
-            magnitudes.push(spectrum[bin].norm()); // magnitude
-        }
+        let whole = FreqBand {
+            low_bin: 0,
+            high_bin: spectrum.len(),
+            spacing: BinSpacing::Linear,
+        }; // every bin, for callers that pick their own band
+        frames.push(band_magnitudes(&spectrum, whole).collect()); // magnitude list
+    }
 
-        if magnitudes.len() < PILOT_PATTERN.len() {
-            skipped_frames += 1; // not enough bins
-            offset += frame_len;
-            continue;
-        }
+    frames
+}
+
+/// |bin| for every bin of `band`, low to high.
+fn band_magnitudes(spectrum: &[Complex<f32>], band: FreqBand) -> impl Iterator<Item = f32> + '_ {
+    spectrum[band.low_bin..band.high_bin].iter().map(magnitude)
+}
+
+/// Threshold a run of magnitudes (or scores): at or above it is a 1
+/// (decides_one).
+fn bits_from_magnitudes(
+    magnitudes: impl Iterator<Item = f32>,
+    threshold: f32,
+) -> impl Iterator<Item = u8> {
+    magnitudes.map(move |magnitude| u8::from(decides_one(magnitude, threshold)))
+}
+
+/// Median score and share of 1 votes per bin over the frames whose pilot
+/// checks out, plus how many were used and skipped. With a `manual`
+/// threshold (--threshold) every frame is used and votes against it.
+fn summarise_frames(
+    frames: &[Vec<f32>],
+    manual: Option<f32>,
+) -> Result<(Vec<f32>, Vec<f32>, usize, usize), DecodeError> {
+    let usable_bins = frames.first().map_or(0, Vec::len); // candidate bins
+    let mut score_samples: Vec<Vec<f32>> =
+        (0..usable_bins).map(|_| Vec::with_capacity(128)).collect(); // per-bin scores
+    let mut vote_counts = vec![0u32; usable_bins]; // per-bin “1” votes
+    let mut valid_frames = 0usize; // accepted frames
+    let mut skipped_frames = 0usize; // rejected frames
 
-        let scores = spectral_scores(&magnitudes, window_radius); // log-normalised scores
-        if let Some((threshold, matches)) = frame_pilot_stats(&scores) {
-            if matches >= 5 {
+    for scores in frames {
+        let pilot = match manual {
+            Some(threshold) => Some((threshold, PILOT_PATTERN.len())), // taken on trust
+            None => frame_pilot_stats(scores),
+        };
+        if let Some((threshold, matches)) = pilot {
+            if matches >= PILOT_MIN_MATCHES {
                 valid_frames += 1; // accept frame
                 for (idx, score) in scores.iter().enumerate() {
-                    if idx >= usable_bins {
-                        break;
-                    }
                     score_samples[idx].push(*score); // record score
-                    if *score >= threshold {
+                    if decides_one(*score, threshold) {
                         vote_counts[idx] += 1; // vote for “1”
                     }
                 }
@@ -170,12 +1677,10 @@ fn summarise_frames(
         } else {
             skipped_frames += 1; // pilot unusable
         }
-
-        offset += frame_len; // advance frame pointer
     }
 
     if valid_frames == 0 {
-        panic!("unable to decode watermark: no reliable frames detected");
+        return Err(DecodeError::NoReliableFrames);
     }
 
     let mut medians = Vec::with_capacity(usable_bins); // aggregated scores
@@ -195,7 +1700,7 @@ fn summarise_frames(
         .map(|votes| votes as f32 / valid_frames as f32)
         .collect(); // convert to ratios
 
-    (medians, ratios, valid_frames, skipped_frames) // summary
+    Ok((medians, ratios, valid_frames, skipped_frames)) // summary
 }
 
 fn spectral_scores(magnitudes: &[f32], window_radius: usize) -> Vec<f32> {
@@ -248,16 +1753,26 @@ fn frame_pilot_stats(scores: &[f32]) -> Option<(f32, usize)> {
         return None; // degenerate
     }
 
-    let threshold = (sum_high / count_high as f32 + sum_low / count_low as f32) * 0.5; // per-frame decision
-    let matches = pilot
-        .iter()
+    let threshold = threshold_from_pilot(sum_high / count_high as f32, sum_low / count_low as f32); // per-frame decision
+    let matches = bits_from_magnitudes(pilot.iter().copied(), threshold)
         .zip(PILOT_PATTERN.iter())
-        .filter(|(score, expected)| u8::from(**score >= threshold) == **expected)
+        .filter(|(bit, expected)| bit == *expected)
         .count(); // match count
 
     Some((threshold, matches))
 }
 
+/// --threshold in place of the pilot's `calibrated` one, when given.
+fn manual_threshold(calibrated: f32, config: &WatermarkConfig) -> f32 {
+    match config.threshold {
+        Some(threshold) => {
+            diag!("Manual threshold {:.6}; pilot calibration bypassed", threshold);
+            threshold
+        }
+        None => calibrated,
+    }
+}
+
 fn pilot_stats(scores: &[f32]) -> (f32, f32, f32) {
     let pilot = &scores[..PILOT_PATTERN.len()];
     let mut sum_high = 0.0f32;
@@ -277,7 +1792,7 @@ fn pilot_stats(scores: &[f32]) -> (f32, f32, f32) {
 
     let avg_high = sum_high / count_high as f32;
     let avg_low = sum_low / count_low as f32;
-    let threshold = (avg_high + avg_low) * 0.5;
+    let threshold = threshold_from_pilot(avg_high, avg_low);
     (avg_high, avg_low, threshold)
 }
 
@@ -287,6 +1802,7 @@ fn decide_bits(
     threshold: f32,
     avg_high: f32,
     avg_low: f32,
+    header_bits: usize,
 ) -> Vec<u8> {
     let decision_band = (avg_high - avg_low) * 0.1; // hysteresis
 
@@ -296,61 +1812,1133 @@ fn decide_bits(
         .enumerate()
         .map(|(idx, (&score, &ratio))| {
             let in_length_header =
-                (PILOT_PATTERN.len()..PILOT_PATTERN.len() + LENGTH_HEADER_BITS).contains(&idx); // header segments
-            let bit = if in_length_header {
-                u8::from(ratio >= 0.54 && score >= threshold)
-            } else if score >= threshold {
+                (PILOT_PATTERN.len()..PILOT_PATTERN.len() + header_bits).contains(&idx); // header segments
+            if in_length_header {
+                u8::from(ratio >= 0.54 && decides_one(score, threshold))
+            } else if decides_one(score, threshold) {
                 1 // confident one
             } else if score <= threshold - decision_band {
                 0 // confident zero
             } else {
                 u8::from(ratio >= 0.48 || score >= threshold - decision_band * 0.5)
                 // soft fallback
-            };
-            bit
+            }
         })
         .collect()
 }
 
 // --- Bitstream utilities ----------------------------------------------------
 
-fn decode_length_header(bits: &[u8]) -> usize {
-    let mut len = 0u16;
-    for bit in bits {
-        len = (len << 1) | u16::from(bit & 1); // shift & merge
+/// --typed: split off the type tag and describe non-text payloads in `message`.
+fn typed_message(bytes: Vec<u8>) -> DecodedWatermark {
+    let Some(payload) = Payload::from_bytes(&bytes, true) else {
+        diag!("Warning: unrecognised payload type tag; returning the bytes as raw");
+        let mut decoded = bytes_to_message(bytes.clone());
+        decoded.payload = Payload::Raw(bytes);
+        return decoded;
+    };
+    diag!("Typed payload: {}", payload);
+    let mut decoded = bytes_to_message(bytes[1..].to_vec()); // body without the tag
+    if !matches!(payload, Payload::Text(_)) {
+        decoded.message = payload.to_string(); // readable form of an ID or raw bytes
     }
-    len as usize
+    decoded.payload = payload;
+    decoded
 }
 
-fn bits_to_message(bits: Vec<u8>) -> DecodedWatermark {
-    let mut bytes = Vec::with_capacity((bits.len() + 7) / 8); // allocate
-    for chunk in bits.chunks(8) {
-        let mut byte = 0u8;
-        for bit in chunk {
-            byte = (byte << 1) | (bit & 1); // pack bits
-        }
-        bytes.push(byte);
-    }
+fn bytes_to_message(bytes: Vec<u8>) -> DecodedWatermark {
+    let message = String::from_utf8_lossy(&bytes).into_owned();
     DecodedWatermark {
-        message: String::from_utf8_lossy(&bytes).into_owned(),
+        payload: Payload::Text(message.clone()),
+        message,
         raw_bytes: bytes,
+        bits: Vec::new(),
+        crc_ok: None,
+        confidence: 0.0,
+        nonce: None,
+        tag_ok: None,
+    }
+}
+
+/// Bit sequence the encoder embeds for `message` (pilot + magic + framing + payload).
+/// Pass the decoded nonce to rebuild a nonce-carrying stream.
+pub fn expected_bits(message: &str, config: &WatermarkConfig, nonce: Option<u16>) -> Vec<u8> {
+    let mut bytes = Payload::Text(message.to_string()).to_bytes(config.typed_payload);
+    if config.compress {
+        bytes = compress(&bytes);
     }
+    let mut watermark = Watermark::new(&bytes, config.framing);
+    if let Some(nonce) = nonce {
+        watermark = watermark.with_nonce(nonce);
+    }
+    if let Some(key) = &config.key {
+        watermark = watermark.with_key(key);
+    }
+    watermark.to_bits()
+}
+
+/// Fraction of `expected_bits` that were not recovered correctly.
+/// Positions missing from `decoded_bits` count as errors.
+pub fn bit_error_rate(decoded_bits: &[u8], expected_bits: &[u8]) -> f32 {
+    if expected_bits.is_empty() {
+        return 0.0; // nothing to compare
+    }
+    let errors = expected_bits
+        .iter()
+        .enumerate()
+        .filter(|(idx, want)| decoded_bits.get(*idx) != Some(*want))
+        .count(); // mismatches + missing
+    errors as f32 / expected_bits.len() as f32
 }
 
 // --- Audio I/O --------------------------------------------------------------
 
-fn load_audio(path: &Path) -> (Vec<f32>, u32) {
-    println!("Loading watermarked audio from {}", path.display());
-    let mut reader = WavReader::open(path).expect("failed to open watermarked wav");
-    let spec = reader.spec();
-    let samples: Vec<f32> = reader
-        .samples::<i16>()
-        .map(|s| s.expect("failed to read sample") as f32 / SAMPLE_DIVISOR)
-        .collect();
-    println!(
+/// Samples of the channel the watermark is read from (the first carrier).
+pub fn load_audio(path: &Path, config: &WatermarkConfig) -> Result<(Vec<f32>, u32), DecodeError> {
+    let (samples, channels, sample_rate) = read_wav(path, config)?;
+    check_window(config, sample_rate)?;
+    let samples = carrier_channel(&samples, channels, config)?;
+    Ok((skip_to_since(samples, sample_rate, config), sample_rate))
+}
+
+/// Every sample in the file, still interleaved, plus channel count and rate
+/// (after --resample, if given).
+fn read_wav(path: &Path, config: &WatermarkConfig) -> Result<(Vec<f32>, u16, u32), DecodeError> {
+    diag!("Loading watermarked audio from {}", path.display());
+    // Any depth the encoder can write (--output-format) -> f32
+    let (samples, spec) =
+        wav::read_file(path, config.allow_truncated).map_err(DecodeError::BadWav)?;
+    diag!(
         "Loaded {} samples at {} Hz",
         samples.len(),
         spec.sample_rate
     );
-    (samples, spec.sample_rate)
+    match config.resample_to.filter(|&rate| rate != spec.sample_rate) {
+        Some(rate) => {
+            let samples = resample_interleaved(&samples, spec.channels, spec.sample_rate, rate);
+            diag!("Resampled {} Hz -> {} Hz ({} samples)", spec.sample_rate, rate, samples.len());
+            Ok((samples, spec.channels, rate))
+        }
+        None => Ok((samples, spec.channels, spec.sample_rate)),
+    }
+}
+
+/// Drop everything before --since, or before --input-offset-samples (where
+/// the encoder's first frame starts) when that is later. Offsets reported
+/// afterwards (alignment, trace starts) count from that point.
+fn skip_to_since(mut samples: Vec<f32>, sample_rate: u32, config: &WatermarkConfig) -> Vec<f32> {
+    let since = config.since.map_or(0, |since| since.to_samples(sample_rate));
+    let skip = since.max(config.embed_offset);
+    if skip == 0 {
+        return samples;
+    }
+    let flag = if since >= config.embed_offset { "--since" } else { "--input-offset-samples" };
+    if skip >= samples.len() {
+        diag!("Warning: {} {} is past the end ({} samples)", flag, skip, samples.len());
+    } else {
+        diag!("Skipping to sample {} ({})", skip, flag);
+    }
+    samples.drain(..skip.min(samples.len()));
+    samples
+}
+
+/// The first carrier channel out of interleaved samples.
+fn carrier_channel(
+    samples: &[f32],
+    channels: u16,
+    config: &WatermarkConfig,
+) -> Result<Vec<f32>, DecodeError> {
+    let channels = usize::from(channels.max(1)); // interleaved channel count
+    let channel = config.carrier_channels()[0]; // channel to decode
+    if let ChannelStrategy::MidSide(_) = config.channel_strategy {
+        if channels != 2 {
+            return Err(DecodeError::NotStereo { channels });
+        }
+        let mut tracks = deinterleave(samples, channels); // [left, right]
+        to_mid_side(&mut tracks); // same transform as the encoder
+        diag!("Reading {:?}", config.channel_strategy);
+        return Ok(tracks.swap_remove(channel));
+    }
+    if channel >= channels {
+        return Err(DecodeError::NoSuchChannel { channel, channels });
+    }
+    if channels > 1 {
+        diag!("Reading channel {} of {}", channel, channels);
+    }
+    Ok(samples.iter().skip(channel).step_by(channels).copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attack;
+    use crate::config::{EmbedMode, Framing, Offset};
+    use crate::window::Window;
+    use crate::encoder::Encoder;
+
+    const RATE: u32 = 8000;
+
+    // Deterministic broadband stand-in for speech: uniform noise peaking at
+    // 0.25, so every band bin has energy to boost or suppress
+    fn test_signal(samples: usize) -> Vec<f32> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..samples)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                ((state >> 40) as f32 / (1u64 << 24) as f32 - 0.5) * 0.5
+            })
+            .collect()
+    }
+
+    // Two seconds of test_signal
+    fn two_seconds() -> Vec<f32> {
+        test_signal(RATE as usize * 2)
+    }
+
+    fn encode(text: &str, samples: &[f32], config: &WatermarkConfig) -> Vec<f32> {
+        let message = Payload::Text(text.to_string());
+        Encoder::new(config.clone()).encode(samples, 1, RATE, &message).unwrap().0
+    }
+
+    fn decode(samples: &[f32], config: &WatermarkConfig) -> DecodedWatermark {
+        decode_samples(samples, 1, RATE, config).unwrap()
+    }
+
+    // 16-bit WAV of `samples` in the temp directory, for the decode modes
+    // that read a file; removed when dropped
+    struct TempWav(PathBuf);
+
+    impl TempWav {
+        fn new(name: &str, samples: &[f32]) -> TempWav {
+            TempWav::interleaved(name, samples, 1)
+        }
+
+        fn interleaved(name: &str, samples: &[f32], channels: u16) -> TempWav {
+            let file = format!("msg_encoder-test-{}-{}.wav", std::process::id(), name);
+            let path = std::env::temp_dir().join(file);
+            let spec = hound::WavSpec {
+                channels,
+                sample_rate: RATE,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for &sample in samples {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * 32_767.0).round() as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+            TempWav(path)
+        }
+    }
+
+    impl Drop for TempWav {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn phase_coding_round_trip() {
+        let config = WatermarkConfig {
+            scheme: Scheme::PhaseCoding,
+            ..WatermarkConfig::default()
+        };
+        let encoded = encode("phase", &two_seconds(), &config);
+        assert_eq!(decode(&encoded, &config).message, "phase");
+    }
+
+    #[test]
+    fn a_later_copy_survives_trimming_the_first() {
+        let config = WatermarkConfig {
+            scheme: Scheme::PhaseCoding,
+            repeat_every: Some(2.0),
+            ..WatermarkConfig::default()
+        };
+        let encoded = encode("hello", &test_signal(RATE as usize * 8), &config);
+        let wav = TempWav::new("copies", &encoded);
+        let copies = decode_all(&wav.0, &config).unwrap();
+        assert_eq!(copies.len(), 4);
+        assert!(copies.iter().all(|(_, decoded)| decoded.message == "hello"));
+
+        // Cut into the first copy: the blind decode reads its frame 0 and fails
+        let trimmed = &encoded[12_345..];
+        assert!(decode_samples(trimmed, 1, RATE, &config).map_or(true, |decoded| decoded.message != "hello"));
+        let wav = TempWav::new("copies-trimmed", trimmed);
+        let survivors = decode_all(&wav.0, &config).unwrap();
+        assert!(survivors.iter().all(|(_, decoded)| decoded.message == "hello"));
+        let starts: Vec<usize> = survivors.iter().map(|&(start, _)| start).collect();
+        let later: Vec<usize> = copies[1..].iter().map(|&(start, _)| start - 12_345).collect();
+        assert_eq!(starts, later);
+    }
+
+    #[test]
+    fn overlapped_frames_round_trip() {
+        for overlap in [0.5, 0.75] {
+            let signal = two_seconds();
+            // With no bits the Hann overlap-add gives the input back
+            let silent = WatermarkConfig {
+                window: Window::Hann,
+                overlap,
+                embed_mode: EmbedMode::None,
+                ..WatermarkConfig::default()
+            };
+            let passed = encode("overlap", &signal, &silent);
+            let error = signal.iter().zip(&passed).map(|(x, y)| (x - y).abs()).fold(0.0f32, f32::max);
+            assert!(error < 1e-5, "overlap {}: {}", overlap, error);
+            // The decoder steps its frames by the same hop
+            let config = WatermarkConfig {
+                overlap,
+                ..WatermarkConfig::default()
+            };
+            let encoded = encode("overlap", &signal, &config);
+            assert_eq!(decode(&encoded, &config).message, "overlap", "overlap {}", overlap);
+        }
+    }
+
+    // A 16-bit mono WAV of `samples`, in memory, behind a reader that counts
+    // the bytes taken from it into `read`
+    struct CountingWav<'a> {
+        bytes: std::io::Cursor<Vec<u8>>,
+        read: &'a std::cell::Cell<usize>,
+    }
+
+    impl<'a> CountingWav<'a> {
+        fn new(samples: &[f32], read: &'a std::cell::Cell<usize>) -> CountingWav<'a> {
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: RATE,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let mut bytes = std::io::Cursor::new(Vec::new());
+            let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+            for &sample in samples {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * 32_767.0).round() as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+            bytes.set_position(0);
+            CountingWav { bytes, read }
+        }
+    }
+
+    impl Read for CountingWav<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let count = self.bytes.read(buf)?;
+            self.read.set(self.read.get() + count);
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn streamed_bytes_arrive_with_their_frames_and_match_the_full_decode() {
+        // 6 message bytes: 88 bits over 20-bit frames, the header in the first two
+        let config = WatermarkConfig {
+            max_bits_per_frame: Some(20),
+            ..WatermarkConfig::default()
+        };
+        let encoded = encode("stream", &two_seconds(), &config);
+        let read = std::cell::Cell::new(0);
+        let mut arrivals = Vec::new(); // each byte, and how much of the file was read by then
+        let decoded = decode_stream(CountingWav::new(&encoded, &read), &config, |byte| {
+            arrivals.push((byte, read.get()))
+        })
+        .unwrap();
+        let file_len = read.get();
+        let bytes: Vec<u8> = arrivals.iter().map(|&(byte, _)| byte).collect();
+        assert_eq!(bytes, decoded.raw_bytes);
+        assert_eq!(decoded.message, "stream");
+        // Long before the end (a doubtful bit waits for a copy or two more),
+        // and not all at once
+        assert!(arrivals.iter().all(|&(_, read)| read < file_len / 2), "{:?} of {}", arrivals, file_len);
+        assert!(arrivals[0].1 < arrivals[5].1, "{:?}", arrivals);
+
+        // Trimmed to start mid-copy: the stream waits for the next whole one
+        let frame_len = 256; // 32 ms at RATE
+        let mut streamed = Vec::new();
+        let trimmed = decode_stream(CountingWav::new(&encoded[3 * frame_len..], &read), &config, |byte| {
+            streamed.push(byte)
+        })
+        .unwrap();
+        assert_eq!((streamed, trimmed.message), (b"stream".to_vec(), "stream".to_string()));
+
+        // A deflated payload only inflates whole: everything comes at the end
+        let compressed = WatermarkConfig {
+            compress: true,
+            ..config
+        };
+        let encoded = encode("stream", &two_seconds(), &compressed);
+        read.set(0);
+        let mut arrivals = Vec::new();
+        let decoded = decode_stream(CountingWav::new(&encoded, &read), &compressed, |byte| {
+            arrivals.push((byte, read.get()))
+        })
+        .unwrap();
+        assert_eq!(decoded.message, "stream");
+        assert!(arrivals.iter().all(|&(_, at)| at == read.get()), "{:?}", arrivals);
+    }
+
+    #[test]
+    fn a_cut_off_stream_is_an_error_unless_truncation_is_allowed() {
+        let config = WatermarkConfig::default();
+        let encoded = encode("cut", &two_seconds(), &config);
+        let read = std::cell::Cell::new(0);
+        let cut_off = || {
+            let mut wav = CountingWav::new(&encoded, &read);
+            let len = wav.bytes.get_ref().len();
+            wav.bytes.get_mut().truncate(len - 1001); // 500.5 samples short
+            wav
+        };
+        let result = decode_stream(cut_off(), &config, |_| {});
+        let declared = encoded.len();
+        assert!(
+            matches!(result, Err(DecodeError::BadWav(WavError::Truncated { read, declared: d }))
+                if read == declared - 501 && d == declared),
+            "{:?}",
+            result.map(|decoded| decoded.message)
+        );
+
+        let allowed = WatermarkConfig {
+            allow_truncated: true,
+            ..config
+        };
+        let decoded = decode_stream(cut_off(), &allowed, |_| {}).unwrap();
+        assert_eq!(decoded.message, "cut");
+    }
+
+    #[test]
+    fn bits_per_frame_round_trip_from_any_first_frame() {
+        let config = WatermarkConfig {
+            max_bits_per_frame: Some(20),
+            ..WatermarkConfig::default()
+        };
+        let encoded = encode("grouped", &two_seconds(), &config);
+        assert_eq!(decode(&encoded, &config).message, "grouped");
+        // Cut two frames off the front: the copies now start mid-run
+        let frame_len = 256; // 32 ms at RATE
+        assert_eq!(decode(&encoded[2 * frame_len..], &config).message, "grouped");
+    }
+
+    #[test]
+    fn sentinel_framing_round_trip() {
+        let config = WatermarkConfig {
+            framing: Framing::Sentinel,
+            ..WatermarkConfig::default()
+        };
+        let encoded = encode("hello", &two_seconds(), &config);
+        let decoded = decode(&encoded, &config);
+        assert_eq!(decoded.message, "hello");
+        assert_eq!(decoded.crc_ok, None); // sentinel framing has no header CRC
+    }
+
+    #[test]
+    fn averaging_beats_single_frame_votes_under_noise() {
+        let config = WatermarkConfig::default();
+        let expected = expected_bits("hello", &config, None);
+        let encoded = encode("hello", &two_seconds(), &config);
+        let noisy = attack::add_noise(&encoded, -5.0, attack::NOISE_SEED); // noise 5 dB louder
+        let plans = plans_for(&config, RATE);
+        let voted = decode_amplitude_bits(&noisy, RATE, &plans, &config).unwrap();
+        let averaged = averaged_amplitude_bits(&noisy, RATE, &plans, &config).unwrap();
+        let (voted, averaged) = (
+            bit_error_rate(&voted.bits, &expected),
+            bit_error_rate(&averaged.bits, &expected),
+        );
+        assert!(averaged < voted, "averaged BER {} vs voted {}", averaged, voted);
+    }
+
+    #[test]
+    fn padded_silence_is_trimmed_and_put_back() {
+        let mut padded = vec![0.0; 1000];
+        padded.extend(two_seconds());
+        padded.extend([0.0; 700]);
+        let trimmed = WatermarkConfig {
+            trim_silence_db: Some(-60.0),
+            ..WatermarkConfig::default()
+        };
+        let encoded = encode("hi", &padded, &trimmed);
+        assert_eq!(encoded.len(), padded.len() - 1700);
+        assert_eq!(decode(&encoded, &trimmed).message, "hi");
+
+        // Re-padded: the lead is trimmed in whole frames, so the grid holds
+        let repadded = WatermarkConfig {
+            repad_silence: true,
+            ..trimmed
+        };
+        let encoded = encode("hi", &padded, &repadded);
+        assert_eq!(encoded.len(), padded.len());
+        assert!(encoded[..768].iter().chain(&encoded[encoded.len() - 700..]).all(|&x| x == 0.0));
+        assert_eq!(decode(&encoded, &repadded).message, "hi");
+    }
+
+    #[test]
+    fn alignment_search_recovers_a_small_offset() {
+        let config = WatermarkConfig::default();
+        let encoded = encode("hello", &two_seconds(), &config);
+        let mut shifted = test_signal(64); // 64 samples of lead-in: a quarter frame
+        shifted.extend(&encoded);
+        let wav = TempWav::new("align", &shifted);
+        let (decoded, alignment) =
+            decode_best_alignment(&wav.0, &config, &AtomicBool::new(false)).unwrap();
+        assert_eq!(alignment.offset, 64);
+        assert_eq!(decoded.message, "hello");
+    }
+
+    #[test]
+    fn json_report_has_the_documented_fields() {
+        let config = WatermarkConfig::default();
+        let decoded = decode(&encode("hi", &two_seconds(), &config), &config);
+        let report = DecodeReport::new(&decoded, 0, Some(0.0), config.scheme);
+        let json = serde_json::to_value(&report).unwrap();
+        let mut keys: Vec<&str> = json.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            [
+                "ber", "channel_confidences", "confidence", "crc_ok", "message", "offset",
+                "payload", "scheme", "tag_ok"
+            ]
+        );
+        assert_eq!(json["message"], "hi");
+        assert_eq!(json["crc_ok"], true);
+        assert_eq!(json["scheme"], "amplitude");
+        assert!(json["confidence"].as_f64().unwrap() > 0.9);
+    }
+
+    #[test]
+    fn clean_audio_is_not_watermarked() {
+        let result = decode_samples(&two_seconds(), 1, RATE, &WatermarkConfig::default());
+        assert!(matches!(result, Err(DecodeError::NoWatermark)));
+    }
+
+    #[test]
+    fn six_channel_file_carries_the_mark_in_channel_zero_only() {
+        // Six different stretches of the signal, interleaved
+        let signal = test_signal(RATE as usize * 12);
+        let tracks: Vec<&[f32]> = signal.chunks(RATE as usize * 2).collect();
+        let interleaved: Vec<f32> = (0..RATE as usize * 2)
+            .flat_map(|n| tracks.iter().map(move |track| track[n]))
+            .collect();
+        let config = WatermarkConfig::default(); // channel 0, front left
+        let message = Payload::Text("5.1".to_string());
+        let (encoded, _) = Encoder::new(config.clone()).encode(&interleaved, 6, RATE, &message).unwrap();
+        assert_eq!(encoded.len(), interleaved.len());
+
+        assert_eq!(decode_samples(&encoded, 6, RATE, &config).unwrap().message, "5.1");
+        for channel in 1..6 {
+            // The other channels pass through untouched
+            let mut untouched = encoded.iter().zip(&interleaved).skip(channel).step_by(6);
+            assert!(untouched.all(|(out, x)| out == x), "channel {} changed", channel);
+            let elsewhere = WatermarkConfig {
+                channels: vec![channel],
+                ..WatermarkConfig::default()
+            };
+            let result = decode_samples(&encoded, 6, RATE, &elsewhere);
+            assert!(matches!(result, Err(DecodeError::NoWatermark)), "channel {}", channel);
+        }
+        let missing = WatermarkConfig {
+            channels: vec![6],
+            ..WatermarkConfig::default()
+        };
+        let result = decode_samples(&encoded, 6, RATE, &missing);
+        assert!(matches!(result, Err(DecodeError::NoSuchChannel { channel: 6, channels: 6 })));
+    }
+
+    #[test]
+    fn trace_has_one_record_per_frame() {
+        let config = WatermarkConfig::default();
+        let encoded = encode("trace", &two_seconds(), &config);
+        let wav = TempWav::new("trace", &encoded);
+        let before = decode_watermarked_sample(&wav.0, &config).unwrap();
+        let traces = trace_frames(&wav.0, 0, &config).unwrap();
+        let frame_len = decoder_frame_len(&config, RATE);
+        assert_eq!(traces.len(), encoded.len().div_ceil(frame_len));
+        for (idx, trace) in traces.iter().enumerate() {
+            assert_eq!((trace.frame, trace.start), (idx, idx * frame_len));
+            assert_eq!(trace.bits.len(), trace.scores.len());
+            let line = serde_json::to_string(trace).unwrap();
+            assert!(!line.contains('\n'), "one JSON line per record");
+        }
+        assert!(traces.iter().filter(|trace| trace.used).count() > traces.len() / 2);
+        // Tracing doesn't touch the decode
+        let after = decode_watermarked_sample(&wav.0, &config).unwrap();
+        assert_eq!((before.message, before.raw_bytes), (after.message, after.raw_bytes));
+    }
+
+    #[test]
+    fn window_duration_sets_frame_len_and_bins_on_both_sides() {
+        let message = Payload::Text("hi".to_string());
+        // Runs past Nyquist at every length, so it reaches the top bin
+        let band = FreqBand {
+            low_bin: 2,
+            high_bin: 1000,
+            spacing: BinSpacing::Linear,
+        };
+        for (ms, frame_len, bins) in [(16.0, 128, 63), (32.0, 256, 127), (64.0, 512, 255)] {
+            let config = WatermarkConfig {
+                window_ms: Some(ms),
+                band,
+                ..WatermarkConfig::default()
+            };
+            assert_eq!(decoder_frame_len(&config, RATE), frame_len);
+            assert_eq!(decoder_band(&config, RATE).bin_count(), bins);
+            let encoder = Encoder::new(config.clone());
+            let (encoded, report) = encoder.encode(&test_signal(RATE as usize * 2), 1, RATE, &message).unwrap();
+            assert_eq!(report.frame_len, frame_len);
+            assert_eq!(decode_samples(&encoded, 1, RATE, &config).unwrap().message, "hi", "{} ms", ms);
+        }
+    }
+
+    #[test]
+    fn frames_that_are_not_a_power_of_two_round_trip() {
+        let message = Payload::Text("hi".to_string());
+        // 32 ms at 44.1 kHz, a 30 ms window at RATE, and 32 ms at 48 kHz
+        for (rate, window_ms, frame_len) in [(44_100, None, 1411), (RATE, Some(30.0), 240), (48_000, None, 1536)] {
+            let config = WatermarkConfig {
+                window_ms,
+                ..WatermarkConfig::default()
+            };
+            let samples = test_signal(rate as usize * 2);
+            let (encoded, report) = Encoder::new(config.clone()).encode(&samples, 1, rate, &message).unwrap();
+            assert_eq!((report.frame_len, decoder_frame_len(&config, rate)), (frame_len, frame_len));
+            assert_eq!(decode_samples(&encoded, 1, rate, &config).unwrap().message, "hi", "{} Hz", rate);
+        }
+        // Too short to carry the band is still refused
+        let tiny = WatermarkConfig {
+            window_ms: Some(2.0),
+            ..WatermarkConfig::default()
+        };
+        assert!(matches!(
+            decode_samples(&two_seconds(), 1, RATE, &tiny),
+            Err(DecodeError::BadWindow { frame_len: 16, .. })
+        ));
+    }
+
+    #[test]
+    fn tag_checks_out_only_under_the_embedding_key() {
+        let keyed = |key: &[u8]| WatermarkConfig {
+            key: Some(key.to_vec()),
+            ..WatermarkConfig::default()
+        };
+        let message = Payload::Text("hi".to_string());
+        let encoder = Encoder::new(keyed(b"key"));
+        let (encoded, _) = encoder.encode(&test_signal(RATE as usize * 2), 1, RATE, &message).unwrap();
+        let decoded = decode_samples(&encoded, 1, RATE, &keyed(b"key")).unwrap();
+        assert_eq!((decoded.message.as_str(), decoded.tag_ok), ("hi", Some(true)));
+        // Same bytes, wrong key: the message still comes back, flagged
+        let decoded = decode_samples(&encoded, 1, RATE, &keyed(b"other")).unwrap();
+        assert_eq!((decoded.message.as_str(), decoded.tag_ok), ("hi", Some(false)));
+    }
+
+    // Each strength is a different boost / suppress ratio. The decoder is
+    // never told which: every frame's threshold comes from its own pilot
+    #[test]
+    fn decodes_without_knowing_the_scale_factor_ratio() {
+        let message = Payload::Text("hi".to_string());
+        for strength in [0.3, 0.6, 1.0, 1.5] {
+            let config = WatermarkConfig {
+                strength: Some(strength),
+                ..WatermarkConfig::default()
+            };
+            let encoder = Encoder::new(config);
+            let (encoded, _) = encoder.encode(&test_signal(RATE as usize * 2), 1, RATE, &message).unwrap();
+            let decoded = decode_samples(&encoded, 1, RATE, &WatermarkConfig::default()).unwrap();
+            assert_eq!(decoded.message, "hi", "strength {}", strength);
+        }
+    }
+
+    #[test]
+    fn typed_payloads_come_back_as_the_variant_embedded() {
+        // An 11-byte content ID needs more than the default band's bins
+        let config = WatermarkConfig {
+            typed_payload: true,
+            band: FreqBand::from_name("1-129").unwrap(),
+            ..WatermarkConfig::default()
+        };
+        let payloads = [
+            Payload::Text("hi".to_string()),
+            Payload::ContentId {
+                id: 1_234_567_890_123,
+                version: 7,
+            },
+            Payload::Raw(vec![0x00, 0xff, 0x80]),
+        ];
+        let encoder = Encoder::new(config.clone());
+        for payload in payloads {
+            let (encoded, _) = encoder.encode(&test_signal(RATE as usize * 2), 1, RATE, &payload).unwrap();
+            assert_eq!(decode_samples(&encoded, 1, RATE, &config).unwrap().payload, payload);
+        }
+    }
+
+    #[test]
+    fn since_starts_the_decode_where_the_watermark_does() {
+        let config = WatermarkConfig::default();
+        // Half a frame off the grid, behind clean audio
+        let lead = 50 * 256 + 128;
+        let mut delayed = test_signal(RATE as usize * 3)[..lead].to_vec();
+        delayed.extend(encode("hi", &two_seconds(), &config));
+        let from_zero = decode_samples(&delayed, 1, RATE, &config);
+        assert!(from_zero.map_or(true, |decoded| decoded.message != "hi"));
+        for since in [Offset::Samples(lead), Offset::Seconds(lead as f32 / RATE as f32)] {
+            let config = WatermarkConfig {
+                since: Some(since),
+                ..config.clone()
+            };
+            assert_eq!(decode(&delayed, &config).message, "hi", "{:?}", since);
+        }
+    }
+
+    // Pitch-preserving time-stretch by `ratio` (1.03: 3% longer): 1024-sample
+    // Hann grains read from the input at 1 / ratio of the pace they're laid
+    // down at, overlap-added at 50%
+    fn stretch(samples: &[f32], ratio: f32) -> Vec<f32> {
+        const GRAIN: usize = 1024;
+        let weights = make_window(Window::Hann, GRAIN);
+        let len = (samples.len() as f32 * ratio) as usize;
+        let mut stretched = vec![0.0f32; len + GRAIN];
+        for start in (0..len).step_by(GRAIN / 2) {
+            let source = (start as f32 / ratio) as usize;
+            for (n, weight) in weights.iter().enumerate() {
+                stretched[start + n] += samples.get(source + n).copied().unwrap_or(0.0) * weight;
+            }
+        }
+        stretched.truncate(len);
+        stretched
+    }
+
+    #[test]
+    fn resync_follows_a_three_percent_stretch() {
+        let config = WatermarkConfig::default();
+        let encoded = encode("hello", &test_signal(RATE as usize * 4), &config);
+        for ratio in [1.03, 0.97] {
+            let stretched = stretch(&encoded, ratio);
+            // The frame grid drifts off the fixed one within a few frames
+            let plain = decode_samples(&stretched, 1, RATE, &config);
+            assert!(plain.map_or(true, |decoded| decoded.message != "hello"), "ratio {}", ratio);
+            let wav = TempWav::new(&format!("stretch-{}", ratio), &stretched);
+            let decoded = decode_resync(&wav.0, &config, &AtomicBool::new(false)).unwrap();
+            assert_eq!(decoded.message, "hello", "ratio {}", ratio);
+        }
+    }
+
+    #[test]
+    fn a_clean_channel_rescues_a_wrecked_one() {
+        let config = WatermarkConfig {
+            channels: vec![0, 1],
+            ..WatermarkConfig::default()
+        };
+        let left = test_signal(RATE as usize * 2);
+        let stereo: Vec<f32> = left.iter().zip(left.iter().rev()).flat_map(|(&l, &r)| [l, r]).collect();
+        let message = Payload::Text("hello".to_string());
+        let (encoded, _) = Encoder::new(config.clone()).encode(&stereo, 2, RATE, &message).unwrap();
+        // Bury the left channel's watermark under noise louder than the audio
+        let wrecked_left = attack::add_noise(&deinterleave(&encoded, 2)[0], -6.0, attack::NOISE_SEED);
+        let wrecked: Vec<f32> = encoded
+            .chunks(2)
+            .zip(&wrecked_left)
+            .flat_map(|(pair, &l)| [l, pair[1]])
+            .collect();
+        let left_only = decode_samples(&wrecked, 2, RATE, &WatermarkConfig::default());
+        assert!(left_only.map_or(true, |decoded| decoded.message != "hello"));
+
+        let wav = TempWav::interleaved("rescue", &wrecked, 2);
+        let (decoded, confidences) = decode_channels_independent(&wav.0, &config).unwrap();
+        assert_eq!(decoded.message, "hello");
+        assert!(confidences[1] > confidences[0], "{:?}", confidences);
+    }
+
+    #[test]
+    fn eight_bit_requantization_keeps_the_bits() {
+        let config = WatermarkConfig::default();
+        let encoded = encode("hello", &two_seconds(), &config);
+        let expected = expected_bits("hello", &config, None);
+        for (bits, max_ber) in [(8, 0.0), (6, 0.05)] {
+            let requantized = attack::requantize(&encoded, bits);
+            let blind = decode(&requantized, &config);
+            let ber = bit_error_rate(&blind.bits, &expected);
+            assert!(ber <= max_ber, "{} bits: BER {}", bits, ber);
+
+            // Averaging over every frame is the fallback the hint points to
+            let wav = TempWav::new(&format!("requantize-{}", bits), &requantized);
+            let averaged = decode_averaged(&wav.0, &config).unwrap();
+            assert!(bit_error_rate(&averaged.bits, &expected) <= max_ber, "{} bits averaged", bits);
+        }
+    }
+
+    #[test]
+    fn one_decoder_matches_one_shot_decodes_from_any_thread() {
+        let config = WatermarkConfig::default();
+        let decoder = std::sync::Arc::new(Decoder::new(config.clone()));
+        let encoded: Vec<Vec<f32>> = ["a", "hi", "hello"]
+            .iter()
+            .map(|text| encode(text, &two_seconds(), &config))
+            .collect();
+        let threads: Vec<_> = encoded
+            .iter()
+            .cloned()
+            .map(|samples| {
+                let decoder = decoder.clone();
+                std::thread::spawn(move || decoder.decode(&samples, 1, RATE).unwrap())
+            })
+            .collect();
+        for (thread, samples) in threads.into_iter().zip(&encoded) {
+            let (shared, one_shot) = (thread.join().unwrap(), decode(samples, &config));
+            assert_eq!(shared.message, one_shot.message);
+            assert_eq!(shared.bits, one_shot.bits);
+            assert_eq!(shared.confidence, one_shot.confidence);
+        }
+        // And again on the same thread, with the plans already cached
+        assert_eq!(decoder.decode(&encoded[2], 1, RATE).unwrap().message, "hello");
+    }
+
+    #[test]
+    fn channel_map_puts_a_different_message_in_each_channel() {
+        let config = WatermarkConfig {
+            channels: vec![0, 1],
+            channel_messages: vec![Payload::Text("left".to_string()), Payload::Text("right".to_string())],
+            ..WatermarkConfig::default()
+        };
+        let left = test_signal(RATE as usize * 2);
+        let stereo: Vec<f32> = left.iter().zip(left.iter().rev()).flat_map(|(&l, &r)| [l, r]).collect();
+        // The map overrides the message passed in
+        let message = Payload::Text("unused".to_string());
+        let (encoded, _) = Encoder::new(config.clone()).encode(&stereo, 2, RATE, &message).unwrap();
+
+        let wav = TempWav::interleaved("channel-map", &encoded, 2);
+        let results = decode_per_channel(&wav.0, &WatermarkConfig::default()).unwrap();
+        let messages: Vec<(usize, String)> = results
+            .into_iter()
+            .map(|(channel, decoded)| (channel, decoded.unwrap().message))
+            .collect();
+        assert_eq!(messages, [(0, "left".to_string()), (1, "right".to_string())]);
+    }
+
+    #[test]
+    fn detection_finds_a_non_default_band() {
+        let default = WatermarkConfig::default();
+        let clean = frame_spectra(&two_seconds(), RATE, &default);
+        assert!(detect_watermark_band(&clean).is_none());
+        for (low_bin, high_bin) in [(20, 90), (40, 100)] {
+            let config = WatermarkConfig {
+                band: FreqBand {
+                    low_bin,
+                    high_bin,
+                    spacing: BinSpacing::Linear,
+                },
+                ..WatermarkConfig::default()
+            };
+            let encoded = encode("hi", &two_seconds(), &config);
+            assert!(decode_samples(&encoded, 1, RATE, &default).is_err());
+            let found = detect_watermark_band(&frame_spectra(&encoded, RATE, &default)).unwrap();
+            assert_eq!(found.low_bin, low_bin);
+            // The suggestion decodes, whatever it makes of the end
+            let suggested = WatermarkConfig {
+                band: found,
+                ..WatermarkConfig::default()
+            };
+            assert_eq!(decode(&encoded, &suggested).message, "hi");
+        }
+    }
+
+    #[test]
+    fn magnitudes_on_the_threshold_decide_one() {
+        let threshold = -1.956f32;
+        let scores = [threshold, threshold.next_down(), threshold, threshold.next_up()];
+        let bits: Vec<u8> = bits_from_magnitudes(scores.into_iter(), threshold).collect();
+        assert_eq!(bits, [1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn setting_cancel_mid_scan_stops_the_resync_search() {
+        let config = WatermarkConfig::default();
+        let wav = TempWav::new("cancel", &encode("hi", &test_signal(RATE as usize * 20), &config));
+        let started = std::time::Instant::now();
+        decode_resync(&wav.0, &config, &AtomicBool::new(false)).unwrap();
+        let full = started.elapsed();
+
+        let cancel = std::sync::Arc::new(AtomicBool::new(false));
+        let setter = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(full / 10);
+                cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+            })
+        };
+        let started = std::time::Instant::now();
+        let result = decode_resync(&wav.0, &config, &cancel);
+        let cancelled = started.elapsed();
+        setter.join().unwrap();
+        assert!(matches!(result, Err(DecodeError::Cancelled)));
+        assert!(cancelled < full / 2, "cancelled after {:?} of {:?}", cancelled, full);
+    }
+
+    #[test]
+    fn floor_mode_marks_silence_the_boost_cannot() {
+        let silence = vec![0.0; RATE as usize * 2];
+        let config = WatermarkConfig::default();
+        let encoded = encode("hello", &silence, &config);
+        assert!(decode_samples(&encoded, 1, RATE, &config).is_err());
+
+        let floor = WatermarkConfig {
+            embed_mode: EmbedMode::AdditiveFloor,
+            ..WatermarkConfig::default()
+        };
+        let encoded = encode("hello", &silence, &floor);
+        // The decoder needs no setting for it: the pilot calibrates the threshold
+        assert_eq!(decode(&encoded, &config).message, "hello");
+    }
+
+    #[test]
+    fn an_empty_message_decodes_empty_with_its_crc_ok() {
+        for framing in [Framing::LengthHeader, Framing::Sentinel, Framing::Varint] {
+            let config = WatermarkConfig {
+                framing,
+                ..WatermarkConfig::default()
+            };
+            let decoded = decode(&encode("", &two_seconds(), &config), &config);
+            assert_eq!((decoded.message.as_str(), decoded.raw_bytes.len()), ("", 0), "{:?}", framing);
+            let crc_ok = (framing != Framing::Sentinel).then_some(true);
+            assert_eq!(decoded.crc_ok, crc_ok, "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn vad_selects_the_same_frames_on_both_sides_at_every_threshold() {
+        const FRAME_LEN: usize = 256; // 32 ms at RATE
+        // test_signal sits near -17 dBFS; each run of 4 frames gets a level
+        // 5 dB off every threshold below, so the mark can't tip a frame over
+        let levels_db = [-17.0, -62.0, -27.0, -52.0, -37.0, -47.0, -72.0, -22.0];
+        let frames = 64;
+        let signal: Vec<f32> = test_signal(FRAME_LEN * frames)
+            .chunks(FRAME_LEN)
+            .enumerate()
+            .flat_map(|(frame, chunk)| {
+                let gain = 10f32.powf((levels_db[frame / 4 % levels_db.len()] + 17.0) / 20.0);
+                chunk.iter().map(move |sample| sample * gain)
+            })
+            .collect();
+        let message = Payload::Text("hi".to_string());
+        for threshold_db in [-67.0, -57.0, -42.0, -32.0, -25.0] {
+            let config = WatermarkConfig {
+                vad_threshold_db: Some(threshold_db),
+                max_bits_per_frame: Some(20),
+                ..WatermarkConfig::default()
+            };
+            let (encoded, report) = Encoder::new(config.clone()).encode(&signal, 1, RATE, &message).unwrap();
+            // What the encoder selects on the input and the decoder on the
+            // watermarked copy; every frame written to is among them
+            let selected = |samples: &[f32]| -> Vec<usize> {
+                crate::vad::active_frames(samples, FRAME_LEN, FRAME_LEN, threshold_db)
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(frame, &active)| active.then_some(frame))
+                    .collect()
+            };
+            assert_eq!(selected(&signal), selected(&encoded), "{} dBFS", threshold_db);
+            assert!(report.bins_used.iter().all(|(frame, _)| selected(&signal).contains(frame)));
+            assert_eq!(decode(&encoded, &config).message, "hi", "{} dBFS", threshold_db);
+        }
+    }
+
+    #[test]
+    fn one_doubtful_bit_gives_two_candidates_and_the_tag_picks_one() {
+        let keyed = WatermarkConfig {
+            key: Some(b"key".to_vec()),
+            ..WatermarkConfig::default()
+        };
+        for config in [WatermarkConfig::default(), keyed] {
+            let bits = expected_bits("hello", &config, None);
+            // Scores as the frames would give them: the pilot sets the
+            // contrast, everything after it sits further out (too sure to
+            // flip) except one payload bit, just on the wrong side
+            let doubtful = 77; // in "o": pilot, magic, length and CRC take 40 bits
+            let scores: Vec<f32> = (0..119)
+                .map(|idx| match (idx, bits.get(idx)) {
+                    (idx, Some(&bit)) if idx < PILOT_PATTERN.len() => if bit == 1 { 4.0 } else { -4.0 },
+                    (idx, Some(&bit)) if idx == doubtful => if bit == 1 { -0.5 } else { 0.5 },
+                    (_, Some(&bit)) => if bit == 1 { 6.0 } else { -6.0 },
+                    (_, None) => -6.0,
+                })
+                .collect();
+            let frames = vec![scores; 10];
+            let blind = assemble_watermark(bits_from_frames(&frames, &config).unwrap(), &config).unwrap();
+            assert_ne!(blind.message, "hello");
+
+            let candidates = candidates_from_frames(&frames, &config, 2).unwrap();
+            let messages: Vec<&str> = candidates.iter().map(|(message, _)| message.as_str()).collect();
+            if config.key.is_none() {
+                // The CRC covers only the header, so both readings of the bit pass
+                assert_eq!(messages, [blind.message.as_str(), "hello"]);
+                assert!(candidates[0].1 > candidates[1].1);
+            } else {
+                assert_eq!(messages, ["hello"]);
+            }
+        }
+    }
+
+    #[test]
+    fn candidates_come_from_samples_in_memory() {
+        let config = WatermarkConfig::default();
+        let encoded = encode("hello", &two_seconds(), &config);
+        let candidates = decode_candidates(&encoded, 1, RATE, &config, 2).unwrap();
+        assert_eq!(candidates[0], ("hello".to_string(), 1.0));
+    }
+
+    #[test]
+    fn embedding_at_an_offset_leaves_the_lead_and_decodes_from_it() {
+        let config = WatermarkConfig {
+            embed_offset: 1000,
+            ..WatermarkConfig::default()
+        };
+        let signal = two_seconds();
+        let encoded = encode("hello", &signal, &config);
+        assert_eq!(encoded[..1000], signal[..1000]);
+        assert_ne!(encoded[1000..1256], signal[1000..1256]);
+        assert_eq!(decode(&encoded, &config).message, "hello");
+
+        // Without the offset, the alignment search gets close to the grid:
+        // it steps in eighths of a frame, and 224 is the step before 1000 % 256
+        let wav = TempWav::new("embed-offset", &encoded);
+        let (decoded, alignment) =
+            decode_best_alignment(&wav.0, &WatermarkConfig::default(), &AtomicBool::new(false)).unwrap();
+        assert_eq!((decoded.message.as_str(), alignment.offset), ("hello", 224));
+    }
+
+    #[test]
+    fn manual_threshold_decodes_with_the_pilot_stripped() {
+        let config = WatermarkConfig::default();
+        let mut encoded = encode("hi", &two_seconds(), &config);
+        // Silence the pilot's bins in every frame, as a filter would
+        let plans = PlanCache::new().plans(256);
+        let mut spectrum = plans.forward.make_output_vec();
+        for frame in encoded.chunks_exact_mut(256) {
+            plans.forward.process(frame, &mut spectrum).unwrap();
+            let pilot = config.band.low_bin..config.band.low_bin + PILOT_PATTERN.len();
+            spectrum[pilot].fill(Complex::new(0.0, 0.0));
+            plans.inverse.process(&mut spectrum, frame).unwrap();
+            frame.iter_mut().for_each(|sample| *sample /= 256.0);
+        }
+        let calibrated = decode_samples(&encoded, 1, RATE, &config);
+        assert!(calibrated.map_or(true, |decoded| decoded.message != "hi"));
+
+        for threshold in [-1.0, 0.0] {
+            let manual = WatermarkConfig {
+                threshold: Some(threshold),
+                ..WatermarkConfig::default()
+            };
+            let decoded = decode(&encoded, &manual);
+            assert_eq!(decoded.message, "hi", "threshold {}", threshold);
+        }
+        // Above the boosted level every bit reads 0: garbage, not a message
+        let wrong = WatermarkConfig {
+            threshold: Some(3.0),
+            ..WatermarkConfig::default()
+        };
+        assert!(decode_samples(&encoded, 1, RATE, &wrong).map_or(true, |decoded| decoded.message != "hi"));
+    }
+
+    #[test]
+    fn band_magnitudes_read_only_the_band() {
+        // Bin k holds 3 + 4i scaled by k: magnitude 5k
+        let spectrum: Vec<Complex<f32>> = (0..12).map(|k| Complex::new(3.0, 4.0) * k as f32).collect();
+        let band = FreqBand {
+            low_bin: 4,
+            high_bin: 8,
+            spacing: BinSpacing::Linear,
+        };
+        let magnitudes: Vec<f32> = band_magnitudes(&spectrum, band).collect();
+        assert_eq!(magnitudes, [20.0, 25.0, 30.0, 35.0]);
+        // A magnitude on the threshold is a 1, like every other decision
+        let bits: Vec<u8> = bits_from_magnitudes(magnitudes.into_iter(), 25.0).collect();
+        assert_eq!(bits, [0, 1, 1, 1]);
+    }
+
+    // Tapered windows leak each changed bin into its neighbours. With no
+    // guard those neighbours are data bins too; two guard bins either side
+    // absorb the leakage, so fewer bits flip
+    #[test]
+    fn guard_bins_lower_the_bit_error_rate_under_window_leakage() {
+        let mut state = 0.0f32;
+        let tilted: Vec<f32> = test_signal(RATE as usize * 2)
+            .iter()
+            .map(|&sample| {
+                state = 0.9 * state + sample; // one-pole lowpass: speech-like tilt
+                state * 0.2
+            })
+            .collect();
+        let error_rate = |guard| {
+            let config = WatermarkConfig { window: Window::Hann, guard, ..WatermarkConfig::default() };
+            let message = Payload::Text("hi".to_string());
+            let (encoded, report) = Encoder::new(config.clone()).encode(&tilted, 1, RATE, &message).unwrap();
+            let decoded = decode_samples(&encoded, 1, RATE, &config).unwrap();
+            bit_error_rate(&decoded.bits, &report.bits)
+        };
+        let (unguarded, guarded) = (error_rate(0), error_rate(2));
+        assert!(unguarded > 0.0, "no leakage errors to remove");
+        assert!(guarded < unguarded, "guard 2 BER {} vs guard 0 BER {}", guarded, unguarded);
+    }
+
+    #[test]
+    fn robust_decode_survives_a_simulated_room() {
+        let config = WatermarkConfig::default();
+        let encoded = encode("hi", &test_signal(RATE as usize * 4), &config);
+        // Direct sound 37 samples late, two echoes, then room noise
+        let echoes = [(37, 0.8), (77, 0.3), (147, 0.15)];
+        let mut room = vec![0.0; encoded.len()];
+        for (delay, gain) in echoes {
+            for (out, sample) in room[delay..].iter_mut().zip(&encoded) {
+                *out += gain * sample;
+            }
+        }
+        let recorded = attack::add_noise(&room, 20.0, attack::NOISE_SEED);
+        let wav = TempWav::new("room", &recorded);
+        let (decoded, _) = decode_robust(&wav.0, &config, &AtomicBool::new(false)).unwrap();
+        assert_eq!(decoded.message, "hi");
+    }
+
+    #[test]
+    fn over_wide_band_is_clamped_the_same_on_both_sides() {
+        let band = FreqBand::from_name("60-400").unwrap(); // past the 129-bin spectrum
+        let config = WatermarkConfig {
+            band,
+            ..WatermarkConfig::default()
+        };
+        assert_eq!(decoder_band(&config, RATE), band.clamp_to(129));
+        let encoded = encode("hi", &two_seconds(), &config);
+        assert_eq!(decode(&encoded, &config).message, "hi");
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_spectra() {
+        let samples = two_seconds();
+        let spectra_on = |threads| {
+            let config = WatermarkConfig {
+                threads: Some(threads),
+                ..WatermarkConfig::default()
+            };
+            frame_spectra(&samples, RATE, &config)
+        };
+        let single = spectra_on(1);
+        assert_eq!(single.len(), samples.len().div_ceil(256)); // the tail is zero-padded
+        for threads in [2, 3, 8, 1000] {
+            assert_eq!(spectra_on(threads), single, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn bit_error_rate_counts_flips_and_missing_bits() {
+        let expected = expected_bits("hi", &WatermarkConfig::default(), None);
+        let mut decoded = expected.clone();
+        for idx in [3, 17, 40] {
+            decoded[idx] ^= 1;
+        }
+        let ber = bit_error_rate(&decoded, &expected);
+        assert!((ber - 3.0 / expected.len() as f32).abs() < 1e-6);
+
+        decoded.truncate(expected.len() - 4); // a short stream: 4 more errors
+        let ber = bit_error_rate(&decoded, &expected);
+        assert!((ber - 7.0 / expected.len() as f32).abs() < 1e-6);
+        assert_eq!(bit_error_rate(&expected, &expected), 0.0);
+    }
 }
@@ -1,21 +1,60 @@
-use hound::{WavReader, WavWriter};
-use realfft::RealFftPlanner;
-use std::path::Path;
+use hound::{SampleFormat, WavWriter};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::iter;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::allocate::allocate_bits;
+use crate::channels::{deinterleave, from_mid_side, interleave, peak_track, to_mid_side};
+use crate::compress::compress;
+use crate::config::{
+    frame_len_for, ChannelStrategy, EmbedMode, FreqBand, Framing, NonFiniteMode, RoundMode,
+    WatermarkConfig, DEFAULT_WINDOW_MS, MAX_FRAME_LEN, MIN_FRAME_LEN,
+};
+use crate::fft::{FftPlans, PlanCache};
+use crate::payload::Payload;
+use crate::polar::{magnitude, set_magnitude};
+use crate::resample::resample_interleaved;
+use crate::simd;
+use crate::tag::TAG_BYTES;
+use crate::vad::active_frames;
+use crate::watermark::{threshold_from_pilot, Watermark};
+use crate::wav::{self, WavError};
+use crate::window::{frame_starts, make_window, overlap_gain};
 
 // =============================================================================
 // CONSTANTS - Watermark configuration
 // =============================================================================
 
-// Pilot pattern: A known sequence at the start to help decoder find the threshold
-// Alternating 0s and 1s give us clear separation between high and low magnitudes
-pub const PILOT_PATTERN: [u8; 8] = [0, 1, 0, 1, 0, 1, 0, 1];
-
-
+// Magnitude factors applied to a bin carrying a 1 or a 0 bit.
+// The suppress factor is small but non-zero so a "0" bin keeps its original
+// phase instead of collapsing to the origin (where the phase is undefined).
+const BOOST_FACTOR: f32 = 2.0;
+const SUPPRESS_FACTOR: f32 = 0.01;
+// EmbedMode::AdditiveFloor's floor without --floor-db: well above 16-bit
+// quantization noise in a bin, quiet next to programme material
+const DEFAULT_FLOOR_DBFS: f32 = -60.0;
+// --no-clip halves a clipping frame's weight at most this many times
+// (1/16 strength) before leaving the frame unmarked
+const HEADROOM_STEPS: i32 = 4;
 
-// Sample normalization divisor for i16 -> f32 conversion
-const SAMPLE_DIVISOR: f32 = 32768.0;
+// Peak level --normalize brings the input to, in dBFS. Short of full scale:
+// boosted bins add to the peaks, and the clip check runs after embedding.
+const NORMALIZE_PEAK_DB: f32 = -1.0;
 
+// --snr-retry: each attempt embeds at this fraction of the previous strength,
+// and gives up below MIN_STRENGTH (the bins barely move any more)
+const SNR_RETRY_STEP: f32 = 0.7;
+const MIN_STRENGTH: f32 = 0.1;
 
+// Longest run of equal bits a nonce may produce (the longest in a typical
+// text message), and how many nonces to draw looking for one
+const MAX_NONCE_RUN: usize = 6;
+const NONCE_ATTEMPTS: usize = 64;
 
 // Input and output file paths
 const INPUT_PATH: &str = concat!(
@@ -28,111 +67,1127 @@ const OUTPUT_PATH: &str = concat!(
 );
 
 // =============================================================================
-// ORCHESTRATOR: Main entry point that coordinates the encoding pipeline
+// ERRORS - Reasons the encoder refuses to write an output file
 // =============================================================================
 
-pub fn encode_sample(message: &str) {
-    // Step 1: Load audio and get normalized samples + metadata
-    let (normalized, spec) = load_and_normalize_audio(Path::new(INPUT_PATH));
+#[derive(Debug)]
+pub enum EncodeError {
+    // The input couldn't be read as audio
+    BadWav(WavError),
+    // The input holds fewer samples than one full frame, so no bit can be embedded
+    InputTooShort {
+        samples: usize,
+        required: usize,
+        bits: usize,
+    },
+    // The message is over the configured --max-message-bytes policy limit
+    MessageOverLimit {
+        bytes: usize,
+        limit: usize,
+    },
+    // The bit sequence needs more bins than one frame provides
+    MessageTooLong {
+        bits: usize,
+        capacity: usize,
+    },
+    // The message is longer than the 16-bit length header can state
+    LengthOverflow {
+        bytes: usize,
+    },
+    // A carrier channel the input file doesn't have
+    NoSuchChannel {
+        channel: usize,
+        channels: usize,
+    },
+    // Mid/side embedding asked for on a file that isn't stereo
+    NotStereo {
+        channels: usize,
+    },
+    // --output-format channels=N that can't be made from the input's
+    // channels (only a mono input widens)
+    OutputChannels {
+        input: u16,
+        output: u16,
+    },
+    // --window-duration-ms gives a frame length outside MIN_FRAME_LEN..=MAX_FRAME_LEN
+    BadWindow {
+        ms: f32,
+        frame_len: usize,
+    },
+    // The output file already exists and --force wasn't given
+    OutputExists {
+        path: PathBuf,
+    },
+    // Creating or writing the output failed (a full disk, a closed pipe)
+    Io(io::Error),
+    // A float input with NaN or infinite samples, under --non-finite error
+    NonFiniteSamples {
+        count: usize,
+    },
+    // The watermark is more audible than --min-snr-db allows (at `strength`,
+    // the weakest one tried)
+    SnrTooLow {
+        snr_db: f32,
+        min_db: f32,
+        strength: f32,
+    },
+}
 
-    // Step 2: Build the bit sequence (pilot + length + message)
-    let bits = build_bit_sequence(message);
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::BadWav(err) => write!(f, "{}", err),
+            EncodeError::InputTooShort {
+                samples,
+                required,
+                bits,
+            } => write!(
+                f,
+                "input is too short for the requested message: {} samples read, \
+                 at least {} needed to embed {} bits",
+                samples, required, bits
+            ),
+            EncodeError::MessageOverLimit { bytes, limit } => write!(
+                f,
+                "message is {} bytes, over the configured limit of {} bytes",
+                bytes, limit
+            ),
+            EncodeError::MessageTooLong { bits, capacity } => write!(
+                f,
+                "message needs {} bits but a frame only holds {}; shorten the message",
+                bits, capacity
+            ),
+            EncodeError::LengthOverflow { bytes } => write!(
+                f,
+                "message is {} bytes but the length header holds at most {}; \
+                 --framing varint has no such limit",
+                bytes,
+                u16::MAX
+            ),
+            EncodeError::NoSuchChannel { channel, channels } => write!(
+                f,
+                "channel {} does not exist; the input has {} channel(s)",
+                channel, channels
+            ),
+            EncodeError::NotStereo { channels } => write!(
+                f,
+                "mid/side embedding needs a stereo file; the input has {} channel(s)",
+                channels
+            ),
+            EncodeError::OutputChannels { input, output } => write!(
+                f,
+                "can't write {} channel(s) from a {}-channel input; only mono widens \
+                 (each output channel gets the watermarked track)",
+                output, input
+            ),
+            EncodeError::BadWindow { ms, frame_len } => write!(
+                f,
+                "a {} ms window is {} samples at this rate; it must be between {} and {}",
+                ms, frame_len, MIN_FRAME_LEN, MAX_FRAME_LEN
+            ),
+            EncodeError::OutputExists { path } => write!(
+                f,
+                "{} already exists; pass --force to overwrite it",
+                path.display()
+            ),
+            EncodeError::Io(err) => write!(f, "cannot write the output: {}", err),
+            EncodeError::NonFiniteSamples { count } => write!(
+                f,
+                "input has {} NaN or infinite sample(s); --non-finite zero replaces them",
+                count
+            ),
+            EncodeError::SnrTooLow {
+                snr_db,
+                min_db,
+                strength,
+            } => write!(
+                f,
+                "watermark SNR is {:.1} dB at strength {:.2}, below the required {:.1} dB{}",
+                snr_db,
+                strength,
+                min_db,
+                if *strength < 1.0 { "" } else { "; --snr-retry weakens it until it fits" }
+            ),
+        }
+    }
+}
 
-    // Step 3: Embed bits into audio via FFT processing
-    let encoded = embed_watermark_fft(&normalized, &bits);
+// =============================================================================
+// TIMINGS - Wall-clock time spent in each pipeline step
+// =============================================================================
 
-    // Step 4: Convert back to i16 samples
-    let quantized = quantize_to_i16(encoded);
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncodeTimings {
+    pub load: Duration,       // load, trim and pad
+    pub build_bits: Duration, // bit sequence + policy / capacity checks
+    pub embed: Duration,      // FFT embedding and re-padding
+    pub quantize: Duration,   // f32 -> integer PCM
+    pub write: Duration,      // WAV output
+}
 
-    // Step 5: Write the watermarked audio to disk
-    write_wav_file(Path::new(OUTPUT_PATH), &quantized, spec);
+impl EncodeTimings {
+    pub fn total(&self) -> Duration {
+        self.load + self.build_bits + self.embed + self.quantize + self.write
+    }
 }
 
 // =============================================================================
-// STEP 1: Load and normalize audio
+// BIN STATS - How much each FFT bin was changed, across all frames
+// =============================================================================
+
+// Magnitude change per bin, in sample units (spectrum magnitude / frame_len),
+// over the frames that wrote a bit to the bin
+#[derive(Clone, Debug, Default)]
+pub struct BinStats {
+    sums: Vec<f32>,
+    maxes: Vec<f32>,
+    counts: Vec<usize>,
+}
+
+impl BinStats {
+    fn new(bins: usize) -> BinStats {
+        BinStats {
+            sums: vec![0.0; bins],
+            maxes: vec![0.0; bins],
+            counts: vec![0; bins],
+        }
+    }
+
+    fn record(&mut self, bin: usize, delta: f32) {
+        self.sums[bin] += delta;
+        self.maxes[bin] = self.maxes[bin].max(delta);
+        self.counts[bin] += 1;
+    }
+
+    pub fn bins(&self) -> usize {
+        self.sums.len()
+    }
+
+    pub fn mean(&self, bin: usize) -> f32 {
+        if self.counts[bin] == 0 {
+            return 0.0;
+        }
+        self.sums[bin] / self.counts[bin] as f32
+    }
+
+    pub fn max(&self, bin: usize) -> f32 {
+        self.maxes[bin]
+    }
+}
+
+// One track through a scheme's embed
+pub struct Embedded {
+    pub samples: Vec<f32>,
+    pub stats: Option<BinStats>,        // amplitude scheme only
+    pub bins_used: Vec<(usize, usize)>, // every (frame, bin) a bit was written to
+}
+
+// What encode_sample did besides writing the file
+#[derive(Clone, Debug, Default)]
+pub struct EncodeReport {
+    pub timings: EncodeTimings,
+    pub bin_stats: Option<BinStats>, // amplitude scheme only
+    pub frame_len: usize,            // samples per frame the watermark was embedded with
+    pub latency: usize,              // latency_samples at that frame length
+    pub dc_offsets: Vec<f32>,        // per-channel mean of the input, before any removal
+    pub normalize_gain: Option<f32>, // linear gain --normalize applied (None: not applied)
+    pub snr_db: f32,                 // input vs watermarked, worst carrier channel
+    pub strength: f32,               // boost / suppress strength actually embedded
+    pub bits: Vec<u8>,               // the exact sequence embedded (pilot + magic + framing + payload), first carrier
+    pub bins_used: Vec<(usize, usize)>, // (frame, bin) pairs the embed wrote a bit to, first carrier
+}
+
+// =============================================================================
+// ENCODER - Configuration plus the FFT plans every file of it reuses
+// =============================================================================
+
+// Encodes any number of files or buffers with one configuration. The plan
+// cache is shared by every call, so a second file at the same frame length
+// gets its plans for free. Scratch buffers are still per call, which is what
+// lets `encode` take &self: an Encoder is Send + Sync and can sit behind an
+// Arc in a server.
+pub struct Encoder {
+    config: WatermarkConfig,
+    plans: PlanCache,
+}
+
+// Checked at compile time: an Encoder must stay shareable across threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Encoder>();
+};
+
+impl Encoder {
+    pub fn new(config: WatermarkConfig) -> Encoder {
+        Encoder {
+            config,
+            plans: PlanCache::new(),
+        }
+    }
+}
+
+// =============================================================================
+// ORCHESTRATOR: Main entry point that coordinates the encoding pipeline
 // =============================================================================
 
-fn load_and_normalize_audio(input_path: &Path) -> (Vec<f32>, hound::WavSpec) {
-    println!("Loading clean audio from {}", input_path.display());
+// One-off encode of a file; Encoder::encode_file for many
+pub fn encode_sample(
+    message: &Payload,
+    input_path: &Path,
+    output_path: &Path,
+    config: &WatermarkConfig,
+) -> Result<EncodeReport, EncodeError> {
+    Encoder::new(config.clone()).encode_file(message, input_path, output_path)
+}
+
+// One-off encode of samples already in memory (interleaved, in [-1, 1]): the
+// counterpart of decoder::decode_samples, with no file read or write
+pub fn encode_samples(
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    message: &Payload,
+    config: &WatermarkConfig,
+) -> Result<(Vec<f32>, EncodeReport), EncodeError> {
+    Encoder::new(config.clone()).encode(samples, channels, sample_rate, message)
+}
 
-    let mut reader = WavReader::open(input_path).expect("failed to open wav file");
+impl Encoder {
+    pub fn encode_file(
+        &self,
+        message: &Payload,
+        input_path: &Path,
+        output_path: &Path,
+    ) -> Result<EncodeReport, EncodeError> {
+        let config = &self.config;
+        // Refuse to clobber an existing file (a mistyped path could be the source
+        // audio) before any work is done; write_wav_file refuses again when it
+        // creates the file, in case one appeared meanwhile
+        if !config.overwrite && output_path != Path::new("-") && output_path.exists() {
+            return Err(EncodeError::OutputExists {
+                path: output_path.to_path_buf(),
+            });
+        }
+
+        // Step 1: Load audio and get normalized samples + metadata
+        let started = Instant::now();
+        let (normalized, spec) = load_and_normalize_audio(input_path, config)?;
+        let output_channels = config.output_format.channels.unwrap_or(spec.channels);
+        if output_channels != spec.channels && spec.channels != 1 {
+            return Err(EncodeError::OutputChannels {
+                input: spec.channels,
+                output: output_channels,
+            });
+        }
+        let load = started.elapsed();
 
+        // Steps 1a-3 run in memory
+        let (encoded, mut report) = self.encode(&normalized, spec.channels, spec.sample_rate, message)?;
+        report.timings.load += load;
 
-    // Read and normalize samples in a single pass: i16 -> f32 in [-1.0, 1.0]
-    let mut normalized: Vec<f32> = Vec::new();
+        // Step 4: Convert back to integer samples at the output depth (float
+        // output keeps them as they are, beyond full scale included)
+        let started = Instant::now();
+        let float = spec.sample_format == SampleFormat::Float;
+        let clipped = encoded.iter().filter(|sample| sample.abs() > 1.0).count();
+        if clipped > 0 && !float {
+            diag!(
+                "Warning: {} samples clipped after embedding; --attenuate-input leaves headroom",
+                clipped
+            );
+        }
+        let (encoded, spec) = if output_channels == spec.channels {
+            (encoded, spec)
+        } else {
+            let spec = hound::WavSpec {
+                channels: output_channels,
+                ..spec
+            };
+            (duplicate_mono(encoded, output_channels), spec)
+        };
+        let quantized = quantize(encoded, spec.bits_per_sample, spec.sample_format, config.round_mode);
+        report.timings.quantize = started.elapsed();
 
-    for sample_result in reader.samples::<i16>() {
-       
-        let sample = sample_result.expect("failed to open sound file");
-        let normalized_sample = (sample as f32) / SAMPLE_DIVISOR;
+        // Step 5: Write the watermarked audio to disk
+        let started = Instant::now();
+        write_wav_file(output_path, &quantized, spec, config.overwrite)?;
+        report.timings.write = started.elapsed();
 
-        normalized.push(normalized_sample);
+        Ok(report)
+    }
 
+    // Everything between reading and writing the file: interleaved samples in
+    // [-1, 1] go in, watermarked samples (same layout, not yet quantized) come
+    // out. Nothing here touches the filesystem. With --min-snr-db the result is
+    // checked for transparency, and with --snr-retry re-embedded more gently
+    // until it passes or the strength reaches MIN_STRENGTH.
+    pub fn encode(
+        &self,
+        samples: &[f32],
+        channels: u16,
+        sample_rate: u32,
+        message: &Payload,
+    ) -> Result<(Vec<f32>, EncodeReport), EncodeError> {
+        let mut config = self.config.clone();
+        loop {
+            let (encoded, report) = self.encode_at_strength(samples, channels, sample_rate, message, &config)?;
+            let Some(min_db) = config.min_snr_db else {
+                return Ok((encoded, report));
+            };
+            diag!(
+                "Watermark SNR {:.1} dB at strength {:.2} (minimum {:.1} dB)",
+                report.snr_db, report.strength, min_db
+            );
+            if report.snr_db >= min_db {
+                return Ok((encoded, report));
+            }
+            let weaker = report.strength * SNR_RETRY_STEP;
+            if !config.snr_retry || weaker < MIN_STRENGTH {
+                return Err(EncodeError::SnrTooLow {
+                    snr_db: report.snr_db,
+                    min_db,
+                    strength: report.strength,
+                });
+            }
+            diag!("Too audible; re-embedding at strength {:.2}", weaker);
+            config.strength = Some(weaker);
+        }
     }
 
-    let spec = reader.spec();
-    
-    println!(
+    // One pass of encode at config.strength (the retry loop's copy of the config)
+    fn encode_at_strength(
+        &self,
+        samples: &[f32],
+        channels: u16,
+        sample_rate: u32,
+        message: &Payload,
+        config: &WatermarkConfig,
+    ) -> Result<(Vec<f32>, EncodeReport), EncodeError> {
+        let mut timings = EncodeTimings::default();
+        let started = Instant::now();
+        let frame_len = encoder_frame_len(config, sample_rate)?;
+        let plans = self.plans.plans(frame_len);
+
+        // Step 1a: One track per channel; only the carrier channels get the watermark
+        let channel_count = usize::from(channels.max(1));
+        let mid_side = matches!(config.channel_strategy, ChannelStrategy::MidSide(_));
+        if mid_side && channel_count != 2 {
+            return Err(EncodeError::NotStereo {
+                channels: channel_count,
+            });
+        }
+        let carriers = config.carrier_channels();
+        if let Some(&channel) = carriers.iter().find(|&&channel| channel >= channel_count) {
+            return Err(EncodeError::NoSuchChannel {
+                channel,
+                channels: channel_count,
+            });
+        }
+        let mut tracks = deinterleave(samples, channel_count);
+        if mid_side {
+            to_mid_side(&mut tracks);
+            diag!("Converted stereo to mid/side; watermarking {:?}", config.channel_strategy);
+        } else if channel_count > 1 {
+            diag!(
+                "Split {} channels; watermarking channel(s) {:?}",
+                channel_count, carriers
+            );
+        }
+
+        // Step 1a': Measure each channel's DC offset, and optionally subtract it
+        let dc_offsets: Vec<f32> = tracks.iter().map(|track| dc_offset(track)).collect();
+        if config.remove_dc {
+            for (track, &offset) in tracks.iter_mut().zip(&dc_offsets) {
+                remove_dc(track, offset);
+            }
+            diag!("Removed DC offset from {} channel(s)", channel_count);
+        }
+
+        // Step 1b: Optionally drop leading/trailing silence, which can't carry bits.
+        // The trim is decided on the loudest channel and applied to all of them.
+        let mut leads = vec![Vec::new(); channel_count];
+        let mut tails = vec![Vec::new(); channel_count];
+        if let Some(threshold_db) = config.trim_silence_db {
+            let (_, lead_len, tail_len) = trim_silence(&peak_track(&tracks), threshold_db);
+            // When re-padding, only trim whole frames off the front so the
+            // output keeps the decoder's frame grid starting at sample 0
+            let lead_len = if config.repad_silence {
+                lead_len / frame_len * frame_len
+            } else {
+                lead_len
+            };
+            let end = tracks[0].len() - tail_len;
+            diag!(
+                "Trimmed {} leading and {} trailing silent samples",
+                lead_len, tail_len
+            );
+            for (track, (lead, tail)) in tracks.iter_mut().zip(leads.iter_mut().zip(&mut tails)) {
+                if config.repad_silence {
+                    *lead = track[..lead_len].to_vec();
+                    *tail = track[end..].to_vec();
+                }
+                *track = track[lead_len..end].to_vec();
+            }
+        }
+
+        // Step 1c: Optionally pad up to a minimum duration
+        if let Some(seconds) = config.pad_to_seconds {
+            let target = (seconds * sample_rate as f32).round() as usize;
+            let original_len = tracks[0].len();
+            for track in &mut tracks {
+                *track = pad_to_length(std::mem::take(track), target, config.pad_loop);
+            }
+            if tracks[0].len() > original_len {
+                diag!(
+                    "Padded input from {} to {} samples ({})",
+                    original_len,
+                    target,
+                    if config.pad_loop { "looped" } else { "zeros" }
+                );
+            }
+        }
+
+        // Step 1d: Optionally peak-normalize (after DC removal, so the offset
+        // doesn't eat the headroom), then attenuate to leave room for the boost.
+        // One gain for every channel keeps their balance.
+        let normalize_gain = if config.normalize_input {
+            normalize_gain(&tracks)
+        } else {
+            None
+        };
+        if let Some(gain) = normalize_gain {
+            for track in &mut tracks {
+                attenuate(track, gain);
+            }
+            diag!(
+                "Peak-normalized input to {:.1} dBFS ({:+.1} dB, gain {:.4})",
+                NORMALIZE_PEAK_DB,
+                20.0 * gain.log10(),
+                gain
+            );
+        }
+        if let Some(db) = config.attenuate_db {
+            let gain = attenuation_gain(db);
+            for track in &mut tracks {
+                attenuate(track, gain);
+            }
+            diag!("Attenuated input by {:.1} dB (gain {:.4})", db.abs(), gain);
+        }
+
+        timings.load = started.elapsed();
+
+        // Policy limit comes first: it applies regardless of available capacity
+        let started = Instant::now();
+        let messages = carrier_messages(message, config);
+        if let Some(limit) = config.max_message_bytes {
+            for message in &messages {
+                let bytes = message.to_bytes(config.typed_payload).len();
+                if bytes > limit {
+                    return Err(EncodeError::MessageOverLimit {
+                        bytes,
+                        limit,
+                    });
+                }
+            }
+        }
+
+        // Step 2: Build the bit sequence (pilot + length + message), one per
+        // carrier under --channel-map. The longest has to fit.
+        let mut channel_bits = if config.channel_messages.is_empty() {
+            vec![build_bit_sequence(message, config)?; carriers.len()]
+        } else {
+            messages
+                .iter()
+                .map(|message| build_bit_sequence(message, config))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let bit_count = channel_bits.iter().map(Vec::len).max().unwrap_or(0);
+
+        // Every frame repeats the whole sequence, so it has to fit in one frame
+        let band = encoder_band(config, frame_len);
+        if band != config.band {
+            diag!(
+                "Warning: band {}-{} runs past the spectrum; clamped to bins {}-{}",
+                config.band.low_bin, config.band.high_bin, band.low_bin, band.high_bin
+            );
+        }
+        let capacity = band.capacity(config.guard);
+        if bit_count > capacity {
+            return Err(EncodeError::MessageTooLong {
+                bits: bit_count,
+                capacity,
+            });
+        }
+
+        // Every frame carries the full bit sequence (under --bits-per-frame,
+        // every run of `groups` frames does), so we need at least one whole
+        // copy after --input-offset-samples
+        let track_len = tracks[0].len();
+        let offset = config.embed_offset;
+        let hop = encoder_hop(config, frame_len);
+        let required = offset.saturating_add(frame_len + (frame_groups(capacity, config) - 1) * hop);
+        if track_len < required {
+            return Err(EncodeError::InputTooShort {
+                samples: track_len,
+                required,
+                bits: bit_count,
+            });
+        }
+
+        timings.build_bits = started.elapsed();
+
+        // Step 3: Embed bits into each carrier channel via FFT processing
+        let started = Instant::now();
+        // --limit-frames: embed the first N frames only and pass the rest through.
+        // The head ends at N hops, so with overlap the last frame is cut short.
+        // Frames start counting at --input-offset-samples; the samples before
+        // it pass through untouched.
+        let body_len = track_len - offset;
+        let head_len = config
+            .limit_frames
+            .map_or(body_len, |limit| limit.saturating_mul(hop).min(body_len));
+        let frames = frame_starts(head_len, hop).count();
+        if head_len < body_len {
+            diag!(
+                "Limiting the embed to the first {} frames ({} of {} samples)",
+                frames, head_len, body_len
+            );
+        }
+        if offset > 0 {
+            diag!("Embedding from sample {} (--input-offset-samples)", offset);
+        }
+        if config.embed_mode == EmbedMode::None {
+            diag!("Embed mode none: running the pipeline without writing any bits");
+        }
+        let span = copy_span(config, sample_rate, frame_len);
+        let mut bin_stats = None;
+        let mut bins_used = None;
+        let mut snr = f32::INFINITY;
+        for (&channel, bits) in carriers.iter().zip(&channel_bits) {
+            let track = &mut tracks[channel];
+            let (lead, body) = track.split_at(offset);
+            let (head, rest) = body.split_at(head_len);
+            // No bits still frames and transforms every carrier as usual
+            let bits: &[u8] = if config.embed_mode == EmbedMode::None { &[] } else { bits };
+            let embedded = embed_copies(head, bits, &plans, band, config, span);
+            if bins_used.is_none() {
+                bin_stats = embedded.stats; // first carrier's stats
+                bins_used = Some(embedded.bins_used);
+            }
+            let mut encoded = embedded.samples;
+            snr = snr.min(snr_db(head, &encoded));
+            encoded.extend_from_slice(rest);
+            encoded.splice(0..0, lead.iter().copied());
+            *track = encoded;
+        }
+
+        // --restore-level takes the normalization back out; otherwise the
+        // re-padded silence gets it too, so the whole file is at one level
+        if let Some(gain) = normalize_gain {
+            let (restore, padding) = if config.restore_level { (1.0 / gain, 1.0) } else { (1.0, gain) };
+            for track in &mut tracks {
+                attenuate(track, restore);
+            }
+            for pad in leads.iter_mut().chain(&mut tails) {
+                attenuate(pad, padding);
+            }
+            if config.restore_level {
+                diag!("Restored the input level (gain {:.4})", restore);
+            }
+        }
+
+        // Re-pad: put the original silence back around the watermarked audio
+        for (track, (lead, tail)) in tracks.iter_mut().zip(leads.into_iter().zip(tails)) {
+            if !lead.is_empty() || !tail.is_empty() {
+                *track = [lead, std::mem::take(track), tail].concat();
+            }
+        }
+        if mid_side {
+            from_mid_side(&mut tracks);
+        }
+        let encoded = interleave(&tracks);
+
+        timings.embed = started.elapsed();
+
+        Ok((
+            encoded,
+            EncodeReport {
+                timings,
+                bin_stats,
+                frame_len,
+                latency: latency_samples(config, frame_len),
+                dc_offsets,
+                normalize_gain,
+                snr_db: snr,
+                strength: config.strength.unwrap_or(1.0),
+                bits: channel_bits.swap_remove(0),
+                bins_used: bins_used.unwrap_or_default(),
+            },
+        ))
+    }
+}
+
+// The message each carrier gets, in carrier order: --channel-map's own, or
+// `message` for every one of them
+fn carrier_messages<'a>(message: &'a Payload, config: &'a WatermarkConfig) -> Vec<&'a Payload> {
+    if config.channel_messages.is_empty() {
+        vec![message; config.carrier_channels().len()]
+    } else {
+        config.channel_messages.iter().collect()
+    }
+}
+
+// Strength of `frame` out of `frames` under a `ramp`-frame fade in and out:
+// 1 / (ramp + 1) at either end, rising linearly to 1 inside
+fn ramp_weight(frame: usize, frames: usize, ramp: usize) -> f32 {
+    let from_edge = frame.min(frames.saturating_sub(frame + 1)); // frames to the nearer end
+    if from_edge >= ramp {
+        return 1.0;
+    }
+    (from_edge + 1) as f32 / (ramp + 1) as f32
+}
+
+// Algorithmic latency of embedding with `frame_len`-sample frames, in
+// samples at the embedding rate: how far past an input sample the input has
+// to run before that sample's output is final, were the embed run on a
+// stream. A frame is transformed once its last sample is in, and a sample
+// is final once the last frame covering it has been added. That frame starts
+// at or before the sample, so the worst case (a sample opening a frame) is
+// the rest of the frame, whatever the overlap. The one look-ahead is
+// --ramp-frames: fading the last frames out means knowing where the end is,
+// that many hops early; --vad-threshold-db measures only the frame itself,
+// so it adds none. Steps that scan the whole input first (normalize,
+// silence trim, DC removal, padding) can't stream at all and aren't counted.
+pub fn latency_samples(config: &WatermarkConfig, frame_len: usize) -> usize {
+    let ramp_out = config.ramp_frames * encoder_hop(config, frame_len);
+    frame_len.saturating_sub(1) + ramp_out
+}
+
+// Samples per frame: 32 ms at the input rate unless --window-duration-ms
+// sets it, derived as the decoder derives it
+fn encoder_frame_len(config: &WatermarkConfig, sample_rate: u32) -> Result<usize, EncodeError> {
+    match config.window_frame_len(sample_rate) {
+        None => Ok(frame_len_for(sample_rate, DEFAULT_WINDOW_MS)),
+        Some(Ok(frame_len)) => Ok(frame_len),
+        Some(Err(frame_len)) => Err(EncodeError::BadWindow {
+            ms: config.window_ms.unwrap_or_default(),
+            frame_len,
+        }),
+    }
+}
+
+// --repeat-every: samples between the starts of successive copies, a whole
+// number of frames (at least one). None when each copy would be the whole
+// track, or when the scheme already repeats the message in every frame.
+fn copy_span(config: &WatermarkConfig, sample_rate: u32, frame_len: usize) -> Option<usize> {
+    let seconds = config.repeat_every?;
+    if config.scheme.algorithm().repeats_every_frame() {
+        diag!(
+            "--repeat-every: the {} scheme already repeats the message every frame",
+            config.scheme.name()
+        );
+        return None;
+    }
+    let frames = ((seconds * sample_rate as f32 / frame_len as f32).round() as usize).max(1);
+    diag!("Embedding a full copy every {} frames ({} samples)", frames, frames * frame_len);
+    Some(frames * frame_len)
+}
+
+// One track through the scheme's embed: whole, or as back-to-back spans of
+// `span` samples, each embedded on its own so it carries a complete copy
+// (pilot and all) that decodes without the ones before it. Frames in
+// bins_used count from the start of `head`.
+fn embed_copies(
+    head: &[f32],
+    bits: &[u8],
+    plans: &FftPlans,
+    band: FreqBand,
+    config: &WatermarkConfig,
+    span: Option<usize>,
+) -> Embedded {
+    let algorithm = config.scheme.algorithm();
+    let Some(span) = span else {
+        return algorithm.embed(head, bits, plans, band, config);
+    };
+    let frames_per_copy = span / algorithm.hop(plans.fft_len(), config);
+    let mut encoded = Embedded {
+        samples: Vec::with_capacity(head.len()),
+        stats: None,
+        bins_used: Vec::new(),
+    };
+    for (copy, chunk) in head.chunks(span).enumerate() {
+        let embedded = algorithm.embed(chunk, bits, plans, band, config);
+        encoded.stats = encoded.stats.or(embedded.stats);
+        encoded.samples.extend(embedded.samples);
+        let first_frame = copy * frames_per_copy;
+        encoded
+            .bins_used
+            .extend(embedded.bins_used.into_iter().map(|(frame, bin)| (first_frame + frame, bin)));
+    }
+    encoded
+}
+
+// The configured band, clamped to the bins a frame_len spectrum has
+fn encoder_band(config: &WatermarkConfig, frame_len: usize) -> FreqBand {
+    config.band.clamp_to(config.scheme.carrier_bins(frame_len / 2 + 1))
+}
+
+// Samples between frame starts, as the scheme lays its frames out
+fn encoder_hop(config: &WatermarkConfig, frame_len: usize) -> usize {
+    config.scheme.algorithm().hop(frame_len, config)
+}
+
+// Signal-to-noise ratio of a watermarked track, treating everything the
+// embed changed as noise. Infinite when nothing changed.
+fn snr_db(original: &[f32], watermarked: &[f32]) -> f32 {
+    let signal: f64 = original.iter().map(|&x| f64::from(x) * f64::from(x)).sum();
+    let noise: f64 = original
+        .iter()
+        .zip(watermarked)
+        .map(|(&x, &y)| f64::from(y - x) * f64::from(y - x))
+        .sum();
+    if noise == 0.0 {
+        return f32::INFINITY;
+    }
+    (10.0 * (signal / noise).log10()) as f32
+}
+
+// Default paths used by the CLI when no --input / --output is given
+pub fn default_input_path() -> PathBuf {
+    PathBuf::from(INPUT_PATH)
+}
+
+pub fn default_output_path() -> PathBuf {
+    PathBuf::from(OUTPUT_PATH)
+}
+
+
+// =============================================================================
+// STEP 1: Load and normalize audio
+// =============================================================================
+
+// Returns the samples in [-1.0, 1.0] and the spec the output is written
+// with: the input's channels and rate, as integer PCM at the
+// --output-format depth (16-bit unless asked otherwise) or as 32-bit float.
+pub fn load_and_normalize_audio(
+    input_path: &Path,
+    config: &WatermarkConfig,
+) -> Result<(Vec<f32>, hound::WavSpec), EncodeError> {
+    diag!("Loading clean audio from {}", input_path.display());
+
+    // Read and normalize samples in a single pass: PCM -> f32 in [-1.0, 1.0]
+    let (mut normalized, spec) =
+        wav::read_file(input_path, config.allow_truncated).map_err(EncodeError::BadWav)?;
+
+    diag!(
         "Read and normalized {} samples at {} Hz",
         normalized.len(),
         spec.sample_rate
     );
 
-    (normalized, spec)
+    // Float files can carry NaN / Inf, which would poison every FFT frame
+    // they land in
+    let non_finite = normalized.iter().filter(|sample| !sample.is_finite()).count();
+    if non_finite > 0 {
+        if config.non_finite == NonFiniteMode::Error {
+            return Err(EncodeError::NonFiniteSamples { count: non_finite });
+        }
+        zero_non_finite(&mut normalized);
+        diag!("Replaced {} NaN or infinite samples with 0", non_finite);
+    }
+
+    let mut output_spec = if config.output_format.float {
+        hound::WavSpec {
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+            ..spec
+        }
+    } else {
+        hound::WavSpec {
+            bits_per_sample: config.output_format.bits.unwrap_or(16),
+            sample_format: SampleFormat::Int,
+            ..spec
+        }
+    };
+
+    // --resample: embed (and write) at another rate
+    if let Some(rate) = config.resample_to.filter(|&rate| rate != spec.sample_rate) {
+        normalized = resample_interleaved(&normalized, spec.channels, spec.sample_rate, rate);
+        output_spec.sample_rate = rate;
+        diag!("Resampled {} Hz -> {} Hz ({} samples)", spec.sample_rate, rate, normalized.len());
+    }
+    Ok((normalized, output_spec))
+}
+
+fn zero_non_finite(samples: &mut [f32]) {
+    for sample in samples.iter_mut().filter(|sample| !sample.is_finite()) {
+        *sample = 0.0;
+    }
 }
 
 // =============================================================================
-// STEP 2: Build bit sequence (pilot + length + message)
+// STEP 1a': DC offset
 // =============================================================================
 
+// Mean sample value. A DC offset only lands in bin 0, which no band uses,
+// but it shifts every sample toward one rail and eats into the headroom.
+fn dc_offset(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|&sample| f64::from(sample)).sum::<f64>() / samples.len() as f64) as f32
+}
+
+// Plain mean subtraction: enough for a constant offset, no filter state
+fn remove_dc(samples: &mut [f32], offset: f32) {
+    for sample in samples {
+        *sample -= offset;
+    }
+}
+
+// =============================================================================
+// STEP 1b: Trim silence
+// =============================================================================
 
-fn build_bit_sequence(message: &str) -> Vec<u8> {
-    let message_bytes = message.as_bytes();
-    let length_header = message_bytes.len() as u16;
+// Strip samples quieter than `threshold_db` (dBFS) from both ends.
+// Returns the remaining samples plus how many were removed at the front and back.
+pub fn trim_silence(samples: &[f32], threshold_db: f32) -> (Vec<f32>, usize, usize) {
+    let threshold = 10f32.powf(threshold_db / 20.0); // dBFS -> linear amplitude
+    let is_loud = |sample: &f32| sample.abs() >= threshold;
 
-    let mut bits = Vec::new();
+    let Some(first) = samples.iter().position(is_loud) else {
+        return (Vec::new(), samples.len(), 0); // all silence
+    };
+    let last = samples.iter().rposition(is_loud).unwrap_or(first);
 
-    // 1. Pilot pattern for threshold calibration
-    bits.extend_from_slice(&PILOT_PATTERN);
+    let lead = first;
+    let tail = samples.len() - 1 - last;
+    (samples[first..=last].to_vec(), lead, tail)
+}
 
-    // 2. Length header (16 bits, MSB first)
-    for shift in (0..16).rev() {
-        bits.push(((length_header >> shift) & 1) as u8);
+// =============================================================================
+// STEP 1c: Pad to a minimum length
+// =============================================================================
+
+// Extend `samples` to at least `target` samples, either with zeros or by
+// repeating the input from the start. Zero padding never carries the
+// watermark: scaling a silent bin leaves it silent, and the decoder skips
+// those frames because their pilot is unusable. Looping keeps every padded
+// frame a carrier.
+fn pad_to_length(mut samples: Vec<f32>, target: usize, loop_input: bool) -> Vec<f32> {
+    if samples.len() >= target {
+        return samples; // already long enough
+    }
+
+    let original_len = samples.len();
+    if loop_input && original_len > 0 {
+        let missing = target - original_len;
+        let repeated: Vec<f32> = samples.iter().copied().cycle().take(missing).collect();
+        samples.extend(repeated);
+    } else {
+        samples.resize(target, 0.0);
     }
 
-    // Position:  15 14 13 12 11 10  9  8  7  6  5  4  3  2  1  0
-                                                 // Binary:     0  0  0  0  0  0  0  0  0  0  0  0  0  1  0  1
+    samples
+}
 
-    // 3. Message payload (8 bits per byte, MSB first)
-    for &byte in message_bytes {
-        for shift in (0..8).rev() {
-            bits.push((byte >> shift) & 1);
+// =============================================================================
+// STEP 1d: Attenuate
+// =============================================================================
+
+// Linear gain for a cut of `db` decibels (the sign is ignored: always a cut).
+// Boosting a bin can push peaks past full scale; attenuating first keeps them
+// in range. The watermark is relative to the audio, so the decoder is
+// unaffected, but the output stays quieter: turning it back up later would
+// restore the clipping along with the level.
+fn attenuation_gain(db: f32) -> f32 {
+    10f32.powf(-db.abs() / 20.0)
+}
+
+// Gain that brings the loudest sample of any track to NORMALIZE_PEAK_DB.
+// None for silence, which no gain can normalize.
+fn normalize_gain(tracks: &[Vec<f32>]) -> Option<f32> {
+    let peak = tracks
+        .iter()
+        .flatten()
+        .map(|sample| sample.abs())
+        .fold(0.0f32, f32::max);
+    if peak == 0.0 {
+        diag!("Input is silent; skipping normalization");
+        return None;
+    }
+    Some(10f32.powf(NORMALIZE_PEAK_DB / 20.0) / peak)
+}
+
+fn attenuate(samples: &mut [f32], gain: f32) {
+    for sample in samples {
+        *sample *= gain;
+    }
+}
+
+// =============================================================================
+// STEP 2: Build bit sequence (pilot + magic + length + message)
+// =============================================================================
+
+
+// The watermark for `message`, with a fresh nonce when the config asks for one.
+// A scrambled payload is effectively random, and a long run of equal bits
+// scores close to zero against its own neighbours, so nonces are redrawn
+// until the stream has no run longer than MAX_NONCE_RUN.
+fn message_watermark(message: &Payload, config: &WatermarkConfig) -> Watermark {
+    let mut watermark = Watermark::new(&payload_bytes(message, config), config.framing);
+    if let Some(key) = &config.key {
+        watermark = watermark.with_key(key);
+    }
+    if !config.nonce {
+        return watermark;
+    }
+    let mut candidate = watermark.clone().with_nonce(fresh_nonce());
+    for _ in 1..NONCE_ATTEMPTS {
+        if longest_run(&candidate.to_bits()) <= MAX_NONCE_RUN {
+            break;
+        }
+        candidate = watermark.clone().with_nonce(fresh_nonce());
+    }
+    candidate
+}
+
+// Longest stretch of identical bits
+fn longest_run(bits: &[u8]) -> usize {
+    bits.chunk_by(|a, b| a == b).map(<[u8]>::len).max().unwrap_or(0)
+}
+
+// Random per call: std's RandomState is seeded from the OS for every instance
+fn fresh_nonce() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+// Bytes the framing carries: the (typed) message, deflated with --compress
+fn payload_bytes(message: &Payload, config: &WatermarkConfig) -> Vec<u8> {
+    let bytes = message.to_bytes(config.typed_payload);
+    if config.compress {
+        compress(&bytes)
+    } else {
+        bytes
+    }
+}
+
+fn build_bit_sequence(message: &Payload, config: &WatermarkConfig) -> Result<Vec<u8>, EncodeError> {
+    let message_bytes = payload_bytes(message, config);
+    if config.compress {
+        let original = message.to_bytes(config.typed_payload).len();
+        if message_bytes.len() <= original {
+            diag!("Deflated the payload from {} to {} bytes (flag included)", original, message_bytes.len());
+        } else {
+            diag!("Storing the payload uncompressed; deflate would not shrink {} bytes", original);
         }
     }
 
-    println!(
-        "Encoding message {:?} ({} bytes)",
+    // The header stores the length as a u16; a longer message would wrap
+    // around to a wrong length and decode as garbage
+    if config.framing == Framing::LengthHeader && message_bytes.len() > usize::from(u16::MAX) {
+        return Err(EncodeError::LengthOverflow {
+            bytes: message_bytes.len(),
+        });
+    }
+
+    // Pilot, magic, framing header and payload - the layout lives in watermark.rs
+    let watermark = message_watermark(message, config);
+    if let Some(nonce) = watermark.nonce {
+        diag!("Scrambling the payload with nonce {:#06x}", nonce);
+    }
+    if watermark.key.is_some() {
+        diag!("Appending a {}-byte HMAC tag", TAG_BYTES);
+    }
+    let bits = watermark.to_bits();
+
+    diag!(
+        "Encoding message {} ({} bytes)",
         message,
         message_bytes.len()
     );
-    println!(
-        "Total bits to embed (pilot + length + data): {}",
+    diag!(
+        "Total bits to embed (pilot + magic + framing + data): {}",
         bits.len()
     );
 
-    bits
+    // The decoder thresholds log magnitudes halfway between the pilot's 1s
+    // and 0s; make sure the factors land on opposite sides of that line, and
+    // strictly, so a clean bin never sits on it (a tie reads as a 1, see
+    // decides_one)
+    let (high, low) = (BOOST_FACTOR.ln(), SUPPRESS_FACTOR.ln());
+    let threshold = threshold_from_pilot(high, low);
+    assert!(
+        low < threshold && threshold < high,
+        "boost/suppress factors do not straddle the decoder threshold"
+    );
+    diag!(
+        "Pilot log levels: 1 -> {:.3}, 0 -> {:.3}, decoder threshold {:.3}",
+        high, low, threshold
+    );
+
+    Ok(bits)
 }
 
 // =============================================================================
 // STEP 3: Embed watermark using FFT
 // =============================================================================
 
-fn embed_watermark_fft(audio: &[f32], bits: &[u8]) -> Vec<f32> {
-    let frame_len = (8000.0 * 0.032) as usize;  // 8000 Hz * 32ms = 256 samples
+// Frames one copy takes under --bits-per-frame: enough groups of the cap to
+// cover every data bin of a `capacity`-bit frame, whatever the sequence
+// length, so the decoder can split the frames up knowing only the band.
+// 1 without the cap, or when a whole frame fits under it.
+fn frame_groups(capacity: usize, config: &WatermarkConfig) -> usize {
+    config.max_bits_per_frame.map_or(1, |cap| capacity.div_ceil(cap).max(1))
+}
+
+pub fn embed_watermark_fft(
+    audio: &[f32],
+    bits: &[u8],
+    plans: &FftPlans,
+    hop: usize,
+    band: FreqBand,
+    config: &WatermarkConfig,
+) -> Embedded {
+    let frame_len = plans.fft_len();
+    let window_kind = config.window;
+    let window = make_window(window_kind, frame_len);
 
-    let mut planner = RealFftPlanner::<f32>::new();
-    let fft = planner.plan_fft_forward(frame_len);
-    let ifft = planner.plan_fft_inverse(frame_len);
+    // --strength (or an SNR retry) raises both factors to a power, shrinking
+    // them towards 1 in the log domain the decoder thresholds in
+    let (boost, suppress) = match config.strength {
+        Some(strength) => (BOOST_FACTOR.powf(strength), SUPPRESS_FACTOR.powf(strength)),
+        None => (BOOST_FACTOR, SUPPRESS_FACTOR),
+    };
+
+    // Overlapping frames each add their change, so divide by how many window
+    // copies land on a sample. Back-to-back frames keep the original unity gain.
+    let gain = if hop < frame_len {
+        let (gain, cola) = overlap_gain(&window, hop);
+        if !cola {
+            diag!(
+                "Warning: {:?} window is not constant-overlap-add at a hop of {} samples; \
+                 watermark strength will ripple within each hop",
+                window_kind, hop
+            );
+        }
+        gain
+    } else {
+        1.0
+    };
+
+    // --embed-mode floor: the least magnitude a 1 bin may have. A sine at
+    // amplitude A over a whole frame peaks at A * frame_len / 2, which puts
+    // --floor-db on the same scale as a tone's level.
+    let floor = match config.embed_mode {
+        EmbedMode::AdditiveFloor => {
+            let floor_db = config.floor_db.unwrap_or(DEFAULT_FLOOR_DBFS);
+            10f32.powf(floor_db / 20.0) * frame_len as f32 / 2.0
+        }
+        _ => 0.0,
+    };
+
+    let (fft, ifft) = (&plans.forward, &plans.inverse);
 
     let mut buffer = vec![0.0f32; frame_len];
 
@@ -140,16 +1195,58 @@ fn embed_watermark_fft(audio: &[f32], bits: &[u8]) -> Vec<f32> {
     //[___|___|___|___|___| ... |___|___|___]
 
     let mut spectrum = fft.make_output_vec();
-    let mut output = Vec::new();
+    let mut output = audio.to_vec();
+    let mut stats = BinStats::new(spectrum.len());
+    let frames = frame_starts(audio.len(), hop).count();
+    let capacity = band.capacity(config.guard);
+    let groups = frame_groups(capacity, config);
+    if groups > 1 {
+        diag!(
+            "--bits-per-frame: {} bits spread over {} frames per copy",
+            bits.len(), groups
+        );
+    }
+    // --vad-threshold-db: silent frames get no share of the stream, so the
+    // next loud frame picks up where the last one left off
+    let active = match config.vad_threshold_db {
+        Some(threshold_db) => active_frames(audio, frame_len, hop, threshold_db),
+        None => vec![true; frames],
+    };
+    let silent = active.iter().filter(|&&active| !active).count();
+    if silent > 0 {
+        diag!(
+            "--vad-threshold-db: {} of {} frames are below {:.1} dBFS and stay unmarked",
+            silent, frames, config.vad_threshold_db.unwrap_or_default()
+        );
+    }
+    // The allocation runs over every data bin, not just the ones the bits
+    // reach: a copy takes `groups` frames, and those past the last bit write
+    // nothing
+    let cap = config.max_bits_per_frame.unwrap_or(capacity);
+    let capacities: Vec<usize> = active.iter().map(|&active| if active { cap } else { 0 }).collect();
+    let allocation = allocate_bits(&capacities, capacity);
+    let bit_bins: Vec<usize> = band.bit_bins(bits.len(), config.guard).collect();
+    let mut bins_used = Vec::new();
+    let mut analysed = spectrum.clone();
+    let mut changes = Vec::with_capacity(bits.len());
+    let (mut weakened, mut unmarked) = (0, 0);
 
     // Process each frame
-    for chunk in audio.chunks(frame_len) {
+    for (frame, start) in frame_starts(audio.len(), hop).enumerate() {
+        if !active[frame] {
+            continue; // left as it is
+        }
+        let chunk = &audio[start..(start + frame_len).min(audio.len())];
         // Load audio
         buffer[..frame_len].fill(0.0); //wipe clean every time becasue multiple iterations
         buffer[..chunk.len()].copy_from_slice(chunk); //copies chunk into our empty slots
+        for (sample, w) in buffer.iter_mut().zip(&window) {
+            *sample *= w; // analysis window (all ones for rectangular)
+        }
 
         // Time → Frequency
         fft.process(&mut buffer, &mut spectrum).expect("FFT failed"); //i will explain in the decoder video
+        analysed.copy_from_slice(&spectrum);
 
         // Embed bits: boost (1.15) or reduce (0.85) frequency amplitudes
         // Produces: &0, &1, &0, &1, &0, &1, ...
@@ -164,30 +1261,174 @@ fn embed_watermark_fft(audio: &[f32], bits: &[u8]) -> Vec<f32> {
         // &0     ←──→  bin12
         // &1     ←──→  bin13
          // ...
-        for (&bit, bin) in bits.iter().zip(&mut spectrum[10..]) {
-            let scale = if bit == 1 { 2.0 } else { 0.0 };
-            bin.re *= scale;
-            bin.im *= scale;
+        //
+        // The math runs in polar form: only |bin| is changed, arg(bin) is
+        // carried over from the input. Each frame therefore keeps the phase
+        // the original audio had, so a sustained tone stays continuous across
+        // frame boundaries instead of warbling.
+        //
+        // --ramp-frames fades the factors in and out in the same log domain
+        // as --strength: ramp frame k of N embeds at (k + 1) / (N + 1).
+        let ramp = ramp_weight(frame, frames, config.ramp_frames);
+
+        // --no-clip: when the marked frame would push a sample past full
+        // scale, embed it again at half the weight, down to HEADROOM_STEPS
+        // halvings; past that the frame is left unmarked. Only the weight the
+        // frame settles on reaches stats and the output.
+        let mut halvings = 0;
+        let fits = loop {
+            let weight = ramp * 0.5f32.powi(halvings);
+            let (boost, suppress, floor) = if weight < 1.0 {
+                (boost.powf(weight), suppress.powf(weight), floor * weight)
+            } else {
+                (boost, suppress, floor)
+            };
+            spectrum.copy_from_slice(&analysed);
+            changes.clear();
+            // --bits-per-frame: this frame writes only its share of the sequence
+            let carried = allocation[frame].iter().filter_map(|&index| bits.get(index).zip(bit_bins.get(index)));
+            for (&bit, &bin_idx) in carried {
+                let bin = &mut spectrum[bin_idx];
+                let magnitude = magnitude(bin);
+                let target = if bit == 1 {
+                    (magnitude * boost).max(floor) // floor is 0 unless --embed-mode floor
+                } else {
+                    magnitude * suppress
+                };
+                set_magnitude(bin, target);
+                changes.push((bin_idx, (target - magnitude).abs() / frame_len as f32));
+            }
+
+            // from_polar leaves a rounding-error imaginary part on a real Nyquist
+            // bin (phase 0 or pi), which the inverse real FFT rejects
+            if let Some(nyquist) = spectrum.last_mut() {
+                nyquist.im = 0.0;
+            }
+
+            // Frequency → Time
+            ifft.process(&mut spectrum, &mut buffer).expect("IFFT failed");
+
+            // The change each sample gets (see the overlap-add below)
+            let in_range = !config.headroom
+                || output[start..].iter().zip(chunk).zip(buffer.iter().zip(&window)).all(
+                    |((out, x), (y, w))| (out + (y / frame_len as f32 - w * x) / gain).abs() <= 1.0,
+                );
+            if in_range || halvings == HEADROOM_STEPS {
+                break in_range;
+            }
+            halvings += 1;
+        };
+        if !fits {
+            unmarked += 1;
+            continue;
+        }
+        if halvings > 0 {
+            weakened += 1;
+        }
+        for &(bin_idx, delta) in &changes {
+            stats.record(bin_idx, delta);
+            bins_used.push((frame, bin_idx));
         }
 
-        // Frequency → Time
-        ifft.process(&mut spectrum, &mut buffer).expect("IFFT failed");
+        // Normalize and overlap-add. The IFFT gives back the *windowed* frame plus
+        // the watermark, so we add only the change (y - w*x) onto the original
+        // samples; otherwise a tapered window would fade every frame edge.
+        // The change itself still carries the taper, so frame edges hold less
+        // watermark energy. With the rectangular window and no overlap this is
+        // simply y. For a COLA window/hop this equals overlap-adding y / gain.
+        for ((out, x), (y, w)) in output[start..]
+            .iter_mut()
+            .zip(chunk)
+            .zip(buffer.iter().zip(&window))
+        {
+            *out += (y / frame_len as f32 - w * x) / gain;
+        }
+    }
 
-        // Normalize and append
-        output.extend(buffer[..chunk.len()].iter().map(|x| x / frame_len as f32));
+    if weakened + unmarked > 0 {
+        diag!(
+            "--no-clip: {} of {} frames embedded weaker to stay in range, {} left unmarked",
+            weakened, frames, unmarked
+        );
     }
 
-    output
+    Embedded {
+        samples: output,
+        stats: Some(stats),
+        bins_used,
+    }
 }
 
 // =============================================================================
-// STEP 4: Quantize to i16 samples
+// STEP 4: Quantize to integer samples
 // =============================================================================
 
-fn quantize_to_i16(encoded: Vec<f32>) -> Vec<i16> {
+// Samples ready to write: integer PCM codes at the output depth, or (float
+// output) the embedded samples untouched
+enum QuantizedSamples {
+    Int(Vec<i32>),
+    Float(Vec<f32>),
+}
+
+impl QuantizedSamples {
+    fn len(&self) -> usize {
+        match self {
+            QuantizedSamples::Int(samples) => samples.len(),
+            QuantizedSamples::Float(samples) => samples.len(),
+        }
+    }
+}
+
+// A mono track copied into each of `channels` interleaved channels
+fn duplicate_mono(encoded: Vec<f32>, channels: u16) -> Vec<f32> {
     encoded
         .into_iter()
-        .map(|sample| (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16)
+        .flat_map(|sample| iter::repeat_n(sample, usize::from(channels)))
+        .collect()
+}
+
+// The write-side counterpart of load_and_normalize_audio: samples in the
+// output spec's format. Integer depths (16, 24, 32) go through quantize_int;
+// float output is the embedded buffer itself, out-of-range samples included.
+fn quantize(
+    encoded: Vec<f32>,
+    bits: u16,
+    format: SampleFormat,
+    round_mode: RoundMode,
+) -> QuantizedSamples {
+    match format {
+        SampleFormat::Float => QuantizedSamples::Float(encoded),
+        SampleFormat::Int => QuantizedSamples::Int(quantize_int(encoded, bits, round_mode)),
+    }
+}
+
+// Symmetric scale by the largest positive code, 2^(bits - 1) - 1: at 16 bits
+// +1.0 -> 32767 and -1.0 -> -32767, so the most negative code is never
+// produced and neither rail can overflow. Anything outside [-1, 1] is clamped
+// first. One output step is 1/32767 of full scale at 16 bits, 1/8388607 at
+// 24 and 1/2147483647 at 32.
+//
+// 16 bits multiply in f32, as the encoder always has, so its output stays
+// bit for bit what it was (simd::quantize_narrow, vectorised with --features
+// simd). Deeper codes need more than f32's 24-bit mantissa
+// to land on the right step (2147483647 isn't even representable), so 24 and
+// 32 bits multiply in f64.
+fn quantize_int(encoded: Vec<f32>, bits: u16, round_mode: RoundMode) -> Vec<i32> {
+    let full_scale = ((1i64 << (bits - 1)) - 1) as f64;
+    if bits <= 16 {
+        return simd::quantize_narrow(&encoded, full_scale as f32, round_mode);
+    }
+    encoded
+        .into_iter()
+        .map(|sample| {
+            let scaled = f64::from(sample.clamp(-1.0, 1.0)) * full_scale;
+            let rounded = match round_mode {
+                RoundMode::Nearest => scaled.round(),
+                RoundMode::HalfEven => scaled.round_ties_even(),
+                RoundMode::Truncate => scaled.trunc(),
+            };
+            rounded as i32
+        })
         .collect()
 }
 
@@ -195,13 +1436,837 @@ fn quantize_to_i16(encoded: Vec<f32>) -> Vec<i16> {
 // STEP 5: Write WAV file to disk
 // =============================================================================
 
-fn write_wav_file(output_path: &Path, quantized: &[i16], spec: hound::WavSpec) {
-    let mut writer = WavWriter::create(output_path, spec).expect("failed to create wav writer");
-    
-    for &sample in quantized {
-        writer.write_sample(sample).expect("failed to write sample");
+// "-" streams to stdout (see write_wav_stream); any other path goes through
+// hound, which seeks back to patch the sizes once the samples are written.
+// Without `overwrite` the file must not exist yet: create_new checks and
+// creates it in one step, so nothing written in between gets clobbered.
+fn write_wav_file(
+    output_path: &Path,
+    quantized: &QuantizedSamples,
+    spec: hound::WavSpec,
+    overwrite: bool,
+) -> Result<(), EncodeError> {
+    if output_path == Path::new("-") {
+        let stdout = io::stdout().lock();
+        write_wav_stream(BufWriter::new(stdout), quantized, spec).map_err(EncodeError::Io)?;
+        diag!("Wrote watermarked audio to stdout");
+        return Ok(());
+    }
+
+    let file = if overwrite {
+        File::create(output_path)
+    } else {
+        OpenOptions::new().write(true).create_new(true).open(output_path)
+    };
+    let file = file.map_err(|err| match err.kind() {
+        io::ErrorKind::AlreadyExists => EncodeError::OutputExists {
+            path: output_path.to_path_buf(),
+        },
+        _ => EncodeError::Io(err),
+    })?;
+    let mut writer = WavWriter::new(BufWriter::new(file), spec).map_err(write_error)?;
+    let written = match quantized {
+        QuantizedSamples::Int(samples) => samples.iter().try_for_each(|&s| writer.write_sample(s)),
+        QuantizedSamples::Float(samples) => samples.iter().try_for_each(|&s| writer.write_sample(s)),
+    };
+    written.map_err(write_error)?;
+    writer.finalize().map_err(write_error)?;
+    diag!("Wrote watermarked audio to {}", output_path.display());
+    Ok(())
+}
+
+// A hound write failure as the I/O error behind it
+fn write_error(err: hound::Error) -> EncodeError {
+    match err {
+        hound::Error::IoError(err) => EncodeError::Io(err),
+        err => EncodeError::Io(io::Error::other(err)),
+    }
+}
+
+// Size-known-ahead WAV writer for outputs that can't seek (pipes, stdout).
+// Every sample is already in memory, so the RIFF and data sizes go into a
+// canonical 44-byte header (PCM, or IEEE float) up front and the samples
+// stream after it.
+fn write_wav_stream(
+    mut writer: impl Write,
+    quantized: &QuantizedSamples,
+    spec: hound::WavSpec,
+) -> io::Result<()> {
+    let bytes_per_sample = spec.bits_per_sample / 8;
+    let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "too much audio for one WAV file");
+    let data_len = u32::try_from(quantized.len() * usize::from(bytes_per_sample))
+        .ok()
+        .filter(|len| *len <= u32::MAX - 36)
+        .ok_or_else(too_large)?;
+    let block_align = spec.channels * bytes_per_sample;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+    let format_tag: u16 = match quantized {
+        QuantizedSamples::Int(_) => 1,   // WAVE_FORMAT_PCM
+        QuantizedSamples::Float(_) => 3, // WAVE_FORMAT_IEEE_FLOAT
+    };
+    writer.write_all(&format_tag.to_le_bytes())?;
+    writer.write_all(&spec.channels.to_le_bytes())?;
+    writer.write_all(&spec.sample_rate.to_le_bytes())?;
+    writer.write_all(&(spec.sample_rate * u32::from(block_align)).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&spec.bits_per_sample.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+
+    match quantized {
+        QuantizedSamples::Int(samples) => {
+            for &sample in samples {
+                // Little-endian, so the low bytes come first
+                writer.write_all(&sample.to_le_bytes()[..usize::from(bytes_per_sample)])?;
+            }
+        }
+        QuantizedSamples::Float(samples) => {
+            for &sample in samples {
+                writer.write_all(&sample.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use realfft::num_complex::Complex;
+
+    use super::*;
+    use crate::config::{MidSideChannel, Scheme};
+    use crate::watermark::PILOT_PATTERN;
+
+    const RATE: u32 = 8000;
+    const FRAME_LEN: usize = 256; // 32 ms at RATE
+
+    fn encode(text: &str, samples: &[f32], config: &WatermarkConfig) -> Result<Vec<f32>, EncodeError> {
+        let message = Payload::Text(text.to_string());
+        let encoder = Encoder::new(config.clone());
+        encoder.encode(samples, 1, RATE, &message).map(|(encoded, _)| encoded)
+    }
+
+    // Uniform xorshift noise peaking at 0.25: energy in every bin
+    fn noise(samples: usize) -> Vec<f32> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        (0..samples)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                ((state >> 40) as f32 / (1u64 << 24) as f32 - 0.5) * 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ten_sample_input_is_too_short() {
+        let result = encode("hi", &[0.1; 10], &WatermarkConfig::default());
+        assert!(matches!(
+            result,
+            Err(EncodeError::InputTooShort { samples: 10, required: FRAME_LEN, .. })
+        ));
+    }
+
+    #[test]
+    fn message_over_the_limit_is_rejected_even_when_it_fits() {
+        let limited = WatermarkConfig {
+            max_message_bytes: Some(3),
+            ..WatermarkConfig::default()
+        };
+        let samples = vec![0.1; FRAME_LEN * 4];
+        assert!(encode("hey", &samples, &limited).is_ok());
+        assert!(encode("hello", &samples, &WatermarkConfig::default()).is_ok()); // fits a frame
+        assert!(matches!(
+            encode("hello", &samples, &limited),
+            Err(EncodeError::MessageOverLimit { bytes: 5, limit: 3 })
+        ));
+    }
+
+    #[test]
+    fn a_70000_byte_message_overflows_the_length_header() {
+        let long = "x".repeat(70_000);
+        let samples = vec![0.1; FRAME_LEN * 4];
+        assert!(matches!(
+            encode(&long, &samples, &WatermarkConfig::default()),
+            Err(EncodeError::LengthOverflow { bytes: 70_000 })
+        ));
+        // Varint framing has no such limit, so it gets as far as the capacity check
+        let varint = WatermarkConfig {
+            framing: Framing::Varint,
+            ..WatermarkConfig::default()
+        };
+        assert!(matches!(encode(&long, &samples, &varint), Err(EncodeError::MessageTooLong { .. })));
+    }
+
+    #[test]
+    fn pad_to_seconds_sets_the_output_duration() {
+        let padded = WatermarkConfig {
+            pad_to_seconds: Some(1.5),
+            ..WatermarkConfig::default()
+        };
+        let short = vec![0.1; RATE as usize / 2];
+        assert_eq!(encode("hi", &short, &padded).unwrap().len(), RATE as usize * 3 / 2);
+        let long = vec![0.1; RATE as usize * 2]; // already longer: left as is
+        assert_eq!(encode("hi", &long, &padded).unwrap().len(), long.len());
+    }
+
+    #[test]
+    fn encode_reports_its_step_timings() {
+        let message = Payload::Text("hi".to_string());
+        let encoder = Encoder::new(WatermarkConfig::default());
+        let (_, report) = encoder.encode(&vec![0.1; RATE as usize], 1, RATE, &message).unwrap();
+        let timings = report.timings;
+        assert!(timings.load > Duration::ZERO);
+        assert!(timings.build_bits > Duration::ZERO);
+        assert!(timings.embed > Duration::ZERO);
+        // In memory nothing is quantized or written; encode_file fills those in
+        assert_eq!(timings.quantize + timings.write, Duration::ZERO);
+        assert_eq!(timings.total(), timings.load + timings.build_bits + timings.embed);
+    }
+
+    #[test]
+    fn bin_stats_stay_inside_the_band() {
+        let band = FreqBand::from_name("20-100").unwrap();
+        let config = WatermarkConfig {
+            band,
+            ..WatermarkConfig::default()
+        };
+        let message = Payload::Text("hi".to_string());
+        let (_, report) = Encoder::new(config).encode(&noise(RATE as usize), 1, RATE, &message).unwrap();
+        let stats = report.bin_stats.unwrap();
+        let touched: Vec<usize> = (0..stats.bins()).filter(|&bin| stats.max(bin) > 0.0).collect();
+        assert!(!touched.is_empty());
+        assert!(touched.iter().all(|&bin| (band.low_bin..band.high_bin).contains(&bin)), "{:?}", touched);
+    }
+
+    fn report(text: &str, samples: &[f32], config: WatermarkConfig) -> EncodeReport {
+        let message = Payload::Text(text.to_string());
+        Encoder::new(config).encode(samples, 1, RATE, &message).unwrap().1
+    }
+
+    #[test]
+    fn bins_used_follow_the_bits_per_frame_groups() {
+        let config = WatermarkConfig {
+            max_bits_per_frame: Some(20),
+            ..WatermarkConfig::default()
+        };
+        let data_bins: Vec<usize> = encoder_band(&config, FRAME_LEN).data_bins(config.guard).collect();
+        // One noise frame over and over: every frame has the same spectrum
+        let looped: Vec<f32> = noise(FRAME_LEN).into_iter().cycle().take(FRAME_LEN * 24).collect();
+        let report = report("hi", &looped, config);
+        let bits = report.bits.len();
+        // A copy takes every data bin's worth of groups, however short the bits
+        let groups = data_bins.len().div_ceil(20);
+        assert!(bits > 20 && groups > bits.div_ceil(20));
+        let frames = report.bins_used.last().unwrap().0 + 1;
+        for frame in 0..frames {
+            let written: Vec<usize> =
+                report.bins_used.iter().filter(|&&(f, _)| f == frame).map(|&(_, bin)| bin).collect();
+            let group = frame % groups * 20;
+            let expected = &data_bins[group.min(bits)..(group + 20).min(bits)];
+            assert_eq!(written, expected, "frame {}", frame);
+        }
+        // Each bin changes by the same amount in every frame that writes to
+        // it, so its mean over those frames is its max
+        let stats = report.bin_stats.unwrap();
+        for &bin in &data_bins[..bits] {
+            assert!((stats.mean(bin) - stats.max(bin)).abs() <= 1e-3 * stats.max(bin), "bin {}", bin);
+        }
+    }
+
+    #[test]
+    fn embed_mode_none_writes_no_bins() {
+        let config = WatermarkConfig {
+            embed_mode: EmbedMode::None,
+            ..WatermarkConfig::default()
+        };
+        assert!(report("hi", &noise(RATE as usize), config).bins_used.is_empty());
+    }
+
+    #[test]
+    fn embed_mode_none_leaves_only_the_round_trip_error() {
+        let input = noise(RATE as usize);
+        let max_change = |encoded: &[f32]| {
+            input
+                .iter()
+                .zip(encoded)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0f32, f32::max)
+        };
+        let pass_through = WatermarkConfig {
+            embed_mode: EmbedMode::None,
+            ..WatermarkConfig::default()
+        };
+        let control = encode("hi", &input, &pass_through).unwrap();
+        assert_eq!(control.len(), input.len());
+        // Well under one 16-bit step, so quantizing hides it
+        assert!(max_change(&control) < 1e-5, "{}", max_change(&control));
+        assert!(max_change(&encode("hi", &input, &WatermarkConfig::default()).unwrap()) > 1e-2);
+    }
+
+    #[test]
+    fn latency_is_the_rest_of_a_frame_plus_the_ramp_out() {
+        let default = WatermarkConfig::default();
+        assert_eq!(latency_samples(&default, FRAME_LEN), FRAME_LEN - 1);
+        assert_eq!(report("hi", &noise(RATE as usize), default).latency, FRAME_LEN - 1);
+
+        // 75% overlap: more frames cover each sample, but the last one still
+        // starts at or before it
+        let overlapped = WatermarkConfig {
+            overlap: 0.75,
+            ..WatermarkConfig::default()
+        };
+        assert_eq!(latency_samples(&overlapped, FRAME_LEN), FRAME_LEN - 1);
+        let ramped = WatermarkConfig {
+            ramp_frames: 2,
+            ..overlapped
+        };
+        assert_eq!(latency_samples(&ramped, FRAME_LEN), FRAME_LEN - 1 + 2 * FRAME_LEN / 4);
+    }
+
+    #[test]
+    fn ramp_frames_weaken_the_first_and_last_frames() {
+        let input = noise(FRAME_LEN * 32);
+        // Energy each frame's embedding added
+        let changes = |config: &WatermarkConfig| -> Vec<f32> {
+            let encoded = encode("hi", &input, config).unwrap();
+            input
+                .chunks(FRAME_LEN)
+                .zip(encoded.chunks(FRAME_LEN))
+                .map(|(a, b)| a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum())
+                .collect()
+        };
+        let config = WatermarkConfig {
+            ramp_frames: 4,
+            ..WatermarkConfig::default()
+        };
+        let (full, ramped) = (changes(&WatermarkConfig::default()), changes(&config));
+        let share: Vec<f32> = ramped.iter().zip(&full).map(|(ramped, full)| ramped / full).collect();
+        for edge in 0..3 {
+            assert!(share[edge] < share[edge + 1], "fade in: {:?}", &share[..5]);
+            assert!(share[31 - edge] < share[30 - edge], "fade out: {:?}", &share[27..]);
+        }
+        assert!(share[0] < 0.5 && share[31] < 0.5, "{:?}", share);
+        assert!(share[4..28].iter().all(|&share| (share - 1.0).abs() < 1e-3), "{:?}", share);
+
+        // The decoder leaves the faded frames out of its vote
+        let encoded = encode("hi", &input, &config).unwrap();
+        assert_eq!(crate::decoder::decode_samples(&encoded, 1, RATE, &config).unwrap().message, "hi");
+    }
+
+    #[test]
+    fn phase_coding_writes_its_bits_in_frame_zero_only() {
+        let config = WatermarkConfig {
+            scheme: Scheme::PhaseCoding,
+            ..WatermarkConfig::default()
+        };
+        let report = report("hi", &noise(RATE as usize), config);
+        assert_eq!(report.bins_used.len(), report.bits.len());
+        assert!(report.bins_used.iter().all(|&(frame, _)| frame == 0));
+    }
+
+    #[test]
+    fn frames_no_clip_leaves_unmarked_are_not_listed() {
+        // Noise at full scale: most frames can't take a mark without clipping
+        let loud: Vec<f32> = noise(RATE as usize).iter().map(|x| x * 4.0).collect();
+        let frames = |config: WatermarkConfig| {
+            let mut frames: Vec<usize> = report("hi", &loud, config).bins_used.iter().map(|&(f, _)| f).collect();
+            frames.dedup();
+            frames.len()
+        };
+        let clipping = frames(WatermarkConfig::default());
+        let headroom = frames(WatermarkConfig {
+            headroom: true,
+            ..WatermarkConfig::default()
+        });
+        assert!(headroom < clipping, "{} of {} frames listed", headroom, clipping);
+    }
+
+    #[test]
+    fn no_clip_keeps_a_full_scale_input_in_range_and_decodable() {
+        // Swells up to full scale and back, like speech: the boosted bins
+        // push the loud stretches over, while the quiet ones keep a full mark
+        let swell = |n: usize| 0.15 + 0.85 * (TAU * n as f32 / RATE as f32).sin().abs();
+        let shaped: Vec<f32> = noise(RATE as usize * 2).iter().enumerate().map(|(n, x)| x * swell(n)).collect();
+        let peak = shaped.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        let loud: Vec<f32> = shaped.iter().map(|x| x / peak).collect();
+        let clipped = |samples: &[f32]| samples.iter().filter(|x| x.abs() > 1.0).count();
+        assert!(clipped(&encode("hi", &loud, &WatermarkConfig::default()).unwrap()) > 0);
+
+        let config = WatermarkConfig {
+            headroom: true,
+            ..WatermarkConfig::default()
+        };
+        let encoded = encode("hi", &loud, &config).unwrap();
+        assert_eq!(clipped(&encoded), 0);
+        assert_eq!(crate::decoder::decode_samples(&encoded, 1, RATE, &config).unwrap().message, "hi");
+    }
+
+    fn spectrum_of(frame: &[f32]) -> Vec<Complex<f32>> {
+        let plans = PlanCache::new().plans(frame.len());
+        let mut buffer = frame.to_vec();
+        let mut spectrum = plans.forward.make_output_vec();
+        plans.forward.process(&mut buffer, &mut spectrum).unwrap();
+        spectrum
+    }
+
+    #[test]
+    fn steady_tone_keeps_its_phase_in_every_frame() {
+        // A tone centred on band bin 20; every frame changes only its magnitude
+        let tone: Vec<f32> = (0..FRAME_LEN * 8)
+            .map(|n| 0.5 * (TAU * 20.0 * n as f32 / FRAME_LEN as f32 + 0.3).sin())
+            .collect();
+        let encoded = encode("hi", &tone, &WatermarkConfig::default()).unwrap();
+        for start in (0..tone.len()).step_by(FRAME_LEN) {
+            let before = spectrum_of(&tone[start..start + FRAME_LEN])[20];
+            let after = spectrum_of(&encoded[start..start + FRAME_LEN])[20];
+            assert!((magnitude(&after) - magnitude(&before)).abs() > 1.0, "bin 20 unmarked");
+            assert!((after.arg() - before.arg()).abs() < 1e-3, "phase moved at {}", start);
+        }
+    }
+
+    #[test]
+    fn pilot_bins_straddle_the_shared_threshold() {
+        let signal = noise(FRAME_LEN * 8);
+        for strength in [None, Some(0.5)] {
+            let config = WatermarkConfig {
+                strength,
+                ..WatermarkConfig::default()
+            };
+            let (high, low) = match strength {
+                Some(strength) => (BOOST_FACTOR.powf(strength).ln(), SUPPRESS_FACTOR.powf(strength).ln()),
+                None => (BOOST_FACTOR.ln(), SUPPRESS_FACTOR.ln()),
+            };
+            let threshold = threshold_from_pilot(high, low);
+            let encoded = encode("hi", &signal, &config).unwrap();
+            let pilot_bins = encoder_band(&config, FRAME_LEN).bit_bins(PILOT_PATTERN.len(), config.guard);
+            for start in (0..signal.len()).step_by(FRAME_LEN) {
+                let before = spectrum_of(&signal[start..start + FRAME_LEN]);
+                let after = spectrum_of(&encoded[start..start + FRAME_LEN]);
+                for (&bit, bin) in PILOT_PATTERN.iter().zip(pilot_bins.clone()) {
+                    // Log level change, in the domain the decoder thresholds in
+                    let level = (magnitude(&after[bin]) / magnitude(&before[bin])).ln();
+                    match bit {
+                        1 => assert!(level > threshold, "bin {}: {} <= {}", bin, level, threshold),
+                        _ => assert!(level < threshold, "bin {}: {} >= {}", bin, level, threshold),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compression_fits_a_message_that_would_not_fit_raw() {
+        let long = "a".repeat(40);
+        let input = noise(RATE as usize);
+        assert!(matches!(
+            encode(&long, &input, &WatermarkConfig::default()),
+            Err(EncodeError::MessageTooLong { .. })
+        ));
+        let config = WatermarkConfig {
+            compress: true,
+            ..WatermarkConfig::default()
+        };
+        // "hi" doesn't shrink, so it goes in stored and still comes back
+        for text in [long.as_str(), "hi"] {
+            let encoded = encode(text, &input, &config).unwrap();
+            let decoded = crate::decoder::decode_samples(&encoded, 1, RATE, &config).unwrap();
+            assert_eq!(decoded.message, text);
+        }
+    }
+
+    #[test]
+    fn attenuating_a_hot_input_leaves_room_for_the_mark() {
+        // Noise peaking at full scale: boosting its bins pushes peaks over
+        let hot: Vec<f32> = noise(RATE as usize).iter().map(|x| x * 4.0).collect();
+        let clipped = |samples: &[f32]| samples.iter().filter(|x| x.abs() > 1.0).count();
+        assert!(clipped(&encode("hi", &hot, &WatermarkConfig::default()).unwrap()) > 0);
+        let attenuated = WatermarkConfig {
+            attenuate_db: Some(12.0),
+            ..WatermarkConfig::default()
+        };
+        assert_eq!(clipped(&encode("hi", &hot, &attenuated).unwrap()), 0);
+    }
+
+    #[test]
+    fn min_snr_refuses_or_retries_a_strong_mark() {
+        let input = noise(RATE as usize);
+        let strong = WatermarkConfig {
+            strength: Some(1.5),
+            ..WatermarkConfig::default()
+        };
+        let full = report("hi", &input, strong.clone());
+        let strict = WatermarkConfig {
+            min_snr_db: Some(full.snr_db + 3.0),
+            ..strong
+        };
+        assert!(matches!(
+            encode("hi", &input, &strict),
+            Err(EncodeError::SnrTooLow { strength, .. }) if strength == 1.5
+        ));
+
+        let retried = report(
+            "hi",
+            &input,
+            WatermarkConfig {
+                snr_retry: true,
+                ..strict.clone()
+            },
+        );
+        assert!(retried.strength < 1.5 && retried.snr_db >= full.snr_db + 3.0, "{:?}", retried.strength);
+    }
+
+    #[test]
+    fn normalize_raises_a_quiet_input_to_the_target_peak() {
+        let quiet: Vec<f32> = noise(RATE as usize).iter().map(|x| x * 0.01).collect();
+        let peak = |samples: &[f32]| samples.iter().map(|x| x.abs()).fold(0.0f32, f32::max);
+        let config = WatermarkConfig {
+            normalize_input: true,
+            ..WatermarkConfig::default()
+        };
+        let message = Payload::Text("hi".to_string());
+        let (encoded, report) = encode_samples(&quiet, 1, RATE, &message, &config).unwrap();
+        let gain = report.normalize_gain.unwrap();
+        let target = 10f32.powf(NORMALIZE_PEAK_DB / 20.0);
+        assert!((peak(&quiet) * gain - target).abs() < 1e-4, "gain {}", gain);
+        // The boost moves individual peaks, but the level is now near full scale
+        assert!(peak(&encoded) > 0.5 * target, "{}", peak(&encoded));
+        assert_eq!(crate::decoder::decode_samples(&encoded, 1, RATE, &config).unwrap().message, "hi");
+
+        let restored = WatermarkConfig {
+            restore_level: true,
+            ..config
+        };
+        let (encoded, _) = encode_samples(&quiet, 1, RATE, &message, &restored).unwrap();
+        assert!(peak(&encoded) < 2.0 * peak(&quiet), "{}", peak(&encoded));
+    }
+
+    #[test]
+    fn one_encoder_serves_many_buffers_and_threads() {
+        let config = WatermarkConfig::default();
+        let encoder = std::sync::Arc::new(Encoder::new(config.clone()));
+        let inputs: Vec<Vec<f32>> = (1..=4).map(|seconds| noise(RATE as usize * seconds)).collect();
+        let message = Payload::Text("hi".to_string());
+        let one_shot: Vec<Vec<f32>> = inputs
+            .iter()
+            .map(|input| encode_samples(input, 1, RATE, &message, &config).unwrap().0)
+            .collect();
+
+        let threads: Vec<_> = inputs
+            .into_iter()
+            .map(|input| {
+                let (encoder, message) = (encoder.clone(), message.clone());
+                std::thread::spawn(move || encoder.encode(&input, 1, RATE, &message).unwrap().0)
+            })
+            .collect();
+        for (thread, expected) in threads.into_iter().zip(one_shot) {
+            assert_eq!(thread.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn encode_samples_round_trips_through_decode_samples() {
+        let config = WatermarkConfig::default();
+        let message = Payload::Text("hi".to_string());
+        // Stereo, interleaved: both calls take and return the same layout
+        let stereo: Vec<f32> = noise(RATE as usize * 2).iter().flat_map(|&x| [x, x * 0.5]).collect();
+        let (encoded, report) = encode_samples(&stereo, 2, RATE, &message, &config).unwrap();
+        assert_eq!(encoded.len(), stereo.len());
+        let decoded = crate::decoder::decode_samples(&encoded, 2, RATE, &config).unwrap();
+        assert_eq!(decoded.message, "hi");
+        assert_eq!(decoded.bits[..report.bits.len()], report.bits[..]);
+    }
+
+    #[test]
+    fn dc_offset_is_reported_and_removed() {
+        let shifted: Vec<f32> = noise(RATE as usize).iter().map(|x| x + 0.1).collect();
+        let report = report("hi", &shifted, WatermarkConfig::default());
+        assert!((report.dc_offsets[0] - 0.1).abs() < 0.01, "{:?}", report.dc_offsets);
+
+        let config = WatermarkConfig {
+            remove_dc: true,
+            ..WatermarkConfig::default()
+        };
+        let (encoded, report) = encode_samples(&shifted, 1, RATE, &Payload::Text("hi".to_string()), &config).unwrap();
+        assert!((report.dc_offsets[0] - 0.1).abs() < 0.01); // measured before removal
+        assert!(dc_offset(&encoded).abs() < 1e-3, "{}", dc_offset(&encoded));
+    }
+
+    #[test]
+    fn side_embedding_round_trips_and_is_quieter_than_left() {
+        // A correlated pair, as in most stereo: R = 0.8 L plus a little of another voice
+        let (left, other) = (noise(RATE as usize * 2), noise(RATE as usize * 3));
+        let stereo: Vec<f32> = left
+            .iter()
+            .zip(other.iter().rev())
+            .flat_map(|(&l, &o)| [l, 0.8 * l + 0.2 * o])
+            .collect();
+        let message = Payload::Text("hi".to_string());
+        // Worst of the two channels, input vs watermarked
+        let worst_snr = |encoded: &[f32]| {
+            let tracks = (deinterleave(&stereo, 2), deinterleave(encoded, 2));
+            snr_db(&tracks.0[0], &tracks.1[0]).min(snr_db(&tracks.0[1], &tracks.1[1]))
+        };
+
+        let side = WatermarkConfig {
+            channel_strategy: ChannelStrategy::MidSide(MidSideChannel::Side),
+            ..WatermarkConfig::default()
+        };
+        let (encoded, _) = encode_samples(&stereo, 2, RATE, &message, &side).unwrap();
+        let decoded = crate::decoder::decode_samples(&encoded, 2, RATE, &side).unwrap();
+        assert_eq!(decoded.message, "hi");
+        // L + R = 2M: the side watermark cancels in the mono sum
+        let mono = |samples: &[f32]| -> Vec<f32> { samples.chunks(2).map(|pair| pair[0] + pair[1]).collect() };
+        assert!(snr_db(&mono(&stereo), &mono(&encoded)) > 60.0);
+
+        let (in_left, _) = encode_samples(&stereo, 2, RATE, &message, &WatermarkConfig::default()).unwrap();
+        assert!(
+            worst_snr(&encoded) > worst_snr(&in_left),
+            "side {} dB vs left {} dB",
+            worst_snr(&encoded),
+            worst_snr(&in_left)
+        );
+    }
+
+    #[test]
+    fn nonce_makes_repeat_embeddings_differ_and_both_decode() {
+        let config = WatermarkConfig {
+            nonce: true,
+            ..WatermarkConfig::default()
+        };
+        let input = noise(RATE as usize * 2);
+        let message = Payload::Text("hi".to_string());
+        let embed_and_decode = || {
+            let (encoded, _) = encode_samples(&input, 1, RATE, &message, &config).unwrap();
+            let decoded = crate::decoder::decode_samples(&encoded, 1, RATE, &config).unwrap();
+            (encoded, decoded)
+        };
+        let (first, first_decoded) = embed_and_decode();
+        // Two 16-bit nonces collide 1 time in 65536; draw again until they don't
+        let (second, second_decoded) = iter::repeat_with(embed_and_decode)
+            .take(4)
+            .find(|(_, decoded)| decoded.nonce != first_decoded.nonce)
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first_decoded.message, "hi");
+        assert_eq!(second_decoded.message, "hi");
+    }
+
+    #[test]
+    fn non_finite_float_samples_are_zeroed_or_rejected() {
+        let path = std::env::temp_dir().join(format!("msg_encoder-test-{}-non-finite.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for sample in [0.5, f32::NAN, -0.25, f32::INFINITY, f32::NEG_INFINITY] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (samples, _) = load_and_normalize_audio(&path, &WatermarkConfig::default()).unwrap();
+        assert_eq!(samples, [0.5, 0.0, -0.25, 0.0, 0.0]);
+        let strict = WatermarkConfig {
+            non_finite: NonFiniteMode::Error,
+            ..WatermarkConfig::default()
+        };
+        let result = load_and_normalize_audio(&path, &strict);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(EncodeError::NonFiniteSamples { count: 3 })));
+    }
+
+    #[test]
+    fn limit_frames_one_changes_only_the_first_frame() {
+        let config = WatermarkConfig {
+            limit_frames: Some(1),
+            ..WatermarkConfig::default()
+        };
+        let input = noise(FRAME_LEN * 8);
+        let encoded = encode("hi", &input, &config).unwrap();
+        assert_ne!(encoded[..FRAME_LEN], input[..FRAME_LEN]);
+        assert_eq!(encoded[FRAME_LEN..], input[FRAME_LEN..]);
+    }
+
+    #[test]
+    fn default_frame_len_is_32_ms_at_the_input_rate() {
+        assert_eq!(frame_len_for(RATE, DEFAULT_WINDOW_MS), FRAME_LEN);
+        // At 16 kHz both sides frame at 512; a fixed 256 would leave the
+        // decoder reading 512-sample frames of 256-sample embeds
+        let rate = 16_000;
+        let config = WatermarkConfig::default();
+        let message = Payload::Text("hi".to_string());
+        let (encoded, report) = encode_samples(&noise(rate as usize * 2), 1, rate, &message, &config).unwrap();
+        assert_eq!(report.frame_len, 512);
+        assert_eq!(crate::decoder::decode_samples(&encoded, 1, rate, &config).unwrap().message, "hi");
+    }
+
+    // Write-only, like a pipe: anything that needs to seek back can't use it
+    struct Pipe(Vec<u8>);
+
+    impl Write for Pipe {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streamed_wav_reads_back_through_hound() {
+        for (bits, format) in [(16, SampleFormat::Int), (24, SampleFormat::Int), (32, SampleFormat::Float)] {
+            let spec = hound::WavSpec {
+                channels: 2,
+                sample_rate: RATE,
+                bits_per_sample: bits,
+                sample_format: format,
+            };
+            let samples = vec![0.5, -0.5, 1.0, -1.0, 0.0, 0.25];
+            let quantized = quantize(samples.clone(), bits, format, RoundMode::Nearest);
+            let mut pipe = Pipe(Vec::new());
+            write_wav_stream(&mut pipe, &quantized, spec).unwrap();
+            let mut reader = hound::WavReader::new(io::Cursor::new(pipe.0)).unwrap();
+            assert_eq!(reader.spec(), spec);
+            match quantized {
+                QuantizedSamples::Int(codes) => {
+                    let read: Vec<i32> = reader.samples::<i32>().map(Result::unwrap).collect();
+                    assert_eq!(read, codes, "{}-bit", bits);
+                }
+                QuantizedSamples::Float(written) => {
+                    let read: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+                    assert_eq!(read, written);
+                }
+            }
+        }
+    }
+
+    // A pipe whose reader has gone (`encode --output - | head -c 10`)
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_closed_pipe_fails_the_write() {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let quantized = quantize(vec![0.5; 64], 16, SampleFormat::Int, RoundMode::Nearest);
+        let result = write_wav_stream(ClosedPipe, &quantized, spec);
+        assert_eq!(result.map_err(|err| err.kind()), Err(io::ErrorKind::BrokenPipe));
+    }
+
+    #[test]
+    fn an_output_that_appears_before_the_write_is_kept() {
+        let path = std::env::temp_dir().join(format!("msg_encoder-test-{}-race.wav", std::process::id()));
+        std::fs::write(&path, b"not yours").unwrap(); // created after encode_file's early check
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let quantized = quantize(vec![0.5; 64], 16, SampleFormat::Int, RoundMode::Nearest);
+        let refused = write_wav_file(&path, &quantized, spec, false);
+        assert!(matches!(refused, Err(EncodeError::OutputExists { .. })), "{:?}", refused);
+        assert_eq!(std::fs::read(&path).unwrap(), b"not yours");
+
+        write_wav_file(&path, &quantized, spec, true).unwrap();
+        assert_eq!(hound::WavReader::open(&path).unwrap().len(), 64);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn int_codes(samples: &[f32], bits: u16) -> Vec<i32> {
+        match quantize(samples.to_vec(), bits, SampleFormat::Int, RoundMode::Nearest) {
+            QuantizedSamples::Int(codes) => codes,
+            QuantizedSamples::Float(_) => panic!("integer format gave float samples"),
+        }
+    }
+
+    #[test]
+    fn full_scale_and_clamping_at_each_depth() {
+        for (bits, top) in [(16, 32_767), (24, 8_388_607), (32, 2_147_483_647)] {
+            assert_eq!(
+                int_codes(&[1.0, -1.0, 0.0, 1.5, -3.0], bits),
+                [top, -top, 0, top, -top],
+                "{}-bit",
+                bits
+            );
+        }
+    }
+
+    // 16-bit codes, the encoder's default output, under `round_mode`
+    fn codes_16(samples: Vec<f32>, round_mode: RoundMode) -> Vec<i32> {
+        match quantize(samples, 16, SampleFormat::Int, round_mode) {
+            QuantizedSamples::Int(codes) => codes,
+            QuantizedSamples::Float(_) => panic!("integer format gave float samples"),
+        }
+    }
+
+    #[test]
+    fn full_scale_maps_to_plus_minus_32767() {
+        let codes = codes_16(vec![1.0, -1.0], RoundMode::Nearest);
+        assert_eq!(codes, [32_767, -32_767]); // -32768 is never produced
+    }
+
+    #[test]
+    fn out_of_range_samples_are_clamped() {
+        let codes = codes_16(vec![1.000_1, 2.0, -1.000_1, -50.0], RoundMode::Nearest);
+        assert_eq!(codes, [32_767, 32_767, -32_767, -32_767]);
+    }
+
+    #[test]
+    fn smallest_step_is_one_code() {
+        let step = 1.0 / 32_767.0;
+        let codes = codes_16(vec![step, -step, step * 0.4, step * 0.6], RoundMode::Nearest);
+        assert_eq!(codes, [1, -1, 0, 1]);
+        let codes = codes_16(vec![step * 0.6, -step * 0.6], RoundMode::Truncate);
+        assert_eq!(codes, [0, 0]);
+    }
+
+    #[test]
+    fn rounding_modes_split_half_steps_differently() {
+        let halves: Vec<f32> = [0.5, 1.5, 2.5, -0.5, -1.5].iter().map(|&x| x / 32_767.0).collect();
+        let codes = |mode| codes_16(halves.clone(), mode);
+        assert_eq!(codes(RoundMode::Nearest), [1, 2, 3, -1, -2]); // away from zero
+        assert_eq!(codes(RoundMode::HalfEven), [0, 2, 2, 0, -2]);
+        assert_eq!(codes(RoundMode::Truncate), [0, 1, 2, 0, -1]);
+    }
+
+    #[test]
+    fn float_output_is_passed_through() {
+        let samples = vec![1.5, -0.25, 0.0];
+        match quantize(samples.clone(), 32, SampleFormat::Float, RoundMode::Nearest) {
+            QuantizedSamples::Float(out) => assert_eq!(out, samples),
+            QuantizedSamples::Int(_) => panic!("float format gave integer codes"),
+        }
+    }
+
+    #[test]
+    fn one_step_at_24_bits() {
+        let step = 1.0 / 8_388_607.0;
+        assert_eq!(int_codes(&[step, -step, step * 0.4], 24), [1, -1, 0]);
     }
-    
-    writer.finalize().expect("failed to finalize wav file");
-    println!("Wrote watermarked audio to {}", output_path.display());
 }
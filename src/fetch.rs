@@ -0,0 +1,289 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// =============================================================================
+// FETCH - Download an http(s) --input to a temporary WAV file
+// =============================================================================
+//
+// Only built with the `network` feature (ureq). The response must be a WAV:
+// a content type that can carry one and a body whose header the crate's own
+// reader (wav::SampleStream, extensible headers included) accepts; anything
+// else is refused before it reaches the decoder. Redirects are followed up to
+// MAX_REDIRECTS. The body is written to a file of its own in the system temp
+// directory, so every decode mode can open it like a local --input, and the
+// file is removed when the returned Download is dropped.
+
+#[cfg(feature = "network")]
+const MAX_REDIRECTS: u32 = 5;
+
+// Larger bodies are refused rather than held in memory
+#[cfg(feature = "network")]
+const MAX_DOWNLOAD_BYTES: u64 = 512 * 1024 * 1024;
+
+// Content types a WAV is served with; a missing header is accepted too
+#[cfg(feature = "network")]
+const WAV_CONTENT_TYPES: [&str; 5] = [
+    "audio/wav",
+    "audio/x-wav",
+    "audio/wave",
+    "audio/vnd.wave",
+    "application/octet-stream",
+];
+
+#[derive(Debug)]
+#[cfg_attr(not(feature = "network"), allow(dead_code))] // only Disabled without it
+pub enum FetchError {
+    #[cfg(not(feature = "network"))]
+    Disabled,                      // built without the network feature
+    Request(String),               // connection, TLS or HTTP status failure
+    NotWav { content_type: String }, // served as something other than audio
+    BadWav(String),                // body is not a readable WAV
+    Io(std::io::Error),            // reading the body or writing the temp file
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(not(feature = "network"))]
+            FetchError::Disabled => write!(
+                f,
+                "URL inputs need the network feature; rebuild with --features network"
+            ),
+            FetchError::Request(reason) => write!(f, "request failed: {}", reason),
+            FetchError::NotWav { content_type } => {
+                write!(f, "server sent {:?}, not a WAV file", content_type)
+            }
+            FetchError::BadWav(reason) => write!(f, "downloaded file is not a valid WAV: {}", reason),
+            FetchError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+// A downloaded WAV in the temp directory, deleted on drop
+#[derive(Debug)]
+pub struct Download {
+    path: PathBuf,
+}
+
+impl Download {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Delete the file now, for callers about to process::exit (which skips
+    // the drop)
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+// Whether --input names a URL rather than a local path
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+// Download `url` to a temporary WAV
+#[cfg(feature = "network")]
+pub fn download(url: &str) -> Result<Download, FetchError> {
+    use std::io::{Cursor, Read};
+
+    use crate::wav::SampleStream;
+
+    let agent = ureq::AgentBuilder::new().redirects(MAX_REDIRECTS).build();
+    let response = agent.get(url).call().map_err(|err| FetchError::Request(err.to_string()))?;
+    if response.get_url() != url {
+        diag!("Redirected to {}", response.get_url());
+    }
+
+    // ureq reports a missing header as text/plain, so read it directly
+    if let Some(content_type) = response.header("content-type") {
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        if !WAV_CONTENT_TYPES.contains(&media_type.as_str()) {
+            return Err(FetchError::NotWav {
+                content_type: content_type.to_string(),
+            });
+        }
+    }
+
+    let mut bytes = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_DOWNLOAD_BYTES + 1)
+        .read_to_end(&mut bytes)
+        .map_err(FetchError::Io)?;
+    if bytes.len() as u64 > MAX_DOWNLOAD_BYTES {
+        return Err(FetchError::Request(format!(
+            "body is larger than {} bytes",
+            MAX_DOWNLOAD_BYTES
+        )));
+    }
+
+    // Parse the header before writing, so an HTML error page never gets that far
+    SampleStream::new(Cursor::new(&bytes)).map_err(|err| FetchError::BadWav(err.to_string()))?;
+
+    let download = write_temp(&bytes).map_err(FetchError::Io)?;
+    diag!("Downloaded {} bytes from {} to {}", bytes.len(), url, download.path.display());
+    Ok(download)
+}
+
+#[cfg(not(feature = "network"))]
+pub fn download(_url: &str) -> Result<Download, FetchError> {
+    Err(FetchError::Disabled)
+}
+
+// `bytes` in a new file that no other download (in this process or another)
+// can be using: create_new fails rather than reuse a name, and the next
+// name is tried
+#[cfg(feature = "network")]
+fn write_temp(bytes: &[u8]) -> std::io::Result<Download> {
+    use std::io::{ErrorKind, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.subsec_nanos());
+    loop {
+        let name = format!(
+            "msg_encoder-{}-{}-{}.wav",
+            std::process::id(),
+            nanos,
+            DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                let download = Download { path }; // removed again if the write fails
+                file.write_all(bytes)?;
+                return Ok(download);
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// A local std TcpListener stands in for the web server
+#[cfg(all(test, feature = "network"))]
+mod tests {
+    use std::io::{BufRead, BufReader, Cursor, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    // A 100-sample 16-bit mono WAV
+    fn wav_bytes() -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut bytes = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        for n in 0..100 {
+            writer.write_sample((n * 300) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        bytes.into_inner()
+    }
+
+    // A 100-sample 24-bit mono WAVE_FORMAT_EXTENSIBLE WAV with its redundant
+    // byte rate left at 0, as some recorders write it: hound refuses it and
+    // wav::read_file reads it
+    fn extensible_bytes() -> Vec<u8> {
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&0xfffeu16.to_le_bytes()); // WAVE_FORMAT_EXTENSIBLE
+        fmt.extend_from_slice(&1u16.to_le_bytes()); // channels
+        fmt.extend_from_slice(&8000u32.to_le_bytes()); // sample rate
+        fmt.extend_from_slice(&0u32.to_le_bytes()); // byte rate
+        fmt.extend_from_slice(&3u16.to_le_bytes()); // block align
+        fmt.extend_from_slice(&24u16.to_le_bytes()); // container bits
+        fmt.extend_from_slice(&22u16.to_le_bytes()); // cbSize
+        fmt.extend_from_slice(&24u16.to_le_bytes()); // valid bits
+        fmt.extend_from_slice(&4u32.to_le_bytes()); // channel mask: front centre
+        fmt.extend_from_slice(&[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00]); // PCM GUID
+        fmt.extend_from_slice(&[0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71]);
+        let data = vec![0u8; 300];
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(4 + 8 + fmt.len() as u32 + 8 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&fmt);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    // Serves /sample.wav, an extensible WAV at /extensible.wav, a redirect
+    // to the first at /moved, an HTML page at /page.wav and a 404 for
+    // anything else; returns the base URL
+    fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+                let (status, headers, body) = match path.as_str() {
+                    "/sample.wav" => ("200 OK", "Content-Type: audio/wav\r\n", wav_bytes()),
+                    "/extensible.wav" => ("200 OK", "Content-Type: audio/wav\r\n", extensible_bytes()),
+                    "/moved" => ("302 Found", "Location: /sample.wav\r\n", Vec::new()),
+                    "/page.wav" => ("200 OK", "Content-Type: text/html\r\n", b"<html></html>".to_vec()),
+                    _ => ("404 Not Found", "", Vec::new()),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    headers,
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).and_then(|()| stream.write_all(&body));
+            }
+        });
+        base
+    }
+
+    #[test]
+    fn download_fetches_follows_redirects_and_cleans_up() {
+        let base = serve();
+        for url in [format!("{}/sample.wav", base), format!("{}/moved", base)] {
+            let download = download(&url).unwrap();
+            let path = download.path().to_path_buf();
+            assert_eq!(fs::read(&path).unwrap(), wav_bytes(), "{}", url);
+            drop(download);
+            assert!(!path.exists(), "{} was left behind", path.display());
+        }
+        // Two downloads at once get files of their own
+        let url = format!("{}/sample.wav", base);
+        let (first, second) = (download(&url).unwrap(), download(&url).unwrap());
+        assert_ne!(first.path(), second.path());
+    }
+
+    #[test]
+    fn an_extensible_wav_downloads_as_the_decoder_reads_it() {
+        assert!(hound::WavReader::new(Cursor::new(extensible_bytes())).is_err());
+        let download = download(&format!("{}/extensible.wav", serve())).unwrap();
+        let (samples, spec) = crate::wav::read_file(download.path(), false).unwrap();
+        assert_eq!((samples.len(), spec.bits_per_sample), (100, 24));
+    }
+
+    #[test]
+    fn non_wav_and_missing_urls_are_refused() {
+        let base = serve();
+        assert!(matches!(download(&format!("{}/page.wav", base)), Err(FetchError::NotWav { .. })));
+        assert!(matches!(download(&format!("{}/gone.wav", base)), Err(FetchError::Request(_))));
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::{Arc, Mutex};
+
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+// =============================================================================
+// FFT - Plans shared by the encoder and the decoder
+// =============================================================================
+//
+// Planning an FFT costs more than running it on a short file. Encoder and
+// Decoder each keep a PlanCache, so a batch of files at one frame length is
+// planned once; the one-shot entry points make a throwaway cache per call.
+
+// Forward / inverse real FFTs for one length. Plans are immutable and shared
+// through Arc, so any number of threads can run them at once.
+#[derive(Clone)]
+pub struct FftPlans {
+    pub forward: Arc<dyn RealToComplex<f32>>,
+    pub inverse: Arc<dyn ComplexToReal<f32>>,
+}
+
+impl FftPlans {
+    // Samples each transform takes: one whole frame
+    pub fn fft_len(&self) -> usize {
+        self.forward.len()
+    }
+}
+
+// A RealFftPlanner, which remembers every plan it makes, behind a lock. The
+// lock is held only while looking plans up; the transforms run outside it.
+pub struct PlanCache {
+    planner: Mutex<RealFftPlanner<f32>>,
+}
+
+impl Default for PlanCache {
+    fn default() -> Self {
+        PlanCache::new()
+    }
+}
+
+impl PlanCache {
+    pub fn new() -> PlanCache {
+        PlanCache {
+            planner: Mutex::new(RealFftPlanner::new()),
+        }
+    }
+
+    // Plans for `fft_len`, made on first use
+    pub fn plans(&self, fft_len: usize) -> FftPlans {
+        let mut planner = self.planner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        FftPlans {
+            forward: planner.plan_fft_forward(fft_len),
+            inverse: planner.plan_fft_inverse(fft_len),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+// msg_encoder: hide a short message in audio and read it back.
+//
+// encoder::Encoder and decoder::Decoder work on in-memory sample buffers;
+// encoder::encode_sample and decoder::decode_watermarked_sample wrap them
+// for WAV files, and decoder::decode_stream reads one as it arrives. See
+// examples/embed_and_extract.rs.
+// The msg_encoder binary (src/main.rs) is a command line over this crate.
+
+// --features simd (nightly): std::simd for the 16-bit quantize
+#![cfg_attr(feature = "simd", feature(portable_simd))]
+#![cfg_attr(all(test, feature = "simd"), feature(test))]
+
+#[macro_use]
+pub mod log; // diag! output: off unless enabled, and routing
+pub mod allocate; // Bits carried by each frame
+pub mod attack; // Degradations for robustness testing
+pub mod bits; // Byte <-> bit conversion
+pub mod channels; // Multichannel (de)interleaving
+pub mod compress; // Optional payload deflate
+pub mod config; // Settings shared by encoder and decoder
+pub mod crc; // Header checksum
+pub mod decoder; // Contains all decoding logic
+pub mod encoder; // Contains all encoding logic
+pub mod fetch; // http(s) --input download (network feature)
+pub mod fft; // FFT plans shared by encoder and decoder
+pub mod payload; // Text / content ID / raw payloads
+pub mod phase; // Phase coding scheme
+pub mod polar; // Bin magnitude helpers
+pub mod resample; // Linear sample rate conversion
+pub mod scheme; // Embed / extract per algorithm
+pub mod simd; // Vector 16-bit quantize
+pub mod spectrogram; // STFT image for visual debugging
+pub mod stuffing; // Bit stuffing for sentinel framing
+pub mod tag; // Keyed payload tag
+pub mod vad; // Frames too quiet to carry the watermark
+pub mod varint; // Variable-length length field
+pub mod watermark; // Embedded bit stream layout
+pub mod wav; // WAV sample reading
+pub mod whitening; // Run-breaking mask over the framed bits
+pub mod window; // Analysis windows
+
@@ -0,0 +1,100 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// =============================================================================
+// DIAGNOSTICS - Progress output that machine-readable modes can move aside
+// =============================================================================
+//
+// Off unless enable() is called: the command line turns it on, while a
+// program using the library gets no output it didn't ask for.
+
+// When set, diag! prints at all
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// When set, diag! writes to stderr so stdout only carries the result (e.g. JSON)
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn route_to_stderr(enabled: bool) {
+    TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn routed_to_stderr() -> bool {
+    TO_STDERR.load(Ordering::Relaxed)
+}
+
+thread_local! {
+    // This thread's live Muted guards; diag! prints nothing here while any
+    // exists, and other threads go on printing
+    static MUTED: Cell<usize> = const { Cell::new(0) };
+}
+
+// Silences diag! on this thread until dropped, for searches that run a
+// decode hundreds of times and would bury their result under its progress
+// lines. Not Send: it has to be dropped on the thread that made it.
+pub struct Muted(PhantomData<*const ()>);
+
+impl Muted {
+    pub fn new() -> Muted {
+        MUTED.with(|muted| muted.set(muted.get() + 1));
+        Muted(PhantomData)
+    }
+}
+
+impl Default for Muted {
+    fn default() -> Self {
+        Muted::new()
+    }
+}
+
+impl Drop for Muted {
+    fn drop(&mut self) {
+        MUTED.with(|muted| muted.set(muted.get() - 1));
+    }
+}
+
+pub fn muted() -> bool {
+    MUTED.with(|muted| muted.get() > 0)
+}
+
+// println! for diagnostics: nothing until enabled, then stdout normally and
+// stderr when routed aside
+#[macro_export]
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        if !$crate::log::enabled() || $crate::log::muted() {
+        } else if $crate::log::routed_to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn a_guard_mutes_only_its_own_thread() {
+        let outer = Muted::new();
+        {
+            let _inner = Muted::new();
+            assert!(muted());
+        }
+        assert!(muted(), "the outer guard still holds");
+        assert!(!thread::spawn(muted).join().unwrap());
+        drop(outer);
+        assert!(!muted());
+    }
+}
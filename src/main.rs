@@ -1,9 +1,24 @@
 // Import the standard library's environment module for reading command-line arguments
 use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-// Import modules we defined in separate files
-mod decoder; // Contains all decoding logic
-mod encoder; // Contains all encoding logic
+// The library does the work (src/lib.rs); this file is the command line
+use msg_encoder::{attack, config, decoder, diag, encoder, fetch, log, payload, spectrogram, window};
+
+use attack::Attack;
+use config::{
+    BinSpacing, ChannelStrategy, EmbedMode, FreqBand, Framing, NonFiniteMode, Offset,
+    OutputFormat, RoundMode, Scheme, WatermarkConfig,
+};
+use payload::Payload;
+use window::Window;
 
 // =============================================================================
 // Entry point - runs encode or decode based on command
@@ -12,18 +27,348 @@ mod encoder; // Contains all encoding logic
 fn main() {
     // Collect all command-line arguments into a vector (first arg is program name)
     let args: Vec<String> = env::args().collect();
+    log::enable(); // the library is silent unless asked
+
+    let config = parse_config(&args);
 
     // Match on the first argument to determine what mode we're in
     match args[1].as_str() {
         // If user wants to encode the message
         "encode" => {
-            encoder::encode_sample("hi");
+            let output = flag_value(&args, "--output")
+                .map(PathBuf::from)
+                .unwrap_or_else(encoder::default_output_path);
+            // --output - streams the WAV to stdout; diagnostics move to stderr
+            log::route_to_stderr(output == Path::new("-"));
+            let (input, download) = input_path(&args);
+            let input = input.unwrap_or_else(encoder::default_input_path);
+
+            // Under --channel-map the first channel's message stands in for
+            // the per-message reports (--bins-used)
+            let message = config
+                .channel_messages
+                .first()
+                .cloned()
+                .unwrap_or_else(|| encode_payload(&args));
+
+            let report = encoder::encode_sample(&message, &input, &output, &config)
+                .unwrap_or_else(|err| {
+                    eprintln!("encode failed: {}", err);
+                    download.iter().for_each(fetch::Download::remove); // exit skips the drop
+                    process::exit(1);
+                });
+            let timings = report.timings;
+
+            // --verbose: where the time went, step by step
+            if args.iter().any(|arg| arg == "--verbose") {
+                diag!("\n=== Timings ===");
+                diag!("load:       {:?}", timings.load);
+                diag!("build bits: {:?}", timings.build_bits);
+                diag!("embed:      {:?}", timings.embed);
+                diag!("quantize:   {:?}", timings.quantize);
+                diag!("write:      {:?}", timings.write);
+                diag!("total:      {:?}", timings.total());
+
+                diag!("\n=== Latency ===");
+                diag!(
+                    "algorithmic: {} samples ({}-sample frames)",
+                    report.latency, report.frame_len
+                );
+
+                diag!("\n=== DC offset (input mean per channel) ===");
+                for (channel, offset) in report.dc_offsets.iter().enumerate() {
+                    diag!("channel {}: {:+.6}", channel, offset);
+                }
+
+                if let Some(gain) = report.normalize_gain {
+                    diag!("\n=== Normalization ===");
+                    diag!("gain: {:.4} ({:+.1} dB)", gain, 20.0 * gain.log10());
+                }
+            }
+
+            // --bins-used: where in the spectrogram the watermark lives
+            if args.iter().any(|arg| arg == "--bins-used") {
+                let used = &report.bins_used;
+                diag!("\n=== Bins used ({} frame/bin pairs) ===", used.len());
+                for (frame, bin) in used {
+                    diag!("{} {}", frame, bin);
+                }
+            }
+
+            // --dump-bits <file>: the embedded bit sequence, to diff against a decode
+            if let Some(path) = flag_value(&args, "--dump-bits") {
+                write_bits(path, &report.bits);
+            }
+
+            // --bin-stats: mean / max magnitude change per bin, with a bar per bin
+            if args.iter().any(|arg| arg == "--bin-stats") {
+                match &report.bin_stats {
+                    Some(stats) => print_bin_stats(stats),
+                    None => diag!("\nNo bin stats: phase coding leaves the magnitudes alone"),
+                }
+            }
         }
 
         // If user wants to decode a watermark
         "decode" => {
-            // Decode the watermark from the default path
-            decoder::decode_watermarked_sample(&decoder::default_watermarked_path());
+            // --json keeps stdout for the report; diagnostics move to stderr
+            let json = args.iter().any(|arg| arg == "--json");
+            // --out <file> writes the recovered bytes verbatim ("-" for stdout)
+            let out = flag_value(&args, "--out");
+            if json && out == Some("-") {
+                eprintln!("--json and --out - both write to stdout; pick one");
+                process::exit(1);
+            }
+            log::route_to_stderr(json || out == Some("-"));
+
+            // Decode the watermark from --input, or the default path
+            let (input, download) = input_path(&args);
+            let input = input.unwrap_or_else(decoder::default_watermarked_path);
+
+            // --per-channel: every channel is a watermark of its own (--channel-map)
+            if args.iter().any(|arg| arg == "--per-channel") {
+                print_per_channel(&input, json, &config);
+                return;
+            }
+
+            // --candidates N: every plausible message with up to N bits flipped
+            if let Some(value) = flag_value(&args, "--candidates") {
+                let max_flips = parse_number::<usize>(value, "--candidates");
+                log::route_to_stderr(true); // stdout carries only the list
+                print_candidates(&input, max_flips, &config);
+                return;
+            }
+
+            // --all: every copy --repeat-every left in the file
+            if args.iter().any(|arg| arg == "--all") {
+                print_all_copies(&input, json, &config);
+                return;
+            }
+
+            let cancel = decode_timeout(&args); // stops --robust / --resync / --align
+            let mut channel_confidences = None; // --channels-independent only
+            // --averaged trades the per-frame pilot gate for a mean over all frames
+            let result = if args.iter().any(|arg| arg == "--averaged") {
+                decoder::decode_averaged(&input, &config).map(|decoded| (decoded, 0))
+            } else if args.iter().any(|arg| arg == "--robust") {
+                // --robust combines the alignment search with normalised averaging
+                decoder::decode_robust(&input, &config, &cancel)
+                    .map(|(decoded, alignment)| (decoded, alignment.offset))
+            } else if args.iter().any(|arg| arg == "--resync") {
+                // --resync re-aligns every few frames to follow a tempo change
+                decoder::decode_resync(&input, &config, &cancel).map(|decoded| (decoded, 0))
+            } else if args.iter().any(|arg| arg == "--channels-independent") {
+                // --channels-independent decodes each channel and votes per bit
+                decoder::decode_channels_independent(&input, &config).map(|(decoded, confidences)| {
+                    channel_confidences = Some(confidences);
+                    (decoded, 0)
+                })
+            } else if args.iter().any(|arg| arg == "--align") {
+                // --align searches a few sub-frame offsets before decoding
+                decoder::decode_best_alignment(&input, &config, &cancel)
+                    .map(|(decoded, alignment)| (decoded, alignment.offset))
+            } else {
+                decoder::decode_watermarked_sample(&input, &config).map(|decoded| (decoded, 0))
+            };
+            let (decoded, offset) = result.unwrap_or_else(|err| {
+                eprintln!("decode failed: {}", err);
+                // The stream doesn't record how it was embedded, so a format
+                // mismatch looks exactly like a file with no watermark
+                if matches!(err, decoder::DecodeError::NoWatermark) {
+                    eprintln!(
+                        "hint: if the file is watermarked, decode with the same --scheme, \
+                         --band, --framing, --window, --guard, --overlap, \
+                         --window-duration-ms and --nonce the encoder used"
+                    );
+                }
+                // A wrong --band is the usual cause; see where the marks really are
+                if matches!(
+                    err,
+                    decoder::DecodeError::NoWatermark | decoder::DecodeError::NoReliableFrames
+                ) {
+                    if let Ok(Some(band)) = decoder::detect_band(&input, &config) {
+                        eprintln!(
+                            "hint: bins {}-{} look watermarked; try --band {}-{}",
+                            band.low_bin, band.high_bin, band.low_bin, band.high_bin
+                        );
+                    }
+                }
+                download.iter().for_each(fetch::Download::remove); // exit skips the drop
+                process::exit(1);
+            });
+
+            // With --expect, score the recovered bits against a known payload
+            let ber = flag_value(&args, "--expect").map(|expected| {
+                let expected_bits = decoder::expected_bits(expected, &config, decoded.nonce);
+                let ber = decoder::bit_error_rate(&decoded.bits, &expected_bits);
+                diag!(
+                    "Bit error rate against {:?}: {:.4} ({} bits compared)",
+                    expected,
+                    ber,
+                    expected_bits.len()
+                );
+                // Broadband damage (requantization, added noise) costs
+                // single frames bits that a mean over all frames keeps
+                let modes = ["--averaged", "--robust", "--resync", "--align", "--channels-independent"];
+                if ber > 0.0 && !args.iter().any(|arg| modes.contains(&arg.as_str())) {
+                    diag!("hint: --averaged decodes from the mean of all frames, which often recovers these bits");
+                }
+                ber
+            });
+
+            // --dump-bits <file>: every decided bit, in the encoder's dump format
+            if let Some(path) = flag_value(&args, "--dump-bits") {
+                write_bits(path, &decoded.bits);
+            }
+
+            // --trace <file>: one JSON line per frame for forensic review
+            if let Some(trace_path) = flag_value(&args, "--trace") {
+                write_trace(&input, trace_path, offset, &config);
+            }
+
+            match out {
+                Some("-") => {
+                    let mut stdout = std::io::stdout().lock();
+                    stdout
+                        .write_all(&decoded.raw_bytes)
+                        .and_then(|()| stdout.flush())
+                        .unwrap_or_else(|err| {
+                            eprintln!("failed to write payload to stdout: {}", err);
+                            process::exit(1);
+                        });
+                }
+                Some(path) => {
+                    fs::write(path, &decoded.raw_bytes).unwrap_or_else(|err| {
+                        eprintln!("failed to write payload to {}: {}", path, err);
+                        process::exit(1);
+                    });
+                    diag!("Wrote {} payload bytes to {}", decoded.raw_bytes.len(), path);
+                }
+                None => {}
+            }
+
+            if json {
+                let mut report = decoder::DecodeReport::new(&decoded, offset, ber, config.scheme);
+                report.channel_confidences = channel_confidences;
+                println!(
+                    "{}",
+                    serde_json::to_string(&report).expect("failed to serialise report")
+                );
+            }
+        }
+
+        // Render a spectrogram PNG: spectrogram <input.wav> <output.png>
+        "spectrogram" => {
+            let (Some(input), Some(output)) = (args.get(2), args.get(3)) else {
+                eprintln!("usage: spectrogram <input.wav> <output.png> [--band ...]");
+                process::exit(1);
+            };
+            let (width, height) =
+                spectrogram::render(input.as_ref(), output.as_ref(), &config).unwrap_or_else(|err| {
+                    eprintln!("spectrogram failed: {}", err);
+                    process::exit(1);
+                });
+            println!("Wrote {}x{} spectrogram to {}", width, height, output);
+        }
+
+        // attack <input.wav> <output.wav> --requantize BITS: damage a file on purpose
+        "attack" => {
+            let (Some(input), Some(output)) = (args.get(2), args.get(3)) else {
+                eprintln!("usage: attack <input.wav> <output.wav> --requantize BITS");
+                process::exit(1);
+            };
+            let Some(value) = flag_value(&args, "--requantize") else {
+                eprintln!("attack needs an attack to apply: --requantize BITS");
+                process::exit(1);
+            };
+            let bits: u32 = parse_number(value, "--requantize");
+            if !(1..=16).contains(&bits) {
+                eprintln!("--requantize expects 1 to 16 bits");
+                process::exit(1);
+            }
+            attack::attack_file(input.as_ref(), output.as_ref(), |samples| {
+                attack::requantize(samples, bits)
+            })
+            .unwrap_or_else(|err| {
+                eprintln!("attack failed: {}", err);
+                process::exit(1);
+            });
+            println!("Requantized {} to {} bits and wrote {}", input, bits, output);
+        }
+
+        // footprint <original.wav> <watermarked.wav>: long-term spectrum change per bin
+        "footprint" => {
+            let (Some(original), Some(modified)) = (args.get(2), args.get(3)) else {
+                eprintln!("usage: footprint <original.wav> <watermarked.wav> [--band ...]");
+                process::exit(1);
+            };
+            let load = |path: &String| {
+                decoder::load_audio(path.as_ref(), &config).unwrap_or_else(|err| {
+                    eprintln!("footprint failed: {}", err);
+                    process::exit(1);
+                })
+            };
+            let (original, rate) = load(original);
+            let (modified, modified_rate) = load(modified);
+            if rate != modified_rate {
+                eprintln!("footprint failed: sample rates differ ({} vs {} Hz)", rate, modified_rate);
+                process::exit(1);
+            }
+            let footprint = spectrogram::spectral_footprint(&original, &modified, rate, &config);
+
+            let band = decoder::decoder_band(&config, rate);
+            let largest = |in_band: bool| {
+                footprint
+                    .iter()
+                    .enumerate()
+                    .filter(|(bin, _)| (band.low_bin..band.high_bin).contains(bin) == in_band)
+                    .map(|(_, &(_, db))| db.abs())
+                    .fold(0.0f32, f32::max)
+            };
+            println!("=== Spectral footprint (dB, watermarked vs original) ===");
+            for (hz, db) in &footprint {
+                println!("{:8.1} Hz  {:+7.2} dB", hz, db);
+            }
+            println!("Largest change inside bins {}-{}: {:.2} dB", band.low_bin, band.high_bin, largest(true));
+            println!("Largest change outside the band: {:.2} dB", largest(false));
+        }
+
+        // robustness <input.wav> <message>: bit error rate across a sweep of added noise
+        "robustness" => {
+            let (Some(input), Some(text)) = (args.get(2), args.get(3)) else {
+                eprintln!("usage: robustness <input.wav> <message> [--band ...]");
+                process::exit(1);
+            };
+            log::route_to_stderr(true); // stdout carries only the curve
+            print_noise_sweep(input.as_ref(), text, &config);
+        }
+
+        // verify <input.wav> <message> --attack NAME:VALUE [--attack ...]:
+        // encode, damage, decode, one row per attack
+        "verify" => {
+            let (Some(input), Some(text)) = (args.get(2), args.get(3)) else {
+                eprintln!("usage: verify <input.wav> <message> --attack NAME:VALUE [--attack ...]");
+                process::exit(1);
+            };
+            let attacks: Vec<(&str, Attack)> = args
+                .windows(2)
+                .filter(|pair| pair[0] == "--attack")
+                .map(|pair| {
+                    let attack = Attack::parse(&pair[1]).unwrap_or_else(|err| {
+                        eprintln!("{}", err);
+                        process::exit(1);
+                    });
+                    (pair[1].as_str(), attack)
+                })
+                .collect();
+            if attacks.is_empty() {
+                eprintln!("verify needs at least one --attack NAME:VALUE, like noise:20");
+                process::exit(1);
+            }
+            log::route_to_stderr(true); // stdout carries only the matrix
+            if !print_attack_matrix(input.as_ref(), text, &attacks, &config) {
+                process::exit(1);
+            }
         }
 
         // If user provided an unknown option
@@ -32,3 +377,580 @@ fn main() {
         }
     }
 }
+
+// decode --per-channel: one result per channel, as text or a JSON array.
+// Fails only when no channel decodes.
+fn print_per_channel(input: &Path, json: bool, config: &WatermarkConfig) {
+    let results = decoder::decode_per_channel(input, config).unwrap_or_else(|err| {
+        eprintln!("decode failed: {}", err);
+        process::exit(1);
+    });
+
+    diag!("\n=== Per-channel results ===");
+    let mut reports = Vec::new();
+    for (channel, result) in &results {
+        match result {
+            Ok(decoded) => {
+                diag!("channel {}: {:?}", channel, decoded.message);
+                reports.push(decoder::ChannelReport {
+                    channel: *channel,
+                    report: Some(decoder::DecodeReport::new(decoded, 0, None, config.scheme)),
+                    error: None,
+                });
+            }
+            Err(err) => {
+                diag!("channel {}: decode failed: {}", channel, err);
+                reports.push(decoder::ChannelReport {
+                    channel: *channel,
+                    report: None,
+                    error: Some(err.to_string()),
+                });
+            }
+        }
+    }
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&reports).expect("failed to serialise report")
+        );
+    }
+    if results.iter().all(|(_, result)| result.is_err()) {
+        process::exit(1);
+    }
+}
+
+// One "score message" line per candidate, best first
+fn print_candidates(input: &Path, max_flips: usize, config: &WatermarkConfig) {
+    let candidates = decoder::decode_candidates_file(input, config, max_flips).unwrap_or_else(|err| {
+        eprintln!("decode failed: {}", err);
+        process::exit(1);
+    });
+    diag!("\n=== Candidates ({}) ===", candidates.len());
+    for (message, score) in &candidates {
+        println!("{:.3}  {:?}", score, message);
+    }
+    if candidates.is_empty() {
+        process::exit(1);
+    }
+}
+
+// One line (or --json report) per copy decode_all finds, with its offset
+fn print_all_copies(input: &Path, json: bool, config: &WatermarkConfig) {
+    let copies = decoder::decode_all(input, config).unwrap_or_else(|err| {
+        eprintln!("decode failed: {}", err);
+        process::exit(1);
+    });
+
+    diag!("\n=== Copies found ===");
+    for (offset, decoded) in &copies {
+        diag!("sample {}: {:?}", offset, decoded.message);
+    }
+    if json {
+        let reports: Vec<decoder::DecodeReport> = copies
+            .iter()
+            .map(|(offset, decoded)| decoder::DecodeReport::new(decoded, *offset, None, config.scheme))
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string(&reports).expect("failed to serialise report")
+        );
+    }
+}
+
+// Encode `text` into `input` in memory, then decode copies with noise added
+// at each attack::NOISE_SWEEP_DB level and print the bit error rate of each
+fn print_noise_sweep(input: &Path, text: &str, config: &WatermarkConfig) {
+    let message = Payload::Text(text.to_string());
+    let (samples, spec) = encoder::load_and_normalize_audio(input, config).unwrap_or_else(|err| {
+        eprintln!("robustness failed: {}", err);
+        process::exit(1);
+    });
+    let (encoded, _) = encoder::encode_samples(&samples, spec.channels, spec.sample_rate, &message, config)
+        .unwrap_or_else(|err| {
+            eprintln!("robustness failed: {}", err);
+            process::exit(1);
+        });
+
+    let decoder = decoder::Decoder::new(config.clone());
+    println!("=== Bit error rate vs added noise ({:?}) ===", text);
+    println!("  SNR dB  BER");
+    for snr_db in attack::NOISE_SWEEP_DB {
+        let noisy = attack::add_noise(&encoded, snr_db, attack::NOISE_SEED);
+        match decoder.decode(&noisy, spec.channels, spec.sample_rate) {
+            Ok(decoded) => {
+                let expected = decoder::expected_bits(text, config, decoded.nonce);
+                let ber = decoder::bit_error_rate(&decoded.bits, &expected);
+                println!("{:>8.1}  {:.4}", snr_db, ber);
+            }
+            Err(err) => println!("{:>8.1}  no decode ({})", snr_db, err),
+        }
+    }
+}
+
+// Encode `text` into `input` once, then decode it after each attack. A row
+// passes when the message comes back intact with its CRC (if any) matching.
+// True when every row passed.
+fn print_attack_matrix(
+    input: &Path,
+    text: &str,
+    attacks: &[(&str, Attack)],
+    config: &WatermarkConfig,
+) -> bool {
+    let message = Payload::Text(text.to_string());
+    let (samples, spec) = encoder::load_and_normalize_audio(input, config).unwrap_or_else(|err| {
+        eprintln!("verify failed: {}", err);
+        process::exit(1);
+    });
+    let (encoded, _) = encoder::encode_samples(&samples, spec.channels, spec.sample_rate, &message, config)
+        .unwrap_or_else(|err| {
+            eprintln!("verify failed: {}", err);
+            process::exit(1);
+        });
+
+    let decoder = decoder::Decoder::new(config.clone());
+    println!("=== Attack matrix ({:?}) ===", text);
+    println!("attack              BER     CRC   result");
+    let mut all_passed = true;
+    for (name, attack) in attacks {
+        let damaged = attack.apply(&encoded, spec.channels, spec.sample_rate);
+        let passed = match decoder.decode(&damaged, spec.channels, spec.sample_rate) {
+            Ok(decoded) => {
+                let expected = decoder::expected_bits(text, config, decoded.nonce);
+                let ber = decoder::bit_error_rate(&decoded.bits, &expected);
+                let crc = match decoded.crc_ok {
+                    Some(true) => "ok",
+                    Some(false) => "bad",
+                    None => "-",
+                };
+                let passed = decoded.message == text && decoded.crc_ok != Some(false);
+                let result = if passed { "pass" } else { "FAIL" };
+                println!("{:<18}  {:.4}  {:<4}  {}", name, ber, crc, result);
+                passed
+            }
+            Err(err) => {
+                println!("{:<18}  -       -     FAIL ({})", name, err);
+                false
+            }
+        };
+        all_passed &= passed;
+    }
+    all_passed
+}
+
+// Write decoder::trace_frames as JSON lines; phase coding has no per-frame gate
+fn write_trace(input: &Path, trace_path: &str, offset: usize, config: &WatermarkConfig) {
+    if !config.scheme.algorithm().repeats_every_frame() {
+        diag!("--trace needs a scheme that repeats per frame; no trace written");
+        return;
+    }
+    let traces = decoder::trace_frames(input, offset, config).unwrap_or_else(|err| {
+        eprintln!("trace failed: {}", err);
+        process::exit(1);
+    });
+    let mut lines = String::new();
+    for trace in &traces {
+        lines.push_str(&serde_json::to_string(trace).expect("failed to serialise trace"));
+        lines.push('\n');
+    }
+    fs::write(trace_path, lines).unwrap_or_else(|err| {
+        eprintln!("failed to write trace to {}: {}", trace_path, err);
+        process::exit(1);
+    });
+    diag!("Wrote {} frame records to {}", traces.len(), trace_path);
+}
+
+// One ASCII '0' / '1' per bit, nothing else, so an encoder dump and a decoder
+// dump line up under `cmp`: the first differing byte is the first wrong bit.
+// The decoder decides every bin in the band, so on a clean decode its dump
+// only runs on past the end of the encoder's.
+fn write_bits(path: &str, bits: &[u8]) {
+    let text: String = bits.iter().map(|&bit| if bit == 1 { '1' } else { '0' }).collect();
+    fs::write(path, text).unwrap_or_else(|err| {
+        eprintln!("failed to write bits to {}: {}", path, err);
+        process::exit(1);
+    });
+    diag!("Wrote {} bits to {}", bits.len(), path);
+}
+
+// Histogram of per-bin changes; bins the encoder never touched are skipped
+fn print_bin_stats(stats: &encoder::BinStats) {
+    let peak = (0..stats.bins()).map(|bin| stats.mean(bin)).fold(0.0f32, f32::max);
+    diag!("\n=== Bin modification (mean / max per frame) ===");
+    for bin in (0..stats.bins()).filter(|&bin| stats.max(bin) > 0.0) {
+        let bar = if peak > 0.0 {
+            (stats.mean(bin) / peak * 40.0).round() as usize
+        } else {
+            0
+        };
+        diag!(
+            "bin {:>3}: {:.6} / {:.6} {}",
+            bin,
+            stats.mean(bin),
+            stats.max(bin),
+            "#".repeat(bar)
+        );
+    }
+}
+
+// Build the shared watermark configuration from command-line flags
+fn parse_config(args: &[String]) -> WatermarkConfig {
+    let mut config = WatermarkConfig::default();
+
+    if let Some(name) = flag_value(args, "--scheme") {
+        config.scheme = Scheme::from_name(name).unwrap_or_else(|| {
+            eprintln!("unknown scheme {:?} (expected amplitude or phase)", name);
+            process::exit(1);
+        });
+    }
+
+    if let Some(name) = flag_value(args, "--framing") {
+        config.framing = Framing::from_name(name).unwrap_or_else(|| {
+            eprintln!("unknown framing {:?} (expected length, sentinel or varint)", name);
+            process::exit(1);
+        });
+    }
+
+    if let Some(name) = flag_value(args, "--window") {
+        config.window = Window::from_name(name).unwrap_or_else(|| {
+            eprintln!(
+                "unknown window {:?} (expected rectangular, hann, hamming or blackman)",
+                name
+            );
+            process::exit(1);
+        });
+    }
+
+    if let Some(name) = flag_value(args, "--band") {
+        config.band = FreqBand::from_name(name).unwrap_or_else(|| {
+            eprintln!("unknown band {:?} (expected full, speech or LOW-HIGH bins)", name);
+            process::exit(1);
+        });
+    }
+
+    // After --band, which resets the spacing to linear
+    if let Some(name) = flag_value(args, "--bin-spacing") {
+        config.band.spacing = BinSpacing::from_name(name).unwrap_or_else(|| {
+            eprintln!("unknown bin spacing {:?} (expected linear or log)", name);
+            process::exit(1);
+        });
+    }
+
+    if let Some(value) = flag_value(args, "--guard") {
+        config.guard = parse_number(value, "--guard");
+        if config.guard == 1 {
+            eprintln!("--guard 1 would leave no data bins (use 0 for none, or 2 and up)");
+            process::exit(1);
+        }
+    }
+
+    if let Some(value) = flag_value(args, "--bits-per-frame") {
+        let cap: usize = parse_number(value, "--bits-per-frame");
+        if cap == 0 {
+            eprintln!("--bits-per-frame expects at least one bit");
+            process::exit(1);
+        }
+        config.max_bits_per_frame = Some(cap);
+    }
+
+    // The frame length this gives is checked once the sample rate is known
+    if let Some(value) = flag_value(args, "--window-duration-ms") {
+        let ms: f32 = parse_number(value, "--window-duration-ms");
+        if !ms.is_finite() || ms <= 0.0 {
+            eprintln!("--window-duration-ms expects a positive duration, got {}", ms);
+            process::exit(1);
+        }
+        config.window_ms = Some(ms);
+    }
+
+    if let Some(value) = flag_value(args, "--overlap") {
+        config.overlap = parse_number(value, "--overlap");
+        if !(0.0..1.0).contains(&config.overlap) {
+            eprintln!("--overlap expects a fraction in [0, 1), got {}", config.overlap);
+            process::exit(1);
+        }
+    }
+
+    // --channels 0,2: watermark channels 0 and 2; decode reads channel 0
+    if let Some(value) = flag_value(args, "--channels") {
+        config.channels = value
+            .split(',')
+            .map(|channel| parse_number(channel.trim(), "--channels"))
+            .collect();
+    }
+
+    // --channel-map 0=hello,1=world: each channel carries a message of its own
+    if let Some(value) = flag_value(args, "--channel-map") {
+        let (channels, messages): (Vec<usize>, Vec<Payload>) = value
+            .split(',')
+            .map(|entry| {
+                let Some((channel, text)) = entry.split_once('=') else {
+                    eprintln!("--channel-map expects CHANNEL=MESSAGE entries, got {:?}", entry);
+                    process::exit(1);
+                };
+                (parse_number::<usize>(channel.trim(), "--channel-map"), Payload::Text(text.to_string()))
+            })
+            .unzip();
+        if let Some(channel) = channels.iter().enumerate().find_map(|(index, channel)| {
+            channels[..index].contains(channel).then_some(channel)
+        }) {
+            eprintln!("channel {} appears twice in --channel-map", channel);
+            process::exit(1);
+        }
+        config.channels = channels;
+        config.channel_messages = messages;
+    }
+
+    if let Some(name) = flag_value(args, "--mid-side") {
+        config.channel_strategy = ChannelStrategy::from_name(name).unwrap_or_else(|| {
+            eprintln!("unknown mid/side channel {:?} (expected mid, side or discrete)", name);
+            process::exit(1);
+        });
+        if matches!(config.channel_strategy, ChannelStrategy::MidSide(_))
+            && !config.channel_messages.is_empty()
+        {
+            eprintln!("--channel-map names discrete channels; it can't be combined with --mid-side");
+            process::exit(1);
+        }
+    }
+
+    config.allow_truncated = args.iter().any(|arg| arg == "--allow-truncated");
+
+    if let Some(value) = flag_value(args, "--resample") {
+        let rate: u32 = parse_number(value, "--resample");
+        if rate == 0 {
+            eprintln!("--resample expects a sample rate in Hz");
+            process::exit(1);
+        }
+        config.resample_to = Some(rate);
+    }
+
+    // --output-format bits=24,channels=2,rate=44100 (any subset)
+    if let Some(value) = flag_value(args, "--output-format") {
+        let format = OutputFormat::parse(value).unwrap_or_else(|| {
+            eprintln!(
+                "--output-format expects bits=16|24|32 (or float), channels=N and/or \
+                 rate=HZ separated by commas, got {:?}",
+                value
+            );
+            process::exit(1);
+        });
+        if let Some(rate) = format.rate {
+            if config.resample_to.is_some_and(|resample| resample != rate) {
+                eprintln!("--output-format rate={} contradicts --resample", rate);
+                process::exit(1);
+            }
+            config.resample_to = Some(rate);
+        }
+        config.output_format = format;
+    }
+
+    if let Some(value) = flag_value(args, "--input-offset-samples") {
+        config.embed_offset = parse_number(value, "--input-offset-samples");
+    }
+
+    // --since 48000 (samples) or --since 6s (seconds)
+    if let Some(value) = flag_value(args, "--since") {
+        config.since = Some(Offset::parse(value).unwrap_or_else(|| {
+            eprintln!("--since expects a sample offset or seconds like 1.5s, got {:?}", value);
+            process::exit(1);
+        }));
+    }
+
+    if let Some(value) = flag_value(args, "--threshold") {
+        let threshold: f32 = parse_number(value, "--threshold");
+        if !threshold.is_finite() {
+            eprintln!("--threshold expects a finite score");
+            process::exit(1);
+        }
+        config.threshold = Some(threshold);
+    }
+
+    if let Some(value) = flag_value(args, "--limit-frames") {
+        let limit: usize = parse_number(value, "--limit-frames");
+        if limit == 0 {
+            eprintln!("--limit-frames expects at least one frame");
+            process::exit(1);
+        }
+        config.limit_frames = Some(limit);
+    }
+
+    if let Some(value) = flag_value(args, "--threads") {
+        let threads: usize = parse_number(value, "--threads");
+        if threads == 0 {
+            eprintln!("--threads expects at least one thread");
+            process::exit(1);
+        }
+        config.threads = Some(threads);
+    }
+
+    if let Some(value) = flag_value(args, "--max-message-bytes") {
+        config.max_message_bytes = Some(parse_number(value, "--max-message-bytes"));
+    }
+
+    if let Some(value) = flag_value(args, "--trim-silence") {
+        config.trim_silence_db = Some(parse_number(value, "--trim-silence"));
+    }
+    config.repad_silence = args.iter().any(|arg| arg == "--repad");
+
+    if let Some(value) = flag_value(args, "--pad-to-seconds") {
+        config.pad_to_seconds = Some(parse_number(value, "--pad-to-seconds"));
+    }
+    config.pad_loop = args.iter().any(|arg| arg == "--pad-loop");
+    config.remove_dc = args.iter().any(|arg| arg == "--remove-dc");
+    config.normalize_input = args.iter().any(|arg| arg == "--normalize");
+    config.restore_level = args.iter().any(|arg| arg == "--restore-level");
+    config.nonce = args.iter().any(|arg| arg == "--nonce");
+    // A content ID or raw bytes only make sense with the type tag
+    config.typed_payload = ["--typed", "--content-id", "--raw-hex"]
+        .iter()
+        .any(|flag| args.iter().any(|arg| arg == flag));
+    config.key = flag_value(args, "--key").map(|key| key.as_bytes().to_vec());
+    config.compress = args.iter().any(|arg| arg == "--compress");
+
+    if let Some(value) = flag_value(args, "--attenuate-input") {
+        config.attenuate_db = Some(parse_number(value, "--attenuate-input"));
+    }
+
+    if let Some(value) = flag_value(args, "--min-snr-db") {
+        config.min_snr_db = Some(parse_number(value, "--min-snr-db"));
+    }
+    config.snr_retry = args.iter().any(|arg| arg == "--snr-retry");
+
+    if let Some(value) = flag_value(args, "--strength") {
+        let strength: f32 = parse_number(value, "--strength");
+        if !strength.is_finite() || strength <= 0.0 {
+            eprintln!("--strength expects a positive number (1 is full strength)");
+            process::exit(1);
+        }
+        config.strength = Some(strength);
+    }
+
+    if let Some(value) = flag_value(args, "--ramp-frames") {
+        config.ramp_frames = parse_number(value, "--ramp-frames");
+    }
+
+    if let Some(value) = flag_value(args, "--vad-threshold-db") {
+        config.vad_threshold_db = Some(parse_number(value, "--vad-threshold-db"));
+    }
+
+    config.headroom = args.iter().any(|arg| arg == "--no-clip");
+
+    if let Some(name) = flag_value(args, "--non-finite") {
+        config.non_finite = NonFiniteMode::from_name(name).unwrap_or_else(|| {
+            eprintln!("unknown non-finite mode {:?} (expected zero or error)", name);
+            process::exit(1);
+        });
+    }
+
+    config.overwrite = args.iter().any(|arg| arg == "--force");
+
+    if let Some(name) = flag_value(args, "--embed-mode") {
+        config.embed_mode = EmbedMode::from_name(name).unwrap_or_else(|| {
+            eprintln!("unknown embed mode {:?} (expected watermark, none or floor)", name);
+            process::exit(1);
+        });
+    }
+
+    if let Some(value) = flag_value(args, "--repeat-every") {
+        let seconds: f32 = parse_number(value, "--repeat-every");
+        if !seconds.is_finite() || seconds <= 0.0 {
+            eprintln!("--repeat-every expects a positive number of seconds");
+            process::exit(1);
+        }
+        config.repeat_every = Some(seconds);
+    }
+
+    if let Some(value) = flag_value(args, "--floor-db") {
+        let floor_db: f32 = parse_number(value, "--floor-db");
+        if !floor_db.is_finite() || floor_db >= 0.0 {
+            eprintln!("--floor-db expects a level below 0 dBFS, like -60");
+            process::exit(1);
+        }
+        config.floor_db = Some(floor_db);
+    }
+
+    if let Some(name) = flag_value(args, "--round") {
+        config.round_mode = RoundMode::from_name(name).unwrap_or_else(|| {
+            eprintln!("unknown rounding mode {:?} (expected nearest, even or trunc)", name);
+            process::exit(1);
+        });
+    }
+
+    config
+}
+
+// Cancel flag for the sync-search decodes. With --timeout SECONDS a timer
+// thread sets it once that long has passed; without it nothing ever does.
+fn decode_timeout(args: &[String]) -> Arc<AtomicBool> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    if let Some(value) = flag_value(args, "--timeout") {
+        let seconds: f32 = parse_number(value, "--timeout");
+        if !(seconds.is_finite() && seconds > 0.0) {
+            eprintln!("--timeout expects a positive number of seconds");
+            process::exit(1);
+        }
+        let timer = Arc::clone(&cancel);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs_f32(seconds));
+            timer.store(true, Ordering::Relaxed);
+        });
+    }
+    cancel
+}
+
+// What to embed: --content-id ID:VERSION, --raw-hex HEX, or --message text
+fn encode_payload(args: &[String]) -> Payload {
+    if let Some(value) = flag_value(args, "--content-id") {
+        let Some((id, version)) = value.split_once(':') else {
+            eprintln!("--content-id expects ID:VERSION, got {:?}", value);
+            process::exit(1);
+        };
+        return Payload::ContentId {
+            id: parse_number(id, "--content-id"),
+            version: parse_number(version, "--content-id"),
+        };
+    }
+    if let Some(hex) = flag_value(args, "--raw-hex") {
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|start| hex.get(start..start + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+            .collect::<Option<Vec<u8>>>();
+        return Payload::Raw(bytes.unwrap_or_else(|| {
+            eprintln!("--raw-hex expects an even number of hex digits, got {:?}", hex);
+            process::exit(1);
+        }));
+    }
+    Payload::Text(flag_value(args, "--message").unwrap_or("hi").to_string())
+}
+
+// --input as a path. An http(s) URL is downloaded to a temporary file first,
+// which is deleted when the returned Download goes out of scope.
+fn input_path(args: &[String]) -> (Option<PathBuf>, Option<fetch::Download>) {
+    let Some(input) = flag_value(args, "--input") else {
+        return (None, None);
+    };
+    if !fetch::is_url(input) {
+        return (Some(PathBuf::from(input)), None);
+    }
+    let download = fetch::download(input).unwrap_or_else(|err| {
+        eprintln!("failed to fetch {}: {}", input, err);
+        process::exit(1);
+    });
+    (Some(download.path().to_path_buf()), Some(download))
+}
+
+// Parse a numeric flag value or exit with a usage message
+fn parse_number<T: std::str::FromStr>(value: &str, flag: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("{} expects a number, got {:?}", flag, value);
+        process::exit(1);
+    })
+}
+
+// Look up the value following a `--flag` on the command line
+fn flag_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|idx| args.get(idx + 1))
+        .map(String::as_str)
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+
+use serde::Serialize;
+
+// =============================================================================
+// PAYLOAD - What the watermark carries: text, a content ID, or raw bytes
+// =============================================================================
+//
+// Untyped (the default), the payload bytes are the message itself. Typed
+// (--typed on both sides), one tag byte leads them so the decoder can hand
+// back the same variant:
+//
+//   Text       0x00 | UTF-8 bytes
+//   ContentId  0x01 | id (u64, big-endian) | version (u16, big-endian)
+//   Raw        0x02 | bytes
+//
+// The tag is part of the payload, so the length header counts it.
+
+const TAG_TEXT: u8 = 0x00;
+const TAG_CONTENT_ID: u8 = 0x01;
+const TAG_RAW: u8 = 0x02;
+
+const CONTENT_ID_BYTES: usize = 8 + 2;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Payload {
+    Text(String),
+    ContentId { id: u64, version: u16 },
+    Raw(Vec<u8>),
+}
+
+impl Payload {
+    // The variant's own bytes, without a tag
+    pub fn body(&self) -> Vec<u8> {
+        match self {
+            Payload::Text(text) => text.as_bytes().to_vec(),
+            Payload::ContentId { id, version } => {
+                [&id.to_be_bytes()[..], &version.to_be_bytes()].concat()
+            }
+            Payload::Raw(bytes) => bytes.clone(),
+        }
+    }
+
+    // Bytes handed to the framing: tag + body when typed, the body alone otherwise
+    pub fn to_bytes(&self, typed: bool) -> Vec<u8> {
+        if !typed {
+            return self.body();
+        }
+        let tag = match self {
+            Payload::Text(_) => TAG_TEXT,
+            Payload::ContentId { .. } => TAG_CONTENT_ID,
+            Payload::Raw(_) => TAG_RAW,
+        };
+        [&[tag][..], &self.body()].concat()
+    }
+
+    // Inverse of to_bytes. Untyped bytes come back as (lossy) text; a typed
+    // payload with an unknown tag or the wrong size is None.
+    pub fn from_bytes(bytes: &[u8], typed: bool) -> Option<Payload> {
+        if !typed {
+            return Some(Payload::Text(String::from_utf8_lossy(bytes).into_owned()));
+        }
+        let (&tag, body) = bytes.split_first()?;
+        match tag {
+            TAG_TEXT => Some(Payload::Text(String::from_utf8_lossy(body).into_owned())),
+            TAG_CONTENT_ID if body.len() == CONTENT_ID_BYTES => {
+                let (id, version) = body.split_at(8);
+                Some(Payload::ContentId {
+                    id: u64::from_be_bytes(id.try_into().ok()?),
+                    version: u16::from_be_bytes(version.try_into().ok()?),
+                })
+            }
+            TAG_RAW => Some(Payload::Raw(body.to_vec())),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Payload::Text(text) => write!(f, "{:?}", text),
+            Payload::ContentId { id, version } => write!(f, "content id {} v{}", id, version),
+            Payload::Raw(bytes) => {
+                write!(f, "raw 0x")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_variant_round_trips_through_its_bytes() {
+        let payloads = [
+            Payload::Text("hi".to_string()),
+            Payload::ContentId {
+                id: 1_234_567_890_123,
+                version: 7,
+            },
+            Payload::Raw(vec![0x00, 0xff, 0x80]),
+        ];
+        for payload in payloads {
+            let bytes = payload.to_bytes(true);
+            assert_eq!(bytes.len(), payload.body().len() + 1); // one tag byte
+            assert_eq!(Payload::from_bytes(&bytes, true), Some(payload));
+        }
+        // Untyped bytes are the body alone and always read back as text
+        assert_eq!(Payload::Text("hi".to_string()).to_bytes(false), b"hi");
+        assert_eq!(Payload::from_bytes(b"hi", false), Some(Payload::Text("hi".to_string())));
+    }
+
+    #[test]
+    fn unknown_tag_or_short_content_id_is_rejected() {
+        assert_eq!(Payload::from_bytes(&[0x7f, 1, 2], true), None);
+        assert_eq!(Payload::from_bytes(&[TAG_CONTENT_ID, 1, 2], true), None);
+        assert_eq!(Payload::from_bytes(&[], true), None);
+    }
+}
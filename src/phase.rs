@@ -0,0 +1,132 @@
+use std::f32::consts::FRAC_PI_2;
+
+use realfft::num_complex::Complex;
+
+use crate::config::{FreqBand, Scheme};
+use crate::fft::FftPlans;
+
+// =============================================================================
+// PHASE CODING - Bits live in the phase spectrum instead of the magnitudes
+// =============================================================================
+//
+// Classic phase coding watermark:
+//   1. Split the audio into frames and take the FFT of each one.
+//   2. In the first frame, replace the phase of each watermark bin with
+//      +pi/2 (bit 0) or -pi/2 (bit 1). Magnitudes are left alone.
+//   3. For every later frame, keep the original phase *difference* to the
+//      previous frame, so the relative phase the ear is sensitive to survives.
+//
+// The decoder only needs the first frame: the sign of each bin's phase is the bit.
+
+// The watermarked audio, and the bins the first frame wrote the bits to
+pub fn embed_phase_coding(
+    audio: &[f32],
+    bits: &[u8],
+    plans: &FftPlans,
+    band: FreqBand,
+    guard: usize,
+) -> (Vec<f32>, Vec<usize>) {
+    let frame_len = plans.fft_len();
+    let (fft, ifft) = (&plans.forward, &plans.inverse);
+
+    let mut buffer = vec![0.0f32; frame_len];
+    let mut spectrum = fft.make_output_vec();
+
+    // The Nyquist bin must stay real, so it can't hold a +-pi/2 phase
+    let band = band.clamp_to(Scheme::PhaseCoding.carrier_bins(spectrum.len()));
+    let bins: Vec<usize> = band.bit_bins(bits.len(), guard).collect();
+    let bin_count = bins.len();
+    let mut previous_original = vec![0.0f32; bin_count]; // phases before embedding
+    let mut previous_embedded = vec![0.0f32; bin_count]; // phases after embedding
+
+    let mut output = Vec::with_capacity(audio.len());
+
+    for (frame_idx, chunk) in audio.chunks(frame_len).enumerate() {
+        buffer.fill(0.0);
+        buffer[..chunk.len()].copy_from_slice(chunk);
+
+        fft.process(&mut buffer, &mut spectrum).expect("FFT failed");
+
+        for (idx, &bin_idx) in bins.iter().enumerate() {
+            let bin = &mut spectrum[bin_idx];
+            let (magnitude, phase) = bin.to_polar();
+
+            let embedded = if frame_idx == 0 {
+                // Absolute phase carries the bit
+                if bits[idx] == 1 {
+                    -FRAC_PI_2
+                } else {
+                    FRAC_PI_2
+                }
+            } else {
+                // Keep the original frame-to-frame phase step
+                previous_embedded[idx] + (phase - previous_original[idx])
+            };
+
+            previous_original[idx] = phase;
+            previous_embedded[idx] = embedded;
+            *bin = Complex::from_polar(magnitude, embedded);
+        }
+
+        ifft.process(&mut spectrum, &mut buffer).expect("IFFT failed");
+
+        output.extend(buffer[..chunk.len()].iter().map(|x| x / frame_len as f32));
+    }
+
+    (output, bins)
+}
+
+pub fn extract_phase_coding(
+    samples: &[f32],
+    plans: &FftPlans,
+    band: FreqBand,
+    guard: usize,
+) -> Vec<u8> {
+    let frame_len = plans.fft_len();
+    let fft = &plans.forward;
+
+    let mut buffer = vec![0.0f32; frame_len];
+    let mut spectrum = fft.make_output_vec();
+
+    // Only the first frame carries the absolute phases
+    let first = &samples[..frame_len.min(samples.len())];
+    buffer[..first.len()].copy_from_slice(first);
+    fft.process(&mut buffer, &mut spectrum).expect("FFT failed");
+
+    let band = band.clamp_to(Scheme::PhaseCoding.carrier_bins(spectrum.len()));
+    band.data_bins(guard)
+        .map(|bin| u8::from(spectrum[bin].arg() < 0.0)) // negative phase -> 1
+        .collect()
+}
+
+// For every sample offset a whole frame fits at: how close the first `bins`
+// watermark bins of the frame starting there sit to +-pi/2, as the mean
+// |sin(phase)|. An anchor frame (the one carrying a copy) scores near 1.0;
+// ordinary audio, or a frame off the anchor by even one sample, about 2/pi.
+pub fn anchor_scores(
+    samples: &[f32],
+    plans: &FftPlans,
+    band: FreqBand,
+    guard: usize,
+    bins: usize,
+) -> Vec<f32> {
+    let frame_len = plans.fft_len();
+    let fft = &plans.forward;
+
+    let mut buffer = vec![0.0f32; frame_len];
+    let mut spectrum = fft.make_output_vec();
+    let mut scratch = fft.make_scratch_vec();
+    let band = band.clamp_to(Scheme::PhaseCoding.carrier_bins(spectrum.len()));
+    let bins: Vec<usize> = band.data_bins(guard).take(bins).collect();
+
+    samples
+        .windows(frame_len)
+        .map(|frame| {
+            buffer.copy_from_slice(frame);
+            fft.process_with_scratch(&mut buffer, &mut spectrum, &mut scratch)
+                .expect("FFT failed");
+            let total: f32 = bins.iter().map(|&bin| spectrum[bin].arg().sin().abs()).sum();
+            total / bins.len().max(1) as f32
+        })
+        .collect()
+}
@@ -0,0 +1,38 @@
+use realfft::num_complex::Complex;
+
+// =============================================================================
+// POLAR - Bin magnitude, read and written the same way on both sides
+// =============================================================================
+//
+// The amplitude scheme is defined on |bin|: the encoder scales it and the
+// decoder scores it. Both go through these helpers so the two halves can't
+// disagree on what "magnitude" means.
+
+// |bin|, the Euclidean norm of the complex value
+pub fn magnitude(bin: &Complex<f32>) -> f32 {
+    bin.norm()
+}
+
+// Give `bin` the magnitude `target` and keep its phase. A zero bin has no
+// phase of its own and takes phase 0.
+pub fn set_magnitude(bin: &mut Complex<f32>, target: f32) {
+    *bin = Complex::from_polar(target, bin.arg());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_magnitude_hits_the_target_and_keeps_the_phase() {
+        for bin in [Complex::new(3.0, 4.0), Complex::new(-1.0, 0.5), Complex::new(0.0, -2.0)] {
+            let mut scaled = bin;
+            set_magnitude(&mut scaled, 10.0);
+            assert!((magnitude(&scaled) - 10.0).abs() < 1e-5);
+            assert!((scaled.arg() - bin.arg()).abs() < 1e-6, "{} -> {}", bin, scaled);
+        }
+        let mut zero = Complex::new(0.0, 0.0);
+        set_magnitude(&mut zero, 2.0);
+        assert_eq!(zero, Complex::new(2.0, 0.0)); // phase 0
+    }
+}
@@ -0,0 +1,44 @@
+use crate::channels::{deinterleave, interleave};
+
+// =============================================================================
+// RESAMPLE - Linear-interpolation sample rate conversion, no dependencies
+// =============================================================================
+//
+// Output sample i sits at input position i * from / to, and is read off the
+// straight line between the two input samples around it. There is no
+// anti-aliasing filter: downsampling folds anything above the new Nyquist
+// back into the spectrum, and upsampling slightly rolls off the top octave.
+// That is enough to bring a file back to the rate it was watermarked at.
+
+// One track from `from_rate` to `to_rate`
+pub fn resample_linear(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let out_len = (samples.len() as u64 * u64::from(to_rate) / u64::from(from_rate)) as usize;
+    let step = f64::from(from_rate) / f64::from(to_rate); // input samples per output sample
+    let last = samples.len() - 1;
+    (0..out_len)
+        .map(|index| {
+            let position = index as f64 * step;
+            let before = (position.floor() as usize).min(last);
+            let after = (before + 1).min(last); // hold the final sample at the edge
+            let frac = (position - before as f64) as f32;
+            samples[before] + (samples[after] - samples[before]) * frac
+        })
+        .collect()
+}
+
+// Interleaved multichannel audio, one channel at a time
+pub fn resample_interleaved(
+    samples: &[f32],
+    channels: u16,
+    from_rate: u32,
+    to_rate: u32,
+) -> Vec<f32> {
+    let tracks: Vec<Vec<f32>> = deinterleave(samples, usize::from(channels.max(1)))
+        .iter()
+        .map(|track| resample_linear(track, from_rate, to_rate))
+        .collect();
+    interleave(&tracks)
+}
@@ -0,0 +1,160 @@
+use crate::config::{FreqBand, Scheme, WatermarkConfig};
+use crate::decoder::{self, DecodeError, FrameBits};
+use crate::encoder::{self, Embedded};
+use crate::fft::FftPlans;
+use crate::phase;
+use crate::window::hop_len;
+
+// =============================================================================
+// SCHEME - One embed / extract pair per watermarking algorithm
+// =============================================================================
+//
+// The encoder and the decoder reach the configured algorithm through
+// Scheme::algorithm() rather than matching on config.scheme at every step.
+// A new scheme is a Scheme variant (its command-line name) plus an
+// Algorithm impl here; framing, payloads, channels and reports are shared.
+
+pub trait Algorithm {
+    // Watermark one track with `bits` in `band`, framed at the plans' length.
+    // BinStats when the scheme measures per-bin levels (only amplitude does).
+    // bins_used lists the (frame, bin) pairs a bit was written to.
+    fn embed(
+        &self,
+        audio: &[f32],
+        bits: &[u8],
+        plans: &FftPlans,
+        band: FreqBand,
+        config: &WatermarkConfig,
+    ) -> Embedded;
+
+    // Per-bin bit decisions read back from one channel
+    fn extract(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+        plans: &FftPlans,
+        config: &WatermarkConfig,
+    ) -> Result<FrameBits, DecodeError>;
+
+    // FFT size the decoder analyses a `frame_len`-sample frame at
+    fn fft_len(&self, frame_len: usize) -> usize;
+
+    // Samples between frame starts
+    fn hop(&self, frame_len: usize, config: &WatermarkConfig) -> usize;
+
+    // Bins of a `spectrum_len`-bin spectrum the scheme can write to
+    fn carrier_bins(&self, spectrum_len: usize) -> usize;
+
+    // Whether every frame repeats the whole message. The multi-frame decode
+    // modes (averaging, alignment search, re-sync, traces) rely on it.
+    fn repeats_every_frame(&self) -> bool;
+}
+
+// Boost / suppress bin magnitudes in every frame
+pub struct Amplitude;
+
+impl Algorithm for Amplitude {
+    fn embed(
+        &self,
+        audio: &[f32],
+        bits: &[u8],
+        plans: &FftPlans,
+        band: FreqBand,
+        config: &WatermarkConfig,
+    ) -> Embedded {
+        let hop = self.hop(plans.fft_len(), config);
+        encoder::embed_watermark_fft(audio, bits, plans, hop, band, config)
+    }
+
+    fn extract(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+        plans: &FftPlans,
+        config: &WatermarkConfig,
+    ) -> Result<FrameBits, DecodeError> {
+        decoder::decode_amplitude_bits(samples, sample_rate, plans, config)
+    }
+
+    // The frame exactly, as the encoder transformed it
+    fn fft_len(&self, frame_len: usize) -> usize {
+        frame_len
+    }
+
+    fn hop(&self, frame_len: usize, config: &WatermarkConfig) -> usize {
+        hop_len(frame_len, config.overlap)
+    }
+
+    fn carrier_bins(&self, spectrum_len: usize) -> usize {
+        spectrum_len
+    }
+
+    fn repeats_every_frame(&self) -> bool {
+        true
+    }
+}
+
+// Absolute phase of the first frame (see phase.rs)
+pub struct PhaseCoding;
+
+impl Algorithm for PhaseCoding {
+    fn embed(
+        &self,
+        audio: &[f32],
+        bits: &[u8],
+        plans: &FftPlans,
+        band: FreqBand,
+        config: &WatermarkConfig,
+    ) -> Embedded {
+        let (samples, bins) = phase::embed_phase_coding(audio, bits, plans, band, config.guard);
+        // Later frames only carry the phase chain on; frame 0 holds the bits
+        let bins_used = if samples.is_empty() {
+            Vec::new()
+        } else {
+            bins.into_iter().map(|bin| (0, bin)).collect()
+        };
+        Embedded {
+            samples,
+            stats: None,
+            bins_used,
+        }
+    }
+
+    fn extract(
+        &self,
+        samples: &[f32],
+        sample_rate: u32,
+        plans: &FftPlans,
+        config: &WatermarkConfig,
+    ) -> Result<FrameBits, DecodeError> {
+        Ok(decoder::phase_bits(samples, sample_rate, plans, config))
+    }
+
+    // The frame exactly, as the encoder transformed it
+    fn fft_len(&self, frame_len: usize) -> usize {
+        frame_len
+    }
+
+    // Back-to-back frames: the phase chain runs frame to frame
+    fn hop(&self, frame_len: usize, _config: &WatermarkConfig) -> usize {
+        frame_len
+    }
+
+    fn carrier_bins(&self, spectrum_len: usize) -> usize {
+        spectrum_len.saturating_sub(1) // Nyquist bin must stay real
+    }
+
+    fn repeats_every_frame(&self) -> bool {
+        false
+    }
+}
+
+impl Scheme {
+    // The algorithm behind this scheme
+    pub fn algorithm(self) -> &'static dyn Algorithm {
+        match self {
+            Scheme::Amplitude => &Amplitude,
+            Scheme::PhaseCoding => &PhaseCoding,
+        }
+    }
+}
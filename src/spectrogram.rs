@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::path::Path;
+
+use image::{Rgb, RgbImage};
+
+use crate::config::WatermarkConfig;
+use crate::decoder;
+
+// =============================================================================
+// SPECTROGRAM - Render a file's STFT as a PNG with the watermark band outlined
+// =============================================================================
+//
+// One pixel per (frame, bin), framed exactly as the decoder frames the audio.
+// Low frequencies sit at the bottom. Magnitudes are mapped to grey over the
+// top DYNAMIC_RANGE_DB below the loudest bin; the edges of the configured
+// band are drawn in red so the watermark's place in the spectrum is obvious.
+
+// Quietest level drawn above black, relative to the peak
+const DYNAMIC_RANGE_DB: f32 = 80.0;
+
+const BAND_EDGE: Rgb<u8> = Rgb([255, 0, 0]);
+
+// Render `input` to `output`; returns the image size (frames x bins)
+pub fn render(
+    input: &Path,
+    output: &Path,
+    config: &WatermarkConfig,
+) -> Result<(u32, u32), Box<dyn Error>> {
+    let (samples, sample_rate) = decoder::load_audio(input, config)?;
+    let frames = decoder::frame_spectra(&samples, sample_rate, config);
+    let band = decoder::decoder_band(config, sample_rate);
+
+    let width = frames.len().max(1) as u32;
+    let height = frames.first().map_or(1, Vec::len) as u32;
+
+    // Everything in dB, then scaled against the loudest bin in the file
+    let to_db = |magnitude: f32| 20.0 * magnitude.max(1e-9).log10();
+    let peak_db = frames
+        .iter()
+        .flatten()
+        .map(|&magnitude| to_db(magnitude))
+        .fold(f32::NEG_INFINITY, f32::max);
+
+    let mut image = RgbImage::new(width, height);
+    for (x, magnitudes) in frames.iter().enumerate() {
+        for (bin, &magnitude) in magnitudes.iter().enumerate() {
+            let level = 1.0 - ((peak_db - to_db(magnitude)) / DYNAMIC_RANGE_DB).clamp(0.0, 1.0);
+            let grey = (level * 255.0).round() as u8;
+            let y = height - 1 - bin as u32; // bin 0 at the bottom
+            image.put_pixel(x as u32, y, Rgb([grey, grey, grey]));
+        }
+    }
+
+    // Outline the band: its lowest and highest bins, across every frame
+    if band.bin_count() > 0 {
+        for bin in [band.low_bin, band.high_bin - 1] {
+            let y = height - 1 - bin as u32;
+            for x in 0..width {
+                image.put_pixel(x, y, BAND_EDGE);
+            }
+        }
+    }
+
+    image.save(output)?;
+    Ok((width, height))
+}
+
+// Per-bin change the watermark makes to the long-term spectrum: the mean
+// magnitude of every bin over all frames, modified vs original, in dB, with
+// each bin's centre frequency. Zero means the bin was left alone on average.
+pub fn spectral_footprint(
+    original: &[f32],
+    modified: &[f32],
+    sample_rate: u32,
+    config: &WatermarkConfig,
+) -> Vec<(f32, f32)> {
+    let original = mean_spectrum(&decoder::frame_spectra(original, sample_rate, config));
+    let modified = mean_spectrum(&decoder::frame_spectra(modified, sample_rate, config));
+    let fft_len = original.len().saturating_sub(1).max(1) * 2; // real FFT: len / 2 + 1 bins
+    let to_db = |magnitude: f32| 20.0 * magnitude.max(1e-9).log10();
+    original
+        .iter()
+        .zip(&modified)
+        .enumerate()
+        .map(|(bin, (&before, &after))| {
+            let hz = bin as f32 * sample_rate as f32 / fft_len as f32;
+            (hz, to_db(after) - to_db(before))
+        })
+        .collect()
+}
+
+// Average magnitude of every bin across frames
+fn mean_spectrum(frames: &[Vec<f32>]) -> Vec<f32> {
+    let bins = frames.first().map_or(0, Vec::len);
+    let mut mean = vec![0.0f32; bins];
+    for magnitudes in frames {
+        for (sum, &magnitude) in mean.iter_mut().zip(magnitudes) {
+            *sum += magnitude;
+        }
+    }
+    for sum in &mut mean {
+        *sum /= frames.len().max(1) as f32;
+    }
+    mean
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+    use crate::config::FreqBand;
+    use crate::encoder;
+    use crate::payload::Payload;
+
+    const RATE: u32 = 8000;
+
+    #[test]
+    fn png_has_a_pixel_per_frame_and_bin() {
+        let wav = env::temp_dir().join(format!("msg_encoder-test-{}-spectrogram.wav", std::process::id()));
+        let png = wav.with_extension("png");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&wav, spec).unwrap();
+        for n in 0..RATE {
+            let sample = (n as f32 * 0.3).sin() * 0.5;
+            writer.write_sample((sample * 32_767.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let config = WatermarkConfig::default();
+        let result = render(&wav, &png, &config);
+        let image = image::open(&png).map(|image| image.to_rgb8());
+        let _ = fs::remove_file(&wav);
+        let _ = fs::remove_file(&png);
+
+        let (width, height) = result.unwrap();
+        let image = image.unwrap();
+        assert_eq!(image.dimensions(), (width, height));
+        assert_eq!(width, RATE.div_ceil(256)); // one column per decoder frame
+        assert_eq!(height, 129); // 256-sample frames: 129 bins
+        // The band's edges are outlined across the whole image
+        let band = decoder::decoder_band(&config, RATE);
+        let edge = height - 1 - band.low_bin as u32;
+        assert!((0..width).all(|x| *image.get_pixel(x, edge) == BAND_EDGE));
+    }
+
+    #[test]
+    fn footprint_is_flat_outside_the_band() {
+        let config = WatermarkConfig {
+            band: FreqBand::from_name("30-90").unwrap(),
+            ..WatermarkConfig::default()
+        };
+        let mut state = 1u32;
+        let noise: Vec<f32> = (0..RATE * 2)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+            })
+            .collect();
+        let message = Payload::Text("hi".to_string());
+        let (encoded, _) = encoder::encode_samples(&noise, 1, RATE, &message, &config).unwrap();
+
+        let footprint = spectral_footprint(&noise, &encoded, RATE, &config);
+        assert_eq!(footprint.len(), 129);
+        assert_eq!(footprint[30].0, 937.5); // 8000 / 256 Hz per bin
+        let (inside, outside): (Vec<_>, Vec<_>) =
+            footprint.iter().enumerate().partition(|(bin, _)| (30..90).contains(bin));
+        assert!(outside.iter().all(|(_, (_, db))| db.abs() < 0.1), "{:?}", outside);
+        assert!(inside.iter().any(|(_, (_, db))| db.abs() > 3.0));
+    }
+}
@@ -0,0 +1,55 @@
+// =============================================================================
+// BIT STUFFING - Keeps the sentinel out of arbitrary payloads
+// =============================================================================
+//
+// Same rule as HDLC: after five consecutive 1s the encoder inserts a 0.
+// The sentinel 0x7E (01111110) holds six 1s in a row, so it can never show
+// up inside a stuffed payload and the decoder can stop at the first match.
+
+// Longest run of 1s allowed before a 0 is inserted
+const MAX_RUN: usize = 5;
+
+pub fn stuff(bits: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(bits.len() + bits.len() / MAX_RUN);
+    let mut run = 0;
+
+    for &bit in bits {
+        stuffed.push(bit);
+        if bit == 1 {
+            run += 1;
+            if run == MAX_RUN {
+                stuffed.push(0); // break the run
+                run = 0;
+            }
+        } else {
+            run = 0;
+        }
+    }
+
+    stuffed
+}
+
+pub fn unstuff(bits: &[u8]) -> Vec<u8> {
+    let mut unstuffed = Vec::with_capacity(bits.len());
+    let mut run = 0;
+    let mut drop_next = false;
+
+    for &bit in bits {
+        if drop_next {
+            drop_next = false; // the inserted 0
+            run = 0;
+            continue;
+        }
+        unstuffed.push(bit);
+        if bit == 1 {
+            run += 1;
+            if run == MAX_RUN {
+                drop_next = true;
+            }
+        } else {
+            run = 0;
+        }
+    }
+
+    unstuffed
+}
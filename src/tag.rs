@@ -0,0 +1,53 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// =============================================================================
+// TAG - Truncated HMAC-SHA256 that shows the payload came from a key holder
+// =============================================================================
+//
+// The header CRC only catches accidental damage: anyone who alters the
+// payload can recompute it. With a key, the payload is followed by the first
+// TAG_BYTES of HMAC-SHA256(key, payload), which can't be forged without the
+// key. 32 bits is short for a MAC, but every tag bit costs a bin per frame.
+
+pub const TAG_BYTES: usize = 4;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(key: &[u8], payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(payload);
+    mac
+}
+
+// Tag appended after the payload
+pub fn tag(key: &[u8], payload: &[u8]) -> [u8; TAG_BYTES] {
+    let digest = mac(key, payload).finalize().into_bytes();
+    let mut tag = [0u8; TAG_BYTES];
+    tag.copy_from_slice(&digest[..TAG_BYTES]);
+    tag
+}
+
+// Constant-time check of a received tag against the recovered payload
+pub fn verify(key: &[u8], payload: &[u8], received: &[u8]) -> bool {
+    received.len() == TAG_BYTES && mac(key, payload).verify_truncated_left(received).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn altering_one_payload_byte_fails_verification() {
+        let payload = b"hello".to_vec();
+        let received = tag(b"key", &payload);
+        assert!(verify(b"key", &payload, &received));
+        for index in 0..payload.len() {
+            let mut altered = payload.clone();
+            altered[index] ^= 0x01;
+            assert!(!verify(b"key", &altered, &received), "byte {}", index);
+        }
+        assert!(!verify(b"other key", &payload, &received));
+        assert!(!verify(b"key", &payload, &received[..TAG_BYTES - 1])); // truncated tag
+    }
+}
@@ -0,0 +1,51 @@
+use crate::window::frame_starts;
+
+// =============================================================================
+// VAD - Which frames are loud enough to carry the watermark
+// =============================================================================
+//
+// With --vad-threshold-db D, a frame whose RMS is below D dBFS counts as
+// silence: the encoder leaves it unmarked and gives it no bits, and the
+// decoder leaves it out before it votes or regroups. Both sides measure the
+// raw frame (before the analysis window) at the same frame starts, so they
+// pick the same frames as long as they are given the same D.
+//
+// A different D on the decode side desynchronizes the selection: frames the
+// encoder skipped get read as marked, or marked ones dropped. Under
+// --bits-per-frame that breaks every run of frames a skipped frame falls in;
+// only the copies in unbroken stretches of loud frames still line up. The
+// watermark itself moves a frame's level by a fraction of a dB, so a frame
+// sitting right at D can also land on different sides of it; a threshold
+// between the levels the recording actually has keeps both sides matched.
+
+// RMS level of `frame` in dBFS; -inf for digital silence
+pub fn frame_level_db(frame: &[f32]) -> f32 {
+    if frame.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let power = frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32;
+    10.0 * power.log10()
+}
+
+// Whether each frame (frame_len samples every `hop`, the last ones cut short
+// by the end of `samples`) is at or above `threshold_db`
+pub fn active_frames(samples: &[f32], frame_len: usize, hop: usize, threshold_db: f32) -> Vec<bool> {
+    frame_starts(samples.len(), hop)
+        .map(|start| frame_level_db(&samples[start..(start + frame_len).min(samples.len())]) >= threshold_db)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_are_rms_dbfs() {
+        assert_eq!(frame_level_db(&[1.0, -1.0]), 0.0);
+        assert!((frame_level_db(&[0.1; 64]) + 20.0).abs() < 1e-4);
+        assert_eq!(frame_level_db(&[0.0; 64]), f32::NEG_INFINITY);
+        // A loud frame, a quiet one, and a short loud tail
+        let samples: Vec<f32> = [vec![0.5; 4], vec![0.001; 4], vec![0.5; 2]].concat();
+        assert_eq!(active_frames(&samples, 4, 4, -40.0), [true, false, true]);
+    }
+}
@@ -0,0 +1,59 @@
+// =============================================================================
+// VARINT - LEB128-style length field for Framing::Varint
+// =============================================================================
+//
+// 7 payload bits per byte, least significant group first; the high bit of
+// each byte says another byte follows. Lengths below 128 take one byte.
+
+// Five groups of 7 bits cover any u32 length
+pub const MAX_VARINT_BYTES: usize = 5;
+
+pub fn encode_varint(mut value: u32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MAX_VARINT_BYTES);
+    loop {
+        let group = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(group); // last byte: continuation bit clear
+            return bytes;
+        }
+        bytes.push(group | 0x80);
+    }
+}
+
+// Decode from the front of `bytes`; returns the value and bytes consumed,
+// or None if the continuation chain runs past the end or past MAX_VARINT_BYTES
+pub fn decode_varint(bytes: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0u32;
+    for (idx, &byte) in bytes.iter().take(MAX_VARINT_BYTES).enumerate() {
+        value |= u32::from(byte & 0x7F) << (7 * idx);
+        if byte & 0x80 == 0 {
+            return Some((value, idx + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths_round_trip_at_each_width() {
+        for (length, encoded) in [
+            (1, vec![0x01]),
+            (200, vec![0xC8, 0x01]),
+            (100_000, vec![0xA0, 0x8D, 0x06]),
+        ] {
+            assert_eq!(encode_varint(length), encoded, "{} bytes", length);
+            assert_eq!(decode_varint(&encoded), Some((length, encoded.len())));
+        }
+        assert_eq!(decode_varint(&encode_varint(u32::MAX)), Some((u32::MAX, MAX_VARINT_BYTES)));
+    }
+
+    #[test]
+    fn unterminated_chain_is_none() {
+        assert_eq!(decode_varint(&[0x80, 0x80]), None); // runs past the end
+        assert_eq!(decode_varint(&[0xFF; 8]), None); // runs past MAX_VARINT_BYTES
+    }
+}
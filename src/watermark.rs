@@ -0,0 +1,568 @@
+use crate::bits::{bits_to_bytes, bits_to_number, bytes_to_bits, BitOrder};
+use crate::config::{Framing, SENTINEL};
+use crate::crc::crc8;
+use crate::decoder::DecodeError;
+use crate::stuffing;
+use crate::tag::{self, TAG_BYTES};
+use crate::varint::{decode_varint, encode_varint, MAX_VARINT_BYTES};
+use crate::whitening::whiten;
+
+// =============================================================================
+// WATERMARK - The embedded bit stream, built and parsed in one place
+// =============================================================================
+//
+// Every frame carries the whole stream:
+//
+//   pilot (8) | magic (8) | whitened( framing header | payload | end marker )
+//
+// The header depends on the framing: a 16-bit length + CRC-8, a varint
+// length + CRC-8, or nothing (sentinel framing closes the bit-stuffed
+// payload with an end marker instead). The encoder calls to_bits and the
+// decoder calls from_bits, so the two sides can't drift apart.
+//
+// With a nonce, the payload is the 16-bit nonce followed by the message
+// XORed with a keystream seeded from it, so embedding the same message
+// twice gives different bits. With a key, an HMAC tag (see tag.rs) over
+// that payload follows it. The length header still counts message bytes.
+
+// Pilot pattern: A known sequence at the start to help decoder find the threshold
+// Alternating 0s and 1s give us clear separation between high and low magnitudes
+pub const PILOT_PATTERN: [u8; 8] = [0, 1, 0, 1, 0, 1, 0, 1];
+
+// Magic pattern: Fixed marker right after the pilot so the decoder can tell
+// a watermarked file from ordinary audio before parsing any framing
+pub const MAGIC_PATTERN: [u8; 8] = [1, 0, 1, 1, 0, 0, 1, 1];
+
+// Decision threshold between the mean 1 and mean 0 pilot levels. The decoder
+// derives its thresholds from this and the encoder checks its boost/suppress
+// factors against it, so the two halves share one convention.
+pub fn threshold_from_pilot(high: f32, low: f32) -> f32 {
+    (high + low) * 0.5
+}
+
+// Whether `score` reads as a 1 against `threshold`. A score exactly on the
+// line is a 1. Every place the decoder splits scores at a threshold (bit
+// decisions, per-frame votes, the pilot match count) goes through here, so
+// a tie can't land on one side in one decode mode and the other side in
+// another, or move when a comparison is rewritten. The encoder keeps its
+// boost and suppress levels strictly off the line (see build_bit_sequence),
+// so only noise or rounding puts a bin exactly on it.
+pub fn decides_one(score: f32, threshold: f32) -> bool {
+    score >= threshold
+}
+
+// Flipped magic bits tolerated before the decoder reports "no watermark"
+const MAGIC_MAX_ERRORS: usize = 1;
+
+// Fixed length header and the CRC-8 protecting it (and the varint length)
+pub const LENGTH_HEADER_BITS: usize = 16;
+pub const HEADER_CRC_BITS: usize = 8;
+
+// How far from the received length the CRC-guided recovery looks
+const LENGTH_SEARCH_RADIUS: usize = 1;
+
+// Computed and received header CRC of a header that failed its check
+pub type CrcMismatch = (u8, u8);
+
+// Nonce carried in front of the payload when the config asks for one
+const NONCE_BYTES: usize = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watermark {
+    pub framing: Framing,
+    pub message: Vec<u8>,
+    pub nonce: Option<u16>,
+    // HMAC key the tag is computed with (to_bits)
+    pub key: Option<Vec<u8>>,
+    // Whether the recovered tag checked out (from_bits; None without a key)
+    pub tag_ok: Option<bool>,
+    // Whether the header CRC checked out (from_bits; None for sentinel framing).
+    // A failed CRC still returns the payload at the received length.
+    pub crc_ok: Option<bool>,
+    // The computed and received header CRC when they disagree and no nearby
+    // length explains it (from_bits; see header_error)
+    pub crc_mismatch: Option<CrcMismatch>,
+}
+
+impl Watermark {
+    pub fn new(message: &[u8], framing: Framing) -> Watermark {
+        Watermark {
+            framing,
+            message: message.to_vec(),
+            nonce: None,
+            key: None,
+            tag_ok: None,
+            crc_ok: None,
+            crc_mismatch: None,
+        }
+    }
+
+    pub fn with_nonce(mut self, nonce: u16) -> Watermark {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    pub fn with_key(mut self, key: &[u8]) -> Watermark {
+        self.key = Some(key.to_vec());
+        self
+    }
+
+    // Full bit stream to embed, MSB first throughout
+    pub fn to_bits(&self) -> Vec<u8> {
+        let mut framed = Vec::new();
+
+        match self.framing {
+            // 16-bit length so the decoder knows where the payload ends
+            Framing::LengthHeader => {
+                let length = self.message.len() as u16;
+                framed.extend(bytes_to_bits(&length.to_be_bytes(), BitOrder::MsbFirst));
+                framed.extend(bytes_to_bits(&[crc8(&length.to_be_bytes())], BitOrder::MsbFirst));
+            }
+            // 1+ length bytes (7 bits each) and the same CRC-8
+            Framing::Varint => {
+                let length_bytes = encode_varint(self.message.len() as u32);
+                framed.extend(bytes_to_bits(&length_bytes, BitOrder::MsbFirst));
+                framed.extend(bytes_to_bits(&[crc8(&length_bytes)], BitOrder::MsbFirst));
+            }
+            Framing::Sentinel => {}
+        }
+
+        let mut payload_bytes = match self.nonce {
+            Some(nonce) => [&nonce.to_be_bytes()[..], &scramble(&self.message, nonce)].concat(),
+            None => self.message.clone(),
+        };
+        if let Some(key) = &self.key {
+            let tag = tag::tag(key, &payload_bytes);
+            payload_bytes.extend_from_slice(&tag);
+        }
+        let payload = bytes_to_bits(&payload_bytes, BitOrder::MsbFirst);
+
+        // Sentinel framing: stuff the payload, then close it with the end marker
+        if self.framing == Framing::Sentinel {
+            framed.extend(stuffing::stuff(&payload));
+            framed.extend(bytes_to_bits(&[SENTINEL], BitOrder::MsbFirst));
+        } else {
+            framed.extend(payload);
+        }
+
+        let mut bits = PILOT_PATTERN.to_vec();
+        bits.extend_from_slice(&MAGIC_PATTERN);
+        bits.extend(whiten(&framed));
+        bits
+    }
+
+    // Parse a decided bit stream (pilot included) back into a watermark.
+    // The pilot is only used for calibration, so it is skipped, not checked.
+    // `with_nonce` must match what the encoder was configured with; a `key`
+    // means a tag follows the payload, and it is checked rather than trusted.
+    pub fn from_bits(
+        bits: &[u8],
+        framing: Framing,
+        with_nonce: bool,
+        key: Option<&[u8]>,
+    ) -> Result<Watermark, DecodeError> {
+        if bits.len() < Watermark::min_bits(framing) {
+            return Err(DecodeError::NotEnoughBins);
+        }
+        let (magic_bits, framed) = bits[PILOT_PATTERN.len()..].split_at(MAGIC_PATTERN.len());
+
+        // Unwatermarked audio still yields *some* bits; the magic pattern is the
+        // cheap test that tells them apart before any framing is parsed
+        let magic_errors = magic_bits
+            .iter()
+            .zip(MAGIC_PATTERN.iter())
+            .filter(|(got, want)| **got != **want)
+            .count();
+        if magic_errors > MAGIC_MAX_ERRORS {
+            diag!(
+                "Magic pattern mismatch ({} / {} bits wrong)",
+                magic_errors,
+                MAGIC_PATTERN.len()
+            );
+            return Err(DecodeError::NoWatermark);
+        }
+        diag!(
+            "Magic pattern verified ({} / {} matches)",
+            MAGIC_PATTERN.len() - magic_errors,
+            MAGIC_PATTERN.len()
+        );
+
+        let framed = whiten(framed); // framed bits as the encoder built them
+        let extra_bytes = if with_nonce { NONCE_BYTES } else { 0 }
+            + if key.is_some() { TAG_BYTES } else { 0 }; // not in the length
+        let (payload, crc_mismatch) = match framing {
+            Framing::LengthHeader => length_framed_payload(&framed, extra_bytes),
+            Framing::Sentinel => (sentinel_framed_payload(&framed), None),
+            Framing::Varint => varint_framed_payload(&framed, extra_bytes)?,
+        };
+        let crc_ok = (framing != Framing::Sentinel).then_some(crc_mismatch.is_none());
+
+        let mut message = bits_to_bytes(&payload, BitOrder::MsbFirst);
+        let tag_ok = key.map(|key| {
+            let received = message.split_off(message.len().saturating_sub(TAG_BYTES));
+            let valid = tag::verify(key, &message, &received);
+            if valid {
+                diag!("HMAC tag verified");
+            } else {
+                diag!("Warning: HMAC tag does not match; the payload or key differs");
+            }
+            valid
+        });
+        let nonce = if with_nonce && message.len() >= NONCE_BYTES {
+            let nonce = u16::from_be_bytes([message[0], message[1]]);
+            message = scramble(&message[NONCE_BYTES..], nonce); // XOR is its own inverse
+            diag!("Nonce {:#06x} unscrambled the payload", nonce);
+            Some(nonce)
+        } else {
+            None
+        };
+
+        Ok(Watermark {
+            framing,
+            message,
+            nonce,
+            key: None,
+            tag_ok,
+            crc_ok,
+            crc_mismatch,
+        })
+    }
+
+    // HeaderCorrupt for a header whose CRC failed. Not returned by from_bits:
+    // the payload behind a bad header is still worth a look (crc_ok false).
+    pub fn header_error(&self) -> Option<DecodeError> {
+        self.crc_mismatch
+            .map(|(expected, found)| DecodeError::HeaderCorrupt { expected, found })
+    }
+
+    // Fewest bits a frame can hold and still carry an (empty) watermark
+    pub fn min_bits(framing: Framing) -> usize {
+        PILOT_PATTERN.len() + Watermark::header_bits(framing) + end_marker_bits(framing)
+    }
+
+    // Message bytes a length-header stream declares, from decided `bits`
+    // (pilot included) that run at least through the header CRC: None
+    // without the magic pattern or when the CRC fails. For a decoder that
+    // reads the payload as it arrives, which has no later bits to fall back on.
+    pub fn declared_length(bits: &[u8]) -> Option<usize> {
+        let header_end = PILOT_PATTERN.len() + Watermark::header_bits(Framing::LengthHeader);
+        if bits.len() < header_end {
+            return None;
+        }
+        let (magic_bits, header) = bits[PILOT_PATTERN.len()..header_end].split_at(MAGIC_PATTERN.len());
+        let magic_errors = magic_bits
+            .iter()
+            .zip(MAGIC_PATTERN.iter())
+            .filter(|(got, want)| **got != **want)
+            .count();
+        if magic_errors > MAGIC_MAX_ERRORS {
+            return None;
+        }
+        let header = whiten(header); // the mask starts right after the magic
+        let (len_bits, crc_bits) = header.split_at(LENGTH_HEADER_BITS);
+        let length = bits_to_number(len_bits);
+        (crc8(&(length as u16).to_be_bytes()) == bits_to_number(crc_bits) as u8).then_some(length)
+    }
+
+    // Bits after the pilot that form the header (magic + length + CRC)
+    pub fn header_bits(framing: Framing) -> usize {
+        MAGIC_PATTERN.len()
+            + match framing {
+                Framing::LengthHeader => LENGTH_HEADER_BITS + HEADER_CRC_BITS,
+                Framing::Sentinel => 0, // no length header
+                Framing::Varint => 8 + HEADER_CRC_BITS, // first length byte + CRC
+            }
+    }
+}
+
+// The default wire format (length header, no nonce, no key); from_bits
+// takes the other settings
+impl TryFrom<&[u8]> for Watermark {
+    type Error = DecodeError;
+
+    fn try_from(bits: &[u8]) -> Result<Watermark, DecodeError> {
+        Watermark::from_bits(bits, Framing::default(), false, None)
+    }
+}
+
+fn end_marker_bits(framing: Framing) -> usize {
+    match framing {
+        Framing::Sentinel => 8,
+        Framing::LengthHeader | Framing::Varint => 0,
+    }
+}
+
+// Payload bits the header asked for, clamped to what the frame actually holds.
+// A CRC-checked length of 0 is an empty message, not a damaged header: it
+// takes no bits (only the nonce / tag bytes, when present) and decodes to ""
+// with the CRC reported ok.
+fn take_payload(data_bits: &[u8], message_bytes: usize) -> Vec<u8> {
+    let required_bits = message_bytes.saturating_mul(8);
+    if data_bits.len() < required_bits {
+        diag!(
+            "Warning: only {} of {} expected bits available; truncating",
+            data_bits.len(),
+            required_bits
+        );
+    }
+    data_bits[..required_bits.min(data_bits.len())].to_vec()
+}
+
+// XOR with a keystream seeded by `nonce` (a 32-bit LCG, top byte per step)
+fn scramble(bytes: &[u8], nonce: u16) -> Vec<u8> {
+    let mut state = u32::from(nonce).wrapping_mul(0x9E37_79B9) | 1;
+    bytes
+        .iter()
+        .map(|&byte| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            byte ^ (state >> 24) as u8
+        })
+        .collect()
+}
+
+// Payload bits, plus the (computed, received) CRCs if the header's failed. A
+// failed CRC is not fatal: the payload is read at the received length, for
+// the caller to judge.
+fn length_framed_payload(framed: &[u8], extra_bytes: usize) -> (Vec<u8>, Option<CrcMismatch>) {
+    let (len_bits, rest) = framed.split_at(LENGTH_HEADER_BITS);
+    let (crc_bits, data_bits) = rest.split_at(HEADER_CRC_BITS);
+    let mut message_bytes = bits_to_number(len_bits);
+
+    // Check the header before trusting the length it reports
+    let crc_for = |length: usize| {
+        let length = u16::try_from(length).ok()?;
+        Some(crc8(&length.to_be_bytes()))
+    };
+    let expected = crc8(&(message_bytes as u16).to_be_bytes());
+    let found = bits_to_number(crc_bits) as u8;
+    let mut mismatch = None;
+    if expected != found {
+        match nearby_length(message_bytes, found, crc_for) {
+            Some(recovered) => message_bytes = recovered,
+            None => mismatch = unverified_length(message_bytes, expected, found),
+        }
+    }
+    if mismatch.is_none() {
+        diag!("Length header reports {} message bytes (CRC ok)", message_bytes);
+    }
+
+    (take_payload(data_bits, message_bytes + extra_bytes), mismatch)
+}
+
+fn varint_framed_payload(
+    framed: &[u8],
+    extra_bytes: usize,
+) -> Result<(Vec<u8>, Option<CrcMismatch>), DecodeError> {
+    let header_bytes: Vec<u8> = bits_to_bytes(framed, BitOrder::MsbFirst)
+        .into_iter()
+        .take(MAX_VARINT_BYTES.min(framed.len() / 8))
+        .collect();
+
+    // A continuation chain that never ends can only come from a damaged header
+    let (message_bytes, used) = decode_varint(&header_bytes).ok_or(DecodeError::BadVarint)?;
+
+    let rest = &framed[used * 8..];
+    let (crc_bits, data_bits) = rest.split_at(HEADER_CRC_BITS.min(rest.len()));
+    let expected = crc8(&header_bytes[..used]);
+    let found = bits_to_number(crc_bits) as u8;
+    let mut message_bytes = message_bytes as usize;
+    let mut mismatch = None;
+    if expected != found {
+        // Only lengths with the same varint width keep the payload where it is
+        let crc_for = |length: usize| {
+            let bytes = encode_varint(u32::try_from(length).ok()?);
+            (bytes.len() == used).then(|| crc8(&bytes))
+        };
+        match nearby_length(message_bytes, found, crc_for) {
+            Some(recovered) => message_bytes = recovered,
+            None => mismatch = unverified_length(message_bytes, expected, found),
+        }
+    }
+    if mismatch.is_none() {
+        diag!(
+            "Varint header ({} bytes) reports {} message bytes (CRC ok)",
+            used, message_bytes
+        );
+    }
+
+    Ok((take_payload(data_bits, message_bytes + extra_bytes), mismatch))
+}
+
+// No nearby length matches either: carry on with the length as received.
+// Always the mismatch, for Watermark::crc_mismatch.
+fn unverified_length(length: usize, expected: u8, found: u8) -> Option<CrcMismatch> {
+    diag!("Warning: reading {} message bytes unverified", length);
+    Some((expected, found))
+}
+
+// A length whose CRC fails is often off by one (a flipped low bit), so try
+// the lengths within LENGTH_SEARCH_RADIUS and keep one the received CRC
+// vouches for. `crc_for` gives the CRC a candidate would have been sent with.
+fn nearby_length(
+    length: usize,
+    found: u8,
+    crc_for: impl Fn(usize) -> Option<u8>,
+) -> Option<usize> {
+    let recovered = (1..=LENGTH_SEARCH_RADIUS)
+        .flat_map(|delta| [length.checked_sub(delta), length.checked_add(delta)])
+        .flatten()
+        .find(|&candidate| crc_for(candidate) == Some(found))?;
+    diag!(
+        "Header CRC fails for length {} but matches {}; using {}",
+        length, recovered, recovered
+    );
+    Some(recovered)
+}
+
+fn sentinel_framed_payload(framed: &[u8]) -> Vec<u8> {
+    let marker = bytes_to_bits(&[SENTINEL], BitOrder::MsbFirst); // 01111110
+
+    // Stuffing keeps the marker out of the payload, so the first match ends it
+    let stuffed = match framed.windows(marker.len()).position(|window| window == marker) {
+        Some(end) => &framed[..end],
+        None => {
+            diag!("Warning: end-of-message sentinel not found; returning all available bits");
+            framed
+        }
+    };
+
+    let mut payload = stuffing::unstuff(stuffed); // drop inserted zeros
+    payload.truncate(payload.len() / 8 * 8); // whole bytes only
+    diag!(
+        "End-of-message sentinel framing yields {} message bytes",
+        payload.len() / 8
+    );
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flip one bit of the stream to_bits produced
+    fn flipped(mut bits: Vec<u8>, idx: usize) -> Vec<u8> {
+        bits[idx] ^= 1;
+        bits
+    }
+
+    #[test]
+    fn a_score_on_the_threshold_is_a_one() {
+        for threshold in [-1.956f32, 0.0, 0.5, 4.6] {
+            assert!(decides_one(threshold, threshold));
+            assert!(!decides_one(threshold.next_down(), threshold), "{}", threshold);
+        }
+        assert!(decides_one(-0.0, 0.0)); // equal, whatever the sign bit says
+        assert!(!decides_one(f32::NAN, 0.0)); // a NaN score never reads as a 1
+    }
+
+    #[test]
+    fn watermark_round_trips_through_bits() {
+        for framing in [Framing::LengthHeader, Framing::Sentinel, Framing::Varint] {
+            let original = Watermark::new(b"round trip", framing);
+            let decoded = Watermark::from_bits(&original.to_bits(), framing, false, None).unwrap();
+            let crc_ok = (framing != Framing::Sentinel).then_some(true);
+            assert_eq!(decoded, Watermark { crc_ok, ..original }, "{:?}", framing);
+
+            let keyed = Watermark::new(b"keyed", framing).with_nonce(0xBEEF).with_key(b"secret");
+            let decoded = Watermark::from_bits(&keyed.to_bits(), framing, true, Some(b"secret")).unwrap();
+            assert_eq!(decoded.message, b"keyed");
+            assert_eq!(decoded.nonce, Some(0xBEEF));
+            assert_eq!(decoded.tag_ok, Some(true));
+        }
+    }
+
+    #[test]
+    fn try_from_reads_the_default_format() {
+        let bits = Watermark::new(b"hi", Framing::LengthHeader).to_bits();
+        let decoded = Watermark::try_from(bits.as_slice()).unwrap();
+        assert_eq!(decoded.message, b"hi");
+        assert!(Watermark::try_from(&bits[..10]).is_err()); // shorter than the header
+    }
+
+    #[test]
+    fn payload_containing_the_sentinel_survives_stuffing() {
+        let message = [SENTINEL, 0xFF, SENTINEL, 0x3F, 0xFC, SENTINEL];
+        let bits = Watermark::new(&message, Framing::Sentinel).to_bits();
+        let decoded = Watermark::from_bits(&bits, Framing::Sentinel, false, None).unwrap();
+        assert_eq!(decoded.message, message);
+    }
+
+    #[test]
+    fn stuffing_round_trips_long_runs_of_ones() {
+        for bits in [vec![1; 23], vec![0, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1], vec![]] {
+            let stuffed = stuffing::stuff(&bits);
+            assert!(!stuffed.windows(6).any(|run| run == [1; 6]), "six 1s left in a row");
+            assert_eq!(stuffing::unstuff(&stuffed), bits);
+        }
+    }
+
+    #[test]
+    fn varint_framing_round_trips_long_messages() {
+        for length in [1, 200, 100_000] {
+            let message: Vec<u8> = (0..length).map(|idx| (idx * 7) as u8).collect();
+            let bits = Watermark::new(&message, Framing::Varint).to_bits();
+            let decoded = Watermark::from_bits(&bits, Framing::Varint, false, None).unwrap();
+            assert_eq!(decoded.message, message, "{} bytes", length);
+            assert_eq!(decoded.crc_ok, Some(true));
+        }
+    }
+
+    #[test]
+    fn failed_crc_still_returns_the_payload() {
+        let bits = Watermark::new(b"hello", Framing::LengthHeader).to_bits();
+        let crc_lsb = PILOT_PATTERN.len() + MAGIC_PATTERN.len() + LENGTH_HEADER_BITS + 7;
+        let decoded = Watermark::from_bits(&flipped(bits, crc_lsb), Framing::LengthHeader, false, None)
+            .unwrap();
+        assert_eq!(decoded.crc_ok, Some(false));
+        assert_eq!(decoded.message, b"hello");
+    }
+
+    #[test]
+    fn flipped_length_bit_is_header_corrupt() {
+        let bits = Watermark::new(b"hello", Framing::LengthHeader).to_bits();
+        let length_msb = PILOT_PATTERN.len() + MAGIC_PATTERN.len(); // 5 bytes reads as 32773
+        let clean = Watermark::from_bits(&bits, Framing::LengthHeader, false, None).unwrap();
+        assert!(clean.header_error().is_none());
+
+        let bits = flipped(bits, length_msb);
+        let decoded = Watermark::from_bits(&bits, Framing::LengthHeader, false, None).unwrap();
+        let computed = crc8(&32_773u16.to_be_bytes());
+        let received = crc8(&5u16.to_be_bytes());
+        assert_eq!(decoded.crc_ok, Some(false));
+        assert!(matches!(
+            decoded.header_error(),
+            Some(DecodeError::HeaderCorrupt { expected, found })
+                if expected == computed && found == received
+        ));
+    }
+
+    #[test]
+    fn off_by_one_length_is_recovered_through_the_crc() {
+        let header = PILOT_PATTERN.len() + MAGIC_PATTERN.len();
+        // The length's lowest bit: 5 bytes reads as 4
+        for (framing, length_lsb) in [
+            (Framing::LengthHeader, header + LENGTH_HEADER_BITS - 1),
+            (Framing::Varint, header + 7),
+        ] {
+            let bits = Watermark::new(b"hello", framing).to_bits();
+            let decoded = Watermark::from_bits(&flipped(bits.clone(), length_lsb), framing, false, None).unwrap();
+            assert_eq!(decoded.message, b"hello", "{:?}", framing);
+            assert_eq!(decoded.crc_ok, Some(true), "{:?}", framing);
+            assert!(decoded.header_error().is_none(), "{:?}", framing);
+
+            // Three bits up (5 reads as 13) is past the search: still corrupt
+            let decoded = Watermark::from_bits(&flipped(bits, length_lsb - 3), framing, false, None).unwrap();
+            assert_eq!(decoded.crc_ok, Some(false), "{:?}", framing);
+            assert!(decoded.header_error().is_some(), "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn endless_varint_chain_is_bad_varint() {
+        let mut bits = PILOT_PATTERN.to_vec();
+        bits.extend_from_slice(&MAGIC_PATTERN);
+        bits.extend(whiten(&[1; 8 * (MAX_VARINT_BYTES + 2)])); // every byte 0xFF
+        let result = Watermark::from_bits(&bits, Framing::Varint, false, None);
+        assert!(matches!(result, Err(DecodeError::BadVarint)));
+    }
+}
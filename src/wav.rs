@@ -0,0 +1,481 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use hound::{SampleFormat, WavSpec};
+
+// =============================================================================
+// WAV - Sample reading shared by the encoder and the decoder
+// =============================================================================
+//
+// SampleStream reads the chunks and samples straight off any reader, so a
+// file on disk (read_file) and one still arriving (decoder::decode_stream)
+// go through the same code. It only ever reads forward; nothing seeks.
+//
+// It reads the header itself rather than through hound, which refuses
+// WAVE_FORMAT_EXTENSIBLE headers (what many recorders write for more than
+// two channels or more than 16 bits) with a cbSize other than 22 or a
+// container wider than its valid bits (20-in-24), and reads 24-in-32
+// right-justified where the format puts it left-justified.
+//
+// The data chunk size in the header is trusted for the sample count, but
+// not for memory: room is only reserved for what the file can hold. When the
+// file ends before that count (a cut-off download, a crashed recorder), the
+// stream yields Truncated partway through.
+
+// Sample normalization divisor for i16 -> f32 conversion
+const SAMPLE_DIVISOR: f32 = 32768.0;
+
+// The longest fmt chunk read: WAVE_FORMAT_EXTENSIBLE's, with cbSize 22
+const MAX_FMT_LEN: u32 = 40;
+
+// Samples reserved ahead for a reader of unknown length (a pipe, a socket),
+// so a header declaring gigabytes doesn't allocate them before any arrive
+const UNSIZED_CAPACITY: usize = 1 << 20;
+
+// The fmt chunk's format tag for an extensible header
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+// Bytes 2..16 of every standard sub-format GUID; bytes 0..2 hold the old
+// format tag (1 PCM, 3 IEEE float, ...)
+const SUBFORMAT_GUID_TAIL: [u8; 14] =
+    [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+// Why a file couldn't be read as audio
+#[derive(Debug)]
+pub enum WavError {
+    // Opening or reading the file failed
+    Io(io::Error),
+    // Not a WAV, or a malformed header
+    Unreadable(hound::Error),
+    // A format tag (or extensible sub-format) that isn't PCM or IEEE float
+    UnsupportedSubFormat { name: String },
+    // A sample container read_file has no conversion for
+    UnsupportedContainer { format: SampleFormat, bits: u16 },
+    // The data chunk ends `read` samples into the `declared` ones (without
+    // --allow-truncated)
+    Truncated { read: usize, declared: usize },
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavError::Io(err) => write!(f, "cannot read the WAV file: {}", err),
+            WavError::Unreadable(err) => write!(f, "not a readable WAV file: {}", err),
+            WavError::UnsupportedSubFormat { name } => write!(
+                f,
+                "WAV file with {} samples; only PCM and IEEE float can be read",
+                name
+            ),
+            WavError::UnsupportedContainer { format, bits } => {
+                write!(f, "cannot read {}-bit {:?} samples", bits, format)
+            }
+            WavError::Truncated { read, declared } => write!(
+                f,
+                "WAV data ends after {} of {} declared samples; --allow-truncated keeps the \
+                 samples before it",
+                read, declared
+            ),
+        }
+    }
+}
+
+// Every sample of the WAV at `path` in [-1, 1], interleaved, and its spec
+// (bits_per_sample is the container width). allow_truncated as read_samples.
+pub fn read_file(path: &Path, allow_truncated: bool) -> Result<(Vec<f32>, WavSpec), WavError> {
+    let file = File::open(path).map_err(WavError::Io)?;
+    let len = file.metadata().map_err(WavError::Io)?.len();
+    let mut stream = SampleStream::with_len(BufReader::new(file), len)?;
+    let samples = read_samples(&mut stream, allow_truncated)?;
+    Ok((samples, stream.spec()))
+}
+
+// Every sample left in `stream`. With `allow_truncated` an early end of the
+// data stops the read with the samples so far, cut back to whole sample
+// frames so the channels stay in step; without it the read fails with
+// Truncated.
+pub fn read_samples<R: Read>(stream: &mut SampleStream<R>, allow_truncated: bool) -> Result<Vec<f32>, WavError> {
+    let declared = stream.declared();
+    let channels = usize::from(stream.spec().channels);
+    let mut samples = Vec::with_capacity(stream.capacity());
+    for sample in stream {
+        match sample {
+            Ok(sample) => samples.push(sample),
+            Err(WavError::Truncated { .. }) if allow_truncated => {
+                samples.truncate(samples.len() / channels * channels);
+                diag!(
+                    "Warning: WAV data ends early; keeping {} of {} declared samples",
+                    samples.len(),
+                    declared
+                );
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(samples)
+}
+
+// The samples of a WAV in [-1, 1], interleaved, read off `reader` one at a
+// time. The header is read by new, up to the start of the data chunk.
+// Integer samples sit left-justified in their container whatever the valid
+// bits, so each is read at the container width and scaled by its full
+// scale (8-bit ones are unsigned); float containers are 32 or 64 bits.
+pub struct SampleStream<R: Read> {
+    reader: R,
+    spec: WavSpec,
+    width: usize,              // bytes per sample
+    convert: fn(&[u8]) -> f32, // one sample's bytes -> [-1, 1]
+    declared: usize,           // samples the data chunk holds
+    capacity: usize,           // of those, the ones the reader can still hold
+    read: usize,               // samples yielded so far
+    ended: bool,               // the data ran out before `declared`
+}
+
+impl<R: Read> SampleStream<R> {
+    pub fn new(reader: R) -> Result<SampleStream<R>, WavError> {
+        SampleStream::open(reader, None)
+    }
+
+    // A stream over the first `len` bytes of a reader, e.g. a file of that
+    // size
+    pub fn with_len(reader: R, len: u64) -> Result<SampleStream<R>, WavError> {
+        SampleStream::open(reader, Some(len))
+    }
+
+    fn open(mut reader: R, len: Option<u64>) -> Result<SampleStream<R>, WavError> {
+        let malformed = |reason| WavError::Unreadable(hound::Error::FormatError(reason));
+        let mut riff = [0u8; 12];
+        read_header(&mut reader, &mut riff)?.ok_or(malformed("no RIFF tag found"))?;
+        if &riff[..4] != b"RIFF" {
+            return Err(malformed("no RIFF tag found"));
+        }
+        if &riff[8..] != b"WAVE" {
+            return Err(malformed("no WAVE tag found"));
+        }
+
+        let mut format = None; // (spec, block_align) from the fmt chunk
+        let mut offset = riff.len() as u64; // bytes read so far
+        let mut chunk = [0u8; 8];
+        loop {
+            read_header(&mut reader, &mut chunk)?.ok_or(malformed("no data chunk found"))?;
+            let chunk_len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            let padded = u64::from(chunk_len) + u64::from(chunk_len % 2); // to an even length
+            offset += chunk.len() as u64 + padded;
+            match &chunk[..4] {
+                b"fmt " if chunk_len > MAX_FMT_LEN => return Err(malformed("fmt chunk is over 40 bytes")),
+                b"fmt " => {
+                    let mut fmt = [0u8; MAX_FMT_LEN as usize];
+                    let fmt = &mut fmt[..chunk_len as usize];
+                    reader.read_exact(fmt).map_err(WavError::Io)?;
+                    format = Some(parse_fmt(fmt)?);
+                }
+                b"data" => {
+                    let (spec, block_align) = format.ok_or(malformed("data chunk before fmt chunk"))?;
+                    let width = usize::from(block_align) / usize::from(spec.channels);
+                    let convert = converter(spec.sample_format, width).ok_or(WavError::UnsupportedContainer {
+                        format: spec.sample_format,
+                        bits: spec.bits_per_sample,
+                    })?;
+                    let declared = chunk_len as usize / width;
+                    let data_start = offset - padded;
+                    let capacity = match len {
+                        Some(len) => declared.min((len.saturating_sub(data_start) / width as u64) as usize),
+                        None => declared.min(UNSIZED_CAPACITY),
+                    };
+                    return Ok(SampleStream {
+                        reader,
+                        spec,
+                        width,
+                        convert,
+                        declared,
+                        capacity,
+                        read: 0,
+                        ended: false,
+                    });
+                }
+                _ => {
+                    let skipped = io::copy(&mut (&mut reader).take(u64::from(chunk_len)), &mut io::sink());
+                    if skipped.map_err(WavError::Io)? < u64::from(chunk_len) {
+                        return Err(malformed("no data chunk found"));
+                    }
+                }
+            }
+            if chunk_len % 2 == 1 {
+                // Chunks are padded to even lengths
+                read_header(&mut reader, &mut [0u8; 1])?;
+            }
+        }
+    }
+
+    // Channels, rate, container width and sample type
+    pub fn spec(&self) -> WavSpec {
+        self.spec
+    }
+
+    // Samples the data chunk declares, whether or not they all arrive
+    pub fn declared(&self) -> usize {
+        self.declared
+    }
+
+    // Samples worth reserving room for: the declared ones, as far as the
+    // reader's length (when known) can hold them
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl<R: Read> Iterator for SampleStream<R> {
+    type Item = Result<f32, WavError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ended || self.read == self.declared {
+            return None;
+        }
+        let mut bytes = [0u8; 8];
+        match self.reader.read_exact(&mut bytes[..self.width]) {
+            Ok(()) => {
+                self.read += 1;
+                Some(Ok((self.convert)(&bytes[..self.width])))
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                self.ended = true;
+                Some(Err(WavError::Truncated {
+                    read: self.read,
+                    declared: self.declared,
+                }))
+            }
+            Err(err) => {
+                self.ended = true;
+                Some(Err(WavError::Io(err)))
+            }
+        }
+    }
+}
+
+// Fill `buf` from `reader`: None when the reader was already at its end
+fn read_header(reader: &mut impl Read, buf: &mut [u8]) -> Result<Option<()>, WavError> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(Some(())),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(WavError::Io(err)),
+    }
+}
+
+// The spec and block alignment a fmt chunk describes; bits_per_sample is the
+// container width, whatever an extensible header's valid bits
+fn parse_fmt(fmt: &[u8]) -> Result<(WavSpec, u16), WavError> {
+    let malformed = |reason| WavError::Unreadable(hound::Error::FormatError(reason));
+    if fmt.len() < 16 {
+        return Err(malformed("invalid fmt chunk size"));
+    }
+    let field = |at: usize| u16::from_le_bytes([fmt[at], fmt[at + 1]]);
+    let sample_format = match field(0) {
+        WAVE_FORMAT_EXTENSIBLE if fmt.len() < 40 => return Err(malformed("invalid extensible fmt chunk size")),
+        WAVE_FORMAT_EXTENSIBLE => sub_format(&fmt[24..40]),
+        tag => format_tag(tag),
+    }
+    .map_err(|name| WavError::UnsupportedSubFormat { name })?;
+    let (channels, block_align) = (field(2), field(12));
+    if channels == 0 || block_align == 0 || block_align % channels != 0 {
+        return Err(malformed("invalid block alignment"));
+    }
+    let spec = WavSpec {
+        channels,
+        sample_rate: u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]),
+        bits_per_sample: field(14),
+        sample_format,
+    };
+    Ok((spec, block_align))
+}
+
+// The sample type a sub-format GUID names, or its name when it's one that
+// can't be read
+fn sub_format(guid: &[u8]) -> Result<SampleFormat, String> {
+    if guid[2..] != SUBFORMAT_GUID_TAIL {
+        let hex: String = guid.iter().map(|byte| format!("{:02x}", byte)).collect();
+        return Err(format!("non-standard sub-format {}", hex));
+    }
+    format_tag(u16::from_le_bytes([guid[0], guid[1]]))
+}
+
+// The sample type a (non-extensible) format tag names, or its name
+fn format_tag(tag: u16) -> Result<SampleFormat, String> {
+    match tag {
+        0x0001 => Ok(SampleFormat::Int),
+        0x0003 => Ok(SampleFormat::Float),
+        0x0002 => Err("Microsoft ADPCM".to_string()),
+        0x0006 => Err("A-law".to_string()),
+        0x0007 => Err("mu-law".to_string()),
+        0x0011 => Err("IMA ADPCM".to_string()),
+        0x0055 => Err("MPEG layer 3".to_string()),
+        tag => Err(format!("format tag {:#06x}", tag)),
+    }
+}
+
+// How to turn one `width`-byte sample into [-1, 1]
+fn converter(format: SampleFormat, width: usize) -> Option<fn(&[u8]) -> f32> {
+    let convert: fn(&[u8]) -> f32 = match (format, width) {
+        (SampleFormat::Int, 1) => |bytes| (f32::from(bytes[0]) - 128.0) / 128.0, // unsigned
+        (SampleFormat::Int, 2) => |bytes| f32::from(i16::from_le_bytes([bytes[0], bytes[1]])) / SAMPLE_DIVISOR,
+        (SampleFormat::Int, 3..=4) => |bytes| {
+            let mut word = [0u8; 4];
+            word[4 - bytes.len()..].copy_from_slice(bytes); // container at the top of an i32
+            (f64::from(i32::from_le_bytes(word)) / 2147483648.0) as f32
+        },
+        (SampleFormat::Float, 4) => |bytes| f32::from_le_bytes(bytes.try_into().expect("4 bytes")),
+        (SampleFormat::Float, 8) => |bytes| f64::from_le_bytes(bytes.try_into().expect("8 bytes")) as f32,
+        _ => return None,
+    };
+    Some(convert)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use hound::WavWriter;
+
+    use super::*;
+
+    // A 16-bit stereo WAV of `frames` sample frames, cut off `cut` bytes
+    // before the end its header declares
+    fn cut_off_wav(frames: usize, cut: usize) -> Vec<u8> {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut bytes = Cursor::new(Vec::new());
+        let mut writer = WavWriter::new(&mut bytes, spec).unwrap();
+        for code in 0..frames as i16 * 2 {
+            writer.write_sample(code).unwrap();
+        }
+        writer.finalize().unwrap();
+        let mut bytes = bytes.into_inner();
+        bytes.truncate(bytes.len() - cut);
+        bytes
+    }
+
+    #[test]
+    fn a_truncated_data_chunk_keeps_the_whole_frames_read() {
+        // 3 bytes short: the last right sample and half the left one before it
+        let bytes = cut_off_wav(100, 3);
+        let mut stream = SampleStream::new(Cursor::new(bytes)).unwrap();
+        let samples = read_samples(&mut stream, true).unwrap();
+        assert_eq!(samples.len(), 99 * 2);
+        assert_eq!(samples[197], 197.0 / 32768.0);
+    }
+
+    #[test]
+    fn a_truncated_data_chunk_is_an_error_without_the_flag() {
+        let mut stream = SampleStream::new(Cursor::new(cut_off_wav(100, 3))).unwrap();
+        let result = read_samples(&mut stream, false);
+        // 198 whole samples came through before the cut
+        assert!(matches!(result, Err(WavError::Truncated { read: 198, declared: 200 })), "{:?}", result);
+    }
+
+    #[test]
+    fn a_header_declaring_more_than_the_file_holds_reserves_what_it_holds() {
+        let mut bytes = cut_off_wav(100, 0);
+        bytes[40..44].copy_from_slice(&u32::MAX.to_le_bytes()); // data chunk length
+        let len = bytes.len() as u64;
+        let sized = SampleStream::with_len(Cursor::new(bytes.clone()), len).unwrap();
+        assert_eq!((sized.declared(), sized.capacity()), (u32::MAX as usize / 2, 200));
+        let unsized_stream = SampleStream::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(unsized_stream.capacity(), UNSIZED_CAPACITY);
+    }
+
+    #[test]
+    fn a_fmt_chunk_over_40_bytes_is_refused() {
+        let mut bytes = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        bytes.extend(u32::MAX.to_le_bytes());
+        bytes.extend([0u8; 64]);
+        let result = SampleStream::new(Cursor::new(bytes)).map(|stream| stream.spec());
+        assert!(matches!(result, Err(WavError::Unreadable(_))), "{:?}", result);
+    }
+
+    // A mono extensible WAV on disk, removed when dropped
+    struct Fixture(std::path::PathBuf);
+
+    impl Fixture {
+        // `data` in `container_bits`-bit containers holding `valid_bits`,
+        // under the sub-format whose old format tag is `tag`
+        fn new(name: &str, tag: u16, container_bits: u16, valid_bits: u16, data: &[u8]) -> Fixture {
+            let block_align = container_bits / 8;
+            let mut fmt = Vec::new();
+            fmt.extend(WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+            fmt.extend(1u16.to_le_bytes()); // channels
+            fmt.extend(8000u32.to_le_bytes());
+            fmt.extend((8000 * u32::from(block_align)).to_le_bytes());
+            fmt.extend(block_align.to_le_bytes());
+            fmt.extend(container_bits.to_le_bytes());
+            fmt.extend(22u16.to_le_bytes()); // cbSize
+            fmt.extend(valid_bits.to_le_bytes());
+            fmt.extend(4u32.to_le_bytes()); // channel mask: front centre
+            fmt.extend(tag.to_le_bytes());
+            fmt.extend(SUBFORMAT_GUID_TAIL);
+
+            let mut bytes = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+            bytes.extend((fmt.len() as u32).to_le_bytes());
+            bytes.extend(fmt);
+            bytes.extend(b"data");
+            bytes.extend((data.len() as u32).to_le_bytes());
+            bytes.extend(data);
+            let riff_len = (bytes.len() - 8) as u32;
+            bytes[4..8].copy_from_slice(&riff_len.to_le_bytes());
+
+            let file = format!("msg_encoder-test-{}-{}.wav", std::process::id(), name);
+            let path = std::env::temp_dir().join(file);
+            std::fs::write(&path, bytes).unwrap();
+            Fixture(path)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn extensible_fixtures_read_at_their_container_width() {
+        let pcm16: Vec<u8> = [16384i16, -32768, 0].iter().flat_map(|code| code.to_le_bytes()).collect();
+        let fixture = Fixture::new("ext16", 0x0001, 16, 16, &pcm16);
+        let (samples, spec) = read_file(&fixture.0, false).unwrap();
+        assert_eq!(samples, [0.5, -1.0, 0.0]);
+        assert_eq!((spec.channels, spec.sample_rate, spec.bits_per_sample), (1, 8000, 16));
+
+        // 20 valid bits in 24: the low nibble is padding, the scale is 24-bit
+        let pcm20: Vec<u8> = [0x40_0000i32, -0x80_0000, 0x10]
+            .iter()
+            .flat_map(|code| code.to_le_bytes()[..3].to_vec())
+            .collect();
+        let fixture = Fixture::new("ext20", 0x0001, 24, 20, &pcm20);
+        let (samples, spec) = read_file(&fixture.0, false).unwrap();
+        assert_eq!(samples, [0.5, -1.0, 16.0 / 8_388_608.0]);
+        assert_eq!(spec.bits_per_sample, 24);
+
+        // 24 valid bits left-justified in 32
+        let pcm24: Vec<u8> = [0x4000_0000i32, -0x2000_0000].iter().flat_map(|code| code.to_le_bytes()).collect();
+        let fixture = Fixture::new("ext24", 0x0001, 32, 24, &pcm24);
+        assert_eq!(read_file(&fixture.0, false).unwrap().0, [0.5, -0.25]);
+
+        let float: Vec<u8> = [0.25f32, -0.75].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let fixture = Fixture::new("extf32", 0x0003, 32, 32, &float);
+        let (samples, spec) = read_file(&fixture.0, false).unwrap();
+        assert_eq!(samples, [0.25, -0.75]);
+        assert_eq!(spec.sample_format, SampleFormat::Float);
+    }
+
+    #[test]
+    fn an_extensible_a_law_file_names_its_sub_format() {
+        let fixture = Fixture::new("extalaw", 0x0006, 8, 8, &[0xd5; 4]);
+        match read_file(&fixture.0, false) {
+            Err(WavError::UnsupportedSubFormat { name }) => assert_eq!(name, "A-law"),
+            other => panic!("expected UnsupportedSubFormat, got {:?}", other.map(|(_, spec)| spec)),
+        }
+    }
+}
@@ -0,0 +1,19 @@
+// =============================================================================
+// WHITENING - Breaks up long runs of equal bits after the magic pattern
+// =============================================================================
+//
+// The decoder scores each bin against its neighbours, so the middle of a long
+// run of 0s (the high bits of every short length header) scores close to zero
+// and can land on either side of the threshold. XORing the framed bits with
+// an alternating mask turns those runs into the 1/0 alternation the pilot
+// already shows decodes cleanly. Applying the mask twice restores the input.
+
+// Mask bit for position 0; the mask alternates from there
+const MASK_START: u8 = 1;
+
+pub fn whiten(bits: &[u8]) -> Vec<u8> {
+    bits.iter()
+        .enumerate()
+        .map(|(idx, &bit)| bit ^ (MASK_START ^ (idx % 2) as u8))
+        .collect()
+}
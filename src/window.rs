@@ -0,0 +1,142 @@
+use std::f32::consts::PI;
+
+// =============================================================================
+// ANALYSIS WINDOWS - Trade main-lobe width against sidelobe leakage
+// =============================================================================
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Window {
+    // No taper: narrowest main lobe, highest sidelobes (original behaviour)
+    #[default]
+    Rectangular,
+    Hann,
+    Hamming,
+    // Lowest sidelobes of the set, widest main lobe
+    Blackman,
+}
+
+impl Window {
+    pub fn from_name(name: &str) -> Option<Window> {
+        match name {
+            "rectangular" => Some(Window::Rectangular),
+            "hann" => Some(Window::Hann),
+            "hamming" => Some(Window::Hamming),
+            "blackman" => Some(Window::Blackman),
+            _ => None,
+        }
+    }
+}
+
+// Periodic (DFT-even) window of `len` samples
+pub fn make_window(kind: Window, len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|n| {
+            let phase = 2.0 * PI * n as f32 / len as f32;
+            match kind {
+                Window::Rectangular => 1.0,
+                Window::Hann => 0.5 - 0.5 * phase.cos(),
+                Window::Hamming => 0.54 - 0.46 * phase.cos(),
+                Window::Blackman => 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos(),
+            }
+        })
+        .collect()
+}
+
+// Samples between frame starts for an overlap fraction in [0, 1)
+pub fn hop_len(frame_len: usize, overlap: f32) -> usize {
+    ((frame_len as f32 * (1.0 - overlap)).round() as usize).clamp(1, frame_len)
+}
+
+// Start of every frame when frames of `frame_len` advance by `hop`. The last
+// few may run past the end; callers zero-pad them like a short final chunk.
+pub fn frame_starts(len: usize, hop: usize) -> impl Iterator<Item = usize> {
+    (0..len).step_by(hop)
+}
+
+// Largest relative ripple in the overlap-added window still counted as COLA
+const COLA_TOLERANCE: f32 = 1e-3;
+
+// Gain of overlap-adding `window` at `hop`, and whether it is constant (the
+// constant-overlap-add condition). Rectangular and Hann/Hamming are COLA at
+// 50% and 75% overlap; Blackman needs a hop of a third of the frame.
+pub fn overlap_gain(window: &[f32], hop: usize) -> (f32, bool) {
+    let sums: Vec<f32> = (0..hop)
+        .map(|offset| window.iter().skip(offset).step_by(hop).sum())
+        .collect();
+    let mean = sums.iter().sum::<f32>() / sums.len() as f32;
+    let ripple = sums.iter().fold(0.0f32, |acc, &sum| acc.max((sum - mean).abs()));
+    (mean, ripple <= COLA_TOLERANCE * mean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fft::PlanCache;
+    use crate::polar::magnitude;
+
+    const LEN: usize = 256;
+
+    // Share of a tone's energy more than 8 bins from it, through `kind`
+    fn far_leakage(kind: Window) -> f32 {
+        let bin = 40.5; // between two bins, where leakage is worst
+        let window = make_window(kind, LEN);
+        let mut frame: Vec<f32> = (0..LEN)
+            .map(|n| (2.0 * PI * bin * n as f32 / LEN as f32).sin() * window[n])
+            .collect();
+        let plans = PlanCache::new().plans(LEN);
+        let mut spectrum = plans.forward.make_output_vec();
+        plans.forward.process(&mut frame, &mut spectrum).unwrap();
+        let energy: Vec<f32> = spectrum.iter().map(|value| magnitude(value).powi(2)).collect();
+        let far: f32 = energy
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| (idx as f32 - bin).abs() > 8.0)
+            .map(|(_, energy)| energy)
+            .sum();
+        far / energy.iter().sum::<f32>()
+    }
+
+    #[test]
+    fn tapered_windows_leak_less_than_rectangular() {
+        let rectangular = far_leakage(Window::Rectangular);
+        let hann = far_leakage(Window::Hann);
+        let hamming = far_leakage(Window::Hamming);
+        let blackman = far_leakage(Window::Blackman);
+        assert!(hann < rectangular / 20.0, "hann {} vs rectangular {}", hann, rectangular);
+        assert!(hamming < rectangular / 20.0, "hamming {} vs rectangular {}", hamming, rectangular);
+        assert!(blackman < hann && blackman < hamming, "blackman {}", blackman);
+    }
+
+    // Overlap-add `kind`-windowed frames of a slow sine at `overlap`, over
+    // the gain: the largest error against the sine away from the ends
+    fn reconstruction_error(kind: Window, overlap: f32) -> (f32, bool) {
+        let signal: Vec<f32> = (0..LEN * 8).map(|n| (n as f32 * 0.01).sin()).collect();
+        let window = make_window(kind, LEN);
+        let hop = hop_len(LEN, overlap);
+        let (gain, cola) = overlap_gain(&window, hop);
+        let mut output = vec![0.0f32; signal.len()];
+        for start in frame_starts(signal.len(), hop) {
+            for ((out, x), w) in output[start..].iter_mut().zip(&signal[start..]).zip(&window) {
+                *out += w * x;
+            }
+        }
+        let error = (LEN..signal.len() - LEN)
+            .map(|n| (output[n] / gain - signal[n]).abs())
+            .fold(0.0f32, f32::max);
+        (error, cola)
+    }
+
+    #[test]
+    fn cola_windows_reconstruct_at_half_and_three_quarter_overlap() {
+        for kind in [Window::Rectangular, Window::Hann, Window::Hamming] {
+            for overlap in [0.5, 0.75] {
+                let (error, cola) = reconstruction_error(kind, overlap);
+                assert!(cola, "{:?} at {}", kind, overlap);
+                assert!(error < 1e-4, "{:?} at {}: {}", kind, overlap, error);
+            }
+        }
+        // Blackman ripples at 50%, and overlap_gain says so
+        let (error, cola) = reconstruction_error(Window::Blackman, 0.5);
+        assert!(!cola && error > 1e-2, "blackman error {}", error);
+    }
+}
@@ -0,0 +1,251 @@
+// End-to-end runs of the msg_encoder binary on the bundled sample
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use msg_encoder::config::WatermarkConfig;
+use msg_encoder::decoder::expected_bits;
+
+const SAMPLE: &str = "input_data/OSR_us_000_0057_8k.wav";
+
+// The bundled sample, or None (and the test passes) when it isn't checked out
+fn sample() -> Option<PathBuf> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SAMPLE);
+    if path.exists() {
+        Some(path)
+    } else {
+        eprintln!("skipping: {} is missing", path.display());
+        None
+    }
+}
+
+// A path in the temp directory, removed when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(name: &str) -> TempFile {
+        let file = format!("msg_encoder-cli-{}-{}", std::process::id(), name);
+        TempFile(env::temp_dir().join(file))
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn msg_encoder(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_msg_encoder"))
+        .args(args)
+        .output()
+        .expect("failed to run msg_encoder")
+}
+
+fn run(args: &[&str]) -> Output {
+    let output = msg_encoder(args);
+    assert!(
+        output.status.success(),
+        "msg_encoder {:?} failed:\n{}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn run_failing(args: &[&str]) -> Output {
+    let output = msg_encoder(args);
+    assert!(!output.status.success(), "msg_encoder {:?} succeeded", args);
+    output
+}
+
+#[test]
+fn sample_round_trips_through_encode_and_decode() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("round-trip.wav");
+    run(&["encode", "--input", input.to_str().unwrap(), "--output", wav.path(), "--message", "sample"]);
+    let decoded = run(&["decode", "--input", wav.path()]);
+    let stdout = String::from_utf8_lossy(&decoded.stdout);
+    assert!(stdout.contains("Decoded message: \"sample\""), "{}", stdout);
+}
+
+#[test]
+fn wav_piped_to_stdout_is_complete_and_decodes() {
+    let Some(input) = sample() else { return };
+    // Command::output reads a pipe, which can't seek back to patch sizes
+    let piped = run(&["encode", "--input", input.to_str().unwrap(), "--output", "-", "--message", "pipe"]);
+    let riff_len = u32::from_le_bytes(piped.stdout[4..8].try_into().unwrap()) as usize;
+    let data_len = u32::from_le_bytes(piped.stdout[40..44].try_into().unwrap()) as usize;
+    assert_eq!(&piped.stdout[..4], b"RIFF");
+    assert_eq!(riff_len + 8, piped.stdout.len());
+    assert_eq!(data_len + 44, piped.stdout.len());
+
+    let wav = TempFile::new("piped.wav");
+    fs::write(&wav.0, &piped.stdout).unwrap();
+    let decoded = run(&["decode", "--input", wav.path()]);
+    assert!(String::from_utf8_lossy(&decoded.stdout).contains("Decoded message: \"pipe\""));
+}
+
+#[test]
+fn mismatched_band_fails_with_a_format_hint() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("band.wav");
+    run(&["encode", "--input", input.to_str().unwrap(), "--output", wav.path(), "--band", "20-140", "--message", "hi"]);
+    // The stream doesn't record its band, so the default one finds nothing
+    let wrong = run_failing(&["decode", "--input", wav.path()]);
+    assert!(String::from_utf8_lossy(&wrong.stderr).contains("hint:"));
+    let right = run(&["decode", "--input", wav.path(), "--band", "20-140"]);
+    assert!(String::from_utf8_lossy(&right.stdout).contains("Decoded message: \"hi\""));
+}
+
+#[test]
+fn resampled_output_decodes() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("16k.wav");
+    run(&["encode", "--input", input.to_str().unwrap(), "--output", wav.path(), "--resample", "16000", "--message", "hi"]);
+    let decoded = run(&["decode", "--input", wav.path()]);
+    assert!(String::from_utf8_lossy(&decoded.stdout).contains("Decoded message: \"hi\""));
+}
+
+#[test]
+fn existing_output_is_kept_unless_forced() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("existing.wav");
+    fs::write(&wav.0, b"not audio").unwrap();
+    let input = input.to_str().unwrap();
+    let refused = run_failing(&["encode", "--input", input, "--output", wav.path()]);
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("already exists"));
+    assert_eq!(fs::read(&wav.0).unwrap(), b"not audio");
+    run(&["encode", "--input", input, "--output", wav.path(), "--force"]);
+    assert_eq!(&fs::read(&wav.0).unwrap()[..4], b"RIFF");
+}
+
+#[test]
+fn binary_payload_is_written_verbatim_by_decode_out() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("raw.wav");
+    let out = TempFile::new("raw.bin");
+    let input = input.to_str().unwrap();
+    run(&["encode", "--input", input, "--output", wav.path(), "--raw-hex", "00ff80fe0a"]);
+    run(&["decode", "--input", wav.path(), "--typed", "--out", out.path()]);
+    assert_eq!(fs::read(&out.0).unwrap(), [0x00, 0xff, 0x80, 0xfe, 0x0a]);
+    // "-" puts the same bytes, and nothing else, on stdout
+    let piped = run(&["decode", "--input", wav.path(), "--typed", "--out", "-"]);
+    assert_eq!(piped.stdout, [0x00, 0xff, 0x80, 0xfe, 0x0a]);
+}
+
+#[test]
+fn dumped_bits_match_the_stream_rebuilt_from_the_message() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("dump.wav");
+    let encoded_bits = TempFile::new("encoded.bits");
+    let decoded_bits = TempFile::new("decoded.bits");
+    let input = input.to_str().unwrap();
+    run(&["encode", "--input", input, "--output", wav.path(), "--message", "hello", "--dump-bits", encoded_bits.path()]);
+    let expected: Vec<u8> = expected_bits("hello", &WatermarkConfig::default(), None)
+        .iter()
+        .map(|&bit| b'0' + bit)
+        .collect();
+    assert_eq!(fs::read(&encoded_bits.0).unwrap(), expected);
+
+    // The decoder decides every band bin, so its dump runs on past the stream
+    run(&["decode", "--input", wav.path(), "--dump-bits", decoded_bits.path()]);
+    assert!(fs::read(&decoded_bits.0).unwrap().starts_with(&expected));
+}
+
+#[test]
+fn noise_sweep_error_rate_climbs_as_the_snr_falls() {
+    let Some(input) = sample() else { return };
+    let sweep = run(&["robustness", input.to_str().unwrap(), "hello"]);
+    // "  SNR dB  BER" rows; a failed decode counts as every bit wrong
+    let rates: Vec<(f32, f32)> = String::from_utf8_lossy(&sweep.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let snr_db = fields.next()?.parse().ok()?;
+            let ber = fields.next()?.parse().unwrap_or(1.0);
+            Some((snr_db, ber))
+        })
+        .collect();
+    assert_eq!(rates.len(), 9, "{:?}", rates);
+    assert_eq!(rates[0], (40.0, 0.0));
+    assert!(rates[8].1 > 0.05, "{:?}", rates);
+    let (high, low) = rates.split_at(4);
+    let mean = |rows: &[(f32, f32)]| rows.iter().map(|row| row.1).sum::<f32>() / rows.len() as f32;
+    assert!(mean(high) < mean(low), "{:?}", rates);
+}
+
+#[test]
+fn output_at_44100_hz_decodes() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("44k.wav");
+    let input = input.to_str().unwrap();
+    run(&["encode", "--input", input, "--output", wav.path(), "--output-format", "rate=44100,bits=24", "--message", "hi"]);
+    let decoded = run(&["decode", "--input", wav.path()]);
+    assert!(String::from_utf8_lossy(&decoded.stdout).contains("Decoded message: \"hi\""));
+}
+
+#[test]
+fn float_output_round_trips_without_bit_errors() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("float.wav");
+    let input = input.to_str().unwrap();
+    run(&["encode", "--input", input, "--output", wav.path(), "--output-format", "float", "--message", "hello"]);
+    let spec = hound::WavReader::open(&wav.0).unwrap().spec();
+    assert_eq!((spec.sample_format, spec.bits_per_sample), (hound::SampleFormat::Float, 32));
+    let decoded = run(&["decode", "--input", wav.path(), "--expect", "hello"]);
+    let stdout = String::from_utf8_lossy(&decoded.stdout);
+    assert!(stdout.contains("Bit error rate against \"hello\": 0.0000"), "{}", stdout);
+}
+
+#[test]
+fn verify_passes_mild_attacks_and_fails_heavy_noise() {
+    let Some(input) = sample() else { return };
+    let input = input.to_str().unwrap();
+    let mild = run(&["verify", input, "hello", "--attack", "noise:30", "--attack", "gain:-6", "--attack", "gain:12"]);
+    let rows = String::from_utf8_lossy(&mild.stdout);
+    assert_eq!(rows.lines().filter(|row| row.ends_with("pass")).count(), 3, "{}", rows);
+
+    // One failing row fails the whole run
+    let heavy = run_failing(&["verify", input, "hello", "--attack", "gain:-6", "--attack", "noise:0"]);
+    let rows = String::from_utf8_lossy(&heavy.stdout);
+    assert!(rows.lines().any(|row| row.starts_with("gain:-6") && row.ends_with("pass")), "{}", rows);
+    assert!(rows.lines().any(|row| row.starts_with("noise:0") && row.ends_with("FAIL")), "{}", rows);
+}
+
+#[test]
+fn a_cut_off_file_is_an_error_not_a_crash() {
+    let Some(input) = sample() else { return };
+    let wav = TempFile::new("cut.wav");
+    run(&["encode", "--input", input.to_str().unwrap(), "--output", wav.path(), "--message", "cut"]);
+    let mut bytes = std::fs::read(&wav.0).unwrap();
+    bytes.truncate(20_000);
+    std::fs::write(&wav.0, bytes).unwrap();
+    let failed = run_failing(&["decode", "--input", wav.path()]);
+    assert_eq!(failed.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&failed.stderr).contains("--allow-truncated"));
+    let decoded = run(&["decode", "--input", wav.path(), "--allow-truncated"]);
+    assert!(String::from_utf8_lossy(&decoded.stdout).contains("Decoded message: \"cut\""));
+}
+
+#[test]
+fn a_closed_stdout_is_an_error_not_a_crash() {
+    let Some(input) = sample() else { return };
+    let mut child = Command::new(env!("CARGO_BIN_EXE_msg_encoder"))
+        .args(["encode", "--input", input.to_str().unwrap(), "--output", "-"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run msg_encoder");
+    drop(child.stdout.take()); // like `| head -c 0`, gone before the header goes out
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("Broken pipe"), "{}", stderr);
+}